@@ -0,0 +1,79 @@
+use std::str::FromStr;
+
+use bigdecimal::BigDecimal;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use jdw_sequencer::master_sequencer::{MasterSequencer, SequencerResetMode, SequencerStartMode};
+use jdw_sequencer::sequencer::{Sequencer, SequencerEntry};
+use jdw_sequencer::ticks::{self, Ticks};
+
+/*
+    Cost of a single engine tick (reset check and due events) for a number of looping aliases,
+        each playing sixteenth notes over a bar, and for a single sequence of growing length.
+    Ticks are 1ms at 120 bpm, as in a live loop.
+*/
+
+fn big(inp: &str) -> BigDecimal {
+    BigDecimal::from_str(inp).unwrap()
+}
+
+fn master_sequencer(aliases: usize) -> MasterSequencer<u32> {
+    let mut ms = MasterSequencer::new(SequencerStartMode::Immediate, SequencerResetMode::Individual);
+    let entries: Vec<SequencerEntry<u32>> = (0..16)
+        .map(|i| SequencerEntry::new(big("0.25") * BigDecimal::from(i), i))
+        .collect();
+
+    for alias in 0..aliases {
+        ms.queue(&format!("alias_{}", alias), entries.clone(), big("4.0"), false);
+    }
+    ms.start_check();
+    ms
+}
+
+fn tick(c: &mut Criterion) {
+    let tick: Ticks = ticks::from_beats(&big("0.002"));
+    let mut group = c.benchmark_group("tick");
+
+    for aliases in [1, 100, 1000] {
+        let mut ms = master_sequencer(aliases);
+        group.bench_with_input(BenchmarkId::from_parameter(aliases), &aliases, |b, _| {
+            b.iter(|| {
+                ms.start_check();
+                ms.reset_check();
+                ms.tick_events(tick)
+            })
+        });
+    }
+
+    group.finish();
+}
+
+// An automation curve with a point every thousandth of a beat; a tick should cost the same at any length
+fn long_sequence(c: &mut Criterion) {
+    let tick: Ticks = ticks::from_beats(&big("0.002"));
+    let mut group = c.benchmark_group("long_sequence");
+
+    for length in [1_000u32, 10_000, 100_000] {
+        let entries: Vec<SequencerEntry<u32>> = (0..length)
+            .map(|i| SequencerEntry::new(BigDecimal::from(i) / BigDecimal::from(1000), i))
+            .collect();
+        let mut sequencer = Sequencer::new();
+        sequencer.queue(entries, BigDecimal::from(length) / BigDecimal::from(1000));
+        sequencer.reset(0);
+
+        group.bench_with_input(BenchmarkId::from_parameter(length), &length, |b, _| {
+            b.iter(|| {
+                if sequencer.is_finished() {
+                    let overshoot = sequencer.get_overshoot();
+                    sequencer.reset(overshoot);
+                }
+                sequencer.tick_events(tick)
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, tick, long_sequence);
+criterion_main!(benches);
@@ -0,0 +1,230 @@
+use std::f64::consts::PI;
+use std::str::FromStr;
+
+use bigdecimal::{BigDecimal, Zero};
+
+use crate::ticks::{self, Ticks};
+
+/*
+
+    Continuous automation for an alias, as opposed to its discrete timed entries: a value curve that is
+        sampled every rate_beats while the sequence plays. Each sample goes out as the lane contents with
+        the value appended, e.g. /set_param "synth" "cutoff" 440.0 (see packet_utils::event_packet).
+
+    - Breakpoints: (beat, value) pairs, linear in between and held before the first and after the last
+    - Lfo: a waveform repeating every period_beats between min and max, starting at phase (in cycles, 0.0 - 1.0)
+
+    Lanes loop with the sequence they were queued with, so both start over from beat 0 every loop.
+
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Waveform {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+}
+
+impl FromStr for Waveform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sine" => Ok(Waveform::Sine),
+            "triangle" => Ok(Waveform::Triangle),
+            "saw" => Ok(Waveform::Saw),
+            "square" => Ok(Waveform::Square),
+            _ => Err(format!("Unknown waveform: {}", s)),
+        }
+    }
+}
+
+impl Waveform {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Waveform::Sine => "sine",
+            Waveform::Triangle => "triangle",
+            Waveform::Saw => "saw",
+            Waveform::Square => "square",
+        }
+    }
+
+    // Value between 0.0 and 1.0 at the given position (0.0 - 1.0) within a cycle
+    fn unit_at(&self, cycle: f64) -> f64 {
+        match self {
+            Waveform::Sine => 0.5 + 0.5 * (2.0 * PI * cycle).sin(),
+            Waveform::Triangle => if cycle < 0.5 { cycle * 2.0 } else { 2.0 - cycle * 2.0 },
+            Waveform::Saw => cycle,
+            Waveform::Square => if cycle < 0.5 { 1.0 } else { 0.0 },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AutomationShape {
+    Breakpoints(Vec<(BigDecimal, f32)>),
+    Lfo { waveform: Waveform, period_beats: BigDecimal, min: f32, max: f32, phase: f32 },
+}
+
+#[derive(Debug, Clone)]
+pub struct AutomationLane<T: Clone> {
+    pub contents: T,
+    pub shape: AutomationShape,
+    pub rate_beats: BigDecimal,
+}
+
+impl<T: Clone> AutomationLane<T> {
+    pub fn new(contents: T, shape: AutomationShape, rate_beats: BigDecimal) -> Result<AutomationLane<T>, String> {
+        if rate_beats <= BigDecimal::zero() {
+            return Err(format!("Automation rate must be positive, got {}", rate_beats));
+        }
+        match &shape {
+            AutomationShape::Breakpoints(points) if points.is_empty() => {
+                return Err("Automation needs at least one breakpoint".to_string());
+            }
+            AutomationShape::Lfo { period_beats, .. } if *period_beats <= BigDecimal::zero() => {
+                return Err(format!("LFO period must be positive, got {}", period_beats));
+            }
+            _ => {}
+        }
+        Ok(AutomationLane { contents, shape, rate_beats })
+    }
+
+    // Beats the lane needs to play out once: up to its last breakpoint, or one LFO period
+    pub fn length_beats(&self) -> BigDecimal {
+        match &self.shape {
+            AutomationShape::Breakpoints(points) => points.iter()
+                .map(|(beat, _)| beat.clone())
+                .max()
+                .unwrap_or_default(),
+            AutomationShape::Lfo { period_beats, .. } => period_beats.clone(),
+        }
+    }
+}
+
+// The shape of a lane with its beats converted to ticks
+#[derive(Debug, Clone)]
+enum Curve {
+    Breakpoints(Vec<(Ticks, f32)>), // Sorted by tick
+    Lfo { waveform: Waveform, period_ticks: Ticks, min: f32, max: f32, phase: f32 },
+}
+
+/*
+    A lane as queued on a sequencer, converted to ticks once rather than on every sample.
+*/
+#[derive(Debug, Clone)]
+pub struct QueuedLane<T: Clone> {
+    pub contents: T,
+    pub rate_ticks: Ticks,
+    curve: Curve,
+}
+
+impl<T: Clone> QueuedLane<T> {
+    pub fn new(lane: AutomationLane<T>) -> QueuedLane<T> {
+        let curve = match lane.shape {
+            AutomationShape::Breakpoints(points) => {
+                let mut points: Vec<(Ticks, f32)> = points.iter()
+                    .map(|(beat, value)| (ticks::from_beats(beat), *value))
+                    .collect();
+                // Stable, so that of two breakpoints on the same beat the later one holds from there on
+                points.sort_by_key(|(tick, _)| *tick);
+                Curve::Breakpoints(points)
+            }
+            AutomationShape::Lfo { waveform, period_beats, min, max, phase } => Curve::Lfo {
+                waveform,
+                period_ticks: ticks::from_beats(&period_beats).max(1),
+                min,
+                max,
+                phase,
+            },
+        };
+
+        QueuedLane {
+            contents: lane.contents,
+            rate_ticks: ticks::from_beats(&lane.rate_beats).max(1),
+            curve,
+        }
+    }
+
+    // Value of the lane at the given tick from the start of the sequence
+    pub fn value_at(&self, tick: Ticks) -> f32 {
+        match &self.curve {
+            Curve::Breakpoints(points) => {
+                let next = points.partition_point(|(point_tick, _)| *point_tick <= tick);
+                match (next.checked_sub(1).map(|index| points[index]), points.get(next)) {
+                    (Some((from_tick, from)), Some((to_tick, to))) => {
+                        let share = (tick - from_tick) as f64 / (to_tick - from_tick) as f64;
+                        (from as f64 + (*to as f64 - from as f64) * share) as f32
+                    }
+                    (Some((_, value)), None) => value,
+                    (None, Some((_, value))) => *value,
+                    (None, None) => 0.0,
+                }
+            }
+            Curve::Lfo { waveform, period_ticks, min, max, phase } => {
+                let cycle = (tick.rem_euclid(*period_ticks) as f64 / *period_ticks as f64 + *phase as f64).rem_euclid(1.0);
+                (*min as f64 + (*max as f64 - *min as f64) * waveform.unit_at(cycle)) as f32
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bigdecimal::BigDecimal;
+
+    use super::{AutomationLane, AutomationShape, QueuedLane, Waveform};
+    use crate::ticks;
+
+    fn big(inp: &str) -> BigDecimal {
+        BigDecimal::from_str(inp).unwrap()
+    }
+
+    fn value_at(lane: &QueuedLane<&str>, beat: &str) -> f32 {
+        lane.value_at(ticks::from_beats(&big(beat)))
+    }
+
+    #[test]
+    fn breakpoints_test() {
+        let shape = AutomationShape::Breakpoints(vec![(big("2.0"), 0.0), (big("1.0"), 100.0), (big("4.0"), 50.0)]);
+        let lane = AutomationLane::new("cutoff", shape, big("0.25")).unwrap();
+        assert_eq!(lane.length_beats(), big("4.0"));
+
+        let lane = QueuedLane::new(lane);
+        assert_eq!(value_at(&lane, "0.0"), 100.0);
+        assert_eq!(value_at(&lane, "1.5"), 50.0);
+        assert_eq!(value_at(&lane, "2.0"), 0.0);
+        assert_eq!(value_at(&lane, "3.0"), 25.0);
+        assert_eq!(value_at(&lane, "6.0"), 50.0);
+    }
+
+    #[test]
+    fn lfo_test() {
+        let lfo = |waveform: Waveform, phase: f32| {
+            let shape = AutomationShape::Lfo { waveform, period_beats: big("2.0"), min: 10.0, max: 20.0, phase };
+            QueuedLane::new(AutomationLane::new("cutoff", shape, big("0.1")).unwrap())
+        };
+
+        let sine = lfo(Waveform::Sine, 0.0);
+        assert_eq!(value_at(&sine, "0.0"), 15.0);
+        assert_eq!(value_at(&sine, "0.5"), 20.0);
+        assert_eq!(value_at(&sine, "1.5"), 10.0);
+
+        let triangle = lfo(Waveform::Triangle, 0.25);
+        assert_eq!(value_at(&triangle, "0.0"), 15.0);
+        assert_eq!(value_at(&triangle, "0.5"), 20.0);
+        assert_eq!(value_at(&triangle, "2.5"), 20.0);
+
+        assert_eq!(value_at(&lfo(Waveform::Saw, 0.0), "1.0"), 15.0);
+        assert_eq!(value_at(&lfo(Waveform::Square, 0.0), "1.0"), 10.0);
+    }
+
+    #[test]
+    fn invalid_lane_test() {
+        assert!(AutomationLane::new("cutoff", AutomationShape::Breakpoints(vec![]), big("0.25")).is_err());
+        assert!(AutomationLane::new("cutoff", AutomationShape::Breakpoints(vec![(big("0.0"), 1.0)]), big("0.0")).is_err());
+    }
+}
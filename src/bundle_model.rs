@@ -1,8 +1,19 @@
+use std::str::FromStr;
+
+use bigdecimal::{BigDecimal, FromPrimitive, Zero};
 use log::warn;
-use rosc::OscPacket;
+use rosc::{OscBundle, OscMessage, OscPacket, OscType};
 
 use jdw_osc_lib::model::{OscArgHandler, TaggedBundle, TimedOSCPacket};
 
+use crate::automation::{AutomationLane, AutomationShape, Waveform};
+use crate::generators::{ArpMode, GeneratorSpec};
+use crate::groove::Groove;
+use crate::random;
+use crate::sequencer::{EntryAttributes, EntryCondition};
+use crate::sequencing_daemon::{Nudge, Variable};
+use crate::transform::Transform;
+
 
 /*
 
@@ -10,9 +21,9 @@ use jdw_osc_lib::model::{OscArgHandler, TaggedBundle, TimedOSCPacket};
 
 */
 
-// Just a collection of the below, useful for wiping non-mentioned queues 
-// TODO: Usage: (1) receive, (2) mark all non-contained aliases as ending on next loop, (3) make sure newly queued aliases also remove that flag if there 
-// NOTE: Might also be viable, if batches are very large, to simply send "stop queue" messages for everything and then requeue
+// Just a collection of the below, useful for wiping non-mentioned queues.
+// With stop_missing, aliases not in the batch end after their current loop, while the batch is queued as usual
+//  (which also clears any earlier end flag on the aliases it contains). The batch is applied on a single tick.
 /*
     Tagged bundle example: 
     [info: batch_update_queue]
@@ -60,14 +71,35 @@ impl BatchUpdateQueuesMessage {
 /*
     Tagged bundle example: 
     [info: update_queue]
-    [update_queue_info: "my_alias"]
+    [update_queue_info: "my_alias", one_shot_flag, (optional) seed]
     bundle: [timed_msg_bundle, timed_msg_bundle ...]
+    (optional) [route_info: "output_name", "output_name" ...]
+    (optional) bundle: [automation_bundle, automation_bundle ...]
+
+    Each timed_msg bundle may carry an extra message after its packet with per-entry attributes 
+        as name/value pairs, e.g. 
+    [entry_attributes: "prob", 0.5, "every", 4, "every_offset", 3, "humanize", "0.02", "rand_amp", 0.1]
+    Supported names: 
+        prob          - chance (0.0 - 1.0) of playing on each loop
+        every         - only play every n:th loop (with every_offset selecting which, default 0)
+        skip_first    - 1 to not play on the first loop
+        first_only    - 1 to only play on the first loop
+        humanize      - max random offset of the trigger beat, in beats
+        rand_<arg>    - max random offset of the numeric named arg <arg>
              
 */
 pub struct UpdateQueueMessage {
     pub alias: String,
     pub one_shot: bool,
-    pub messages: Vec<TimedOSCPacket>,
+    pub seed: Option<u64>,
+    pub messages: Vec<QueueEntryMessage>,
+    pub outputs: Option<Vec<String>>,
+    pub lanes: Vec<AutomationLane<OscPacket>>,
+}
+
+pub struct QueueEntryMessage {
+    pub timed_packet: TimedOSCPacket,
+    pub attributes: EntryAttributes,
 }
 
 impl UpdateQueueMessage {
@@ -82,25 +114,542 @@ impl UpdateQueueMessage {
         let alias = info_msg.get_string_at(0, "alias")?;
         let one_shot_flag = info_msg.get_int_at(1, "one_shot_flag").unwrap_or(0);
         let one_shot = if one_shot_flag == 1 {true} else {false};
+        let seed = match info_msg.args.get(2) {
+            Some(_) => Some(get_count_at(&info_msg, 2, "seed")? as u64),
+            None => None,
+        };
 
         let msg_bundle = bundle.get_bundle(1)?;
-        let mut contained_timed_messages: Vec<TimedOSCPacket> = Vec::new();
+        let mut contained_timed_messages: Vec<QueueEntryMessage> = Vec::new();
         for packet in msg_bundle.content {
             match packet {
                 OscPacket::Bundle(bun) => {
                     let tagged_bun = TaggedBundle::new(&bun)?;
+                    let attributes = match tagged_bun.get_message(2) {
+                        Ok(attr_msg) if attr_msg.addr == "/entry_attributes" => parse_entry_attributes(&attr_msg)?,
+                        _ => EntryAttributes::default(),
+                    };
                     let timed_message = TimedOSCPacket::from_bundle(tagged_bun)?;
-                    contained_timed_messages.push(timed_message);
+                    contained_timed_messages.push(QueueEntryMessage {
+                        timed_packet: timed_message,
+                        attributes,
+                    });
                 },
                 _ => warn!("Found a non-bundle in the update queue"),
             }
         }
 
+        let outputs = get_route_info(&bundle, 2)?;
+        let lanes_index = if outputs.is_some() { 3 } else { 2 };
+        let mut lanes: Vec<AutomationLane<OscPacket>> = Vec::new();
+        if let Ok(lanes_bundle) = bundle.get_bundle(lanes_index) {
+            for packet in lanes_bundle.content {
+                match packet {
+                    OscPacket::Bundle(bun) => lanes.push(parse_automation_lane(TaggedBundle::new(&bun)?)?),
+                    _ => warn!("Found a non-bundle among the automation lanes"),
+                }
+            }
+        }
+
         Ok(UpdateQueueMessage {
             alias,
             one_shot,
-            messages: contained_timed_messages
+            seed,
+            messages: contained_timed_messages,
+            outputs,
+            lanes,
         })
        
     }
-}
\ No newline at end of file
+}
+
+/*
+    Tagged bundle example:
+    [info: automation]
+    [automation_info: rate_beats]
+    [shape message, one of:
+        /breakpoints beat value beat value ...
+        /lfo waveform period_beats min max (optional) phase
+    ]
+    packet to send on each sample, with the sampled value appended as a float
+
+    Waveforms: sine, triangle, saw, square. Phase is in cycles (0.0 - 1.0).
+*/
+fn parse_automation_lane(bundle: TaggedBundle) -> Result<AutomationLane<OscPacket>, String> {
+    if &bundle.bundle_tag != "automation" {
+        return Err(format!("Attempted to parse {} as automation bundle", &bundle.bundle_tag));
+    }
+
+    let info_msg = bundle.get_message(0)?;
+    info_msg.expect_addr("/automation_info")?;
+    let rate_beats = get_beats_at(&info_msg, 0, "rate_beats")?;
+
+    let shape_msg = bundle.get_message(1)?;
+    let shape = match shape_msg.addr.as_str() {
+        "/breakpoints" => {
+            if shape_msg.args.len() % 2 != 0 {
+                return Err("Breakpoints must come in beat/value pairs".to_string());
+            }
+            let mut points = vec![];
+            for index in (0..shape_msg.args.len()).step_by(2) {
+                points.push((get_beats_at(&shape_msg, index, "beat")?, get_number_at(&shape_msg, index + 1, "value")?));
+            }
+            AutomationShape::Breakpoints(points)
+        }
+        "/lfo" => AutomationShape::Lfo {
+            waveform: Waveform::from_str(&shape_msg.get_string_at(0, "waveform")?)?,
+            period_beats: get_beats_at(&shape_msg, 1, "period_beats")?,
+            min: get_number_at(&shape_msg, 2, "min")?,
+            max: get_number_at(&shape_msg, 3, "max")?,
+            phase: if shape_msg.args.len() > 4 { get_number_at(&shape_msg, 4, "phase")? } else { 0.0 },
+        },
+        other => return Err(format!("Unknown automation shape: {}", other)),
+    };
+
+    let contents = match bundle.get_message(2) {
+        Ok(msg) => OscPacket::Message(msg),
+        Err(_) => OscPacket::Bundle(bundle.get_bundle(2)?),
+    };
+
+    AutomationLane::new(contents, shape, rate_beats)
+}
+
+fn parse_entry_attributes(msg: &OscMessage) -> Result<EntryAttributes, String> {
+    let mut attributes = EntryAttributes::default();
+    let mut every_n: Option<u64> = None;
+    let mut every_offset: u64 = 0;
+
+    for index in (0..msg.args.len()).step_by(2) {
+        let name = msg.get_string_at(index, "attribute name")?;
+        let value_index = index + 1;
+        match name.as_str() {
+            "prob" => attributes.probability = Some(get_number_at(msg, value_index, &name)?),
+            "every" => every_n = Some(get_count_at(msg, value_index, &name)? as u64),
+            "every_offset" => every_offset = get_count_at(msg, value_index, &name)? as u64,
+            "skip_first" => if get_count_at(msg, value_index, &name)? == 1 {
+                attributes.conditions.push(EntryCondition::SkipFirstLoop);
+            },
+            "first_only" => if get_count_at(msg, value_index, &name)? == 1 {
+                attributes.conditions.push(EntryCondition::FirstLoopOnly);
+            },
+            "humanize" => attributes.humanize_beats = Some(get_beats_at(msg, value_index, &name)?),
+            _ => match name.strip_prefix("rand_") {
+                Some(arg_name) => attributes.arg_randomization.push((arg_name.to_string(), get_number_at(msg, value_index, &name)?)),
+                None => return Err(format!("Unknown entry attribute: {}", name)),
+            }
+        }
+    }
+
+    if let Some(n) = every_n {
+        attributes.conditions.push(EntryCondition::EveryNthLoop { n, offset: every_offset });
+    }
+
+    Ok(attributes)
+}
+
+/*
+    Tagged bundle example:
+    [info: generate_queue]
+    [generate_queue_info: "my_alias", one_shot_flag, (optional) seed]
+    [generator message, one of:
+        /euclid hits steps rotation step_beats
+        /polyrhythm length_beats divisions...
+        /random_density steps step_beats density seed
+        /arpeggio mode steps step_beats arg_name notes...
+    ]
+    bundle: [packet, packet ...]
+    (optional) [route_info: "output_name", "output_name" ...]
+
+    Beat values may be given as strings ("0.25") for exact parsing, or as numbers.
+    The seed is used by random arpeggios, which are different every time without one.
+*/
+pub struct GenerateQueueMessage {
+    pub alias: String,
+    pub one_shot: bool,
+    pub generator: GeneratorSpec,
+    pub packets: Vec<OscPacket>,
+    pub outputs: Option<Vec<String>>,
+}
+
+impl GenerateQueueMessage {
+    pub fn from_bundle(bundle: TaggedBundle) -> Result<GenerateQueueMessage, String> {
+        if &bundle.bundle_tag != "generate_queue" {
+            return Err(format!("Attempted to parse {} as generate_queue bundle", &bundle.bundle_tag));
+        }
+
+        let info_msg = bundle.get_message(0)?;
+        info_msg.expect_addr("/generate_queue_info")?;
+        let alias = info_msg.get_string_at(0, "alias")?;
+        let one_shot_flag = info_msg.get_int_at(1, "one_shot_flag").unwrap_or(0);
+        let seed = match info_msg.args.get(2) {
+            Some(_) => get_count_at(&info_msg, 2, "seed")? as u64,
+            None => random::fresh_seed(),
+        };
+
+        let generator_msg = bundle.get_message(1)?;
+        let generator = match generator_msg.addr.as_str() {
+            "/euclid" => GeneratorSpec::Euclid {
+                hits: get_count_at(&generator_msg, 0, "hits")?,
+                steps: get_count_at(&generator_msg, 1, "steps")?,
+                rotation: get_count_at(&generator_msg, 2, "rotation")?,
+                step_beats: get_beats_at(&generator_msg, 3, "step_beats")?,
+            },
+            "/polyrhythm" => {
+                let mut divisions = vec![];
+                for index in 1..generator_msg.args.len() {
+                    divisions.push(get_count_at(&generator_msg, index, "divisions")?);
+                }
+                GeneratorSpec::Polyrhythm {
+                    length_beats: get_beats_at(&generator_msg, 0, "length_beats")?,
+                    divisions,
+                }
+            }
+            "/random_density" => GeneratorSpec::RandomDensity {
+                steps: get_count_at(&generator_msg, 0, "steps")?,
+                step_beats: get_beats_at(&generator_msg, 1, "step_beats")?,
+                density: get_number_at(&generator_msg, 2, "density")?,
+                seed: get_count_at(&generator_msg, 3, "seed").unwrap_or(0) as u64,
+            },
+            "/arpeggio" => {
+                let mode_name = generator_msg.get_string_at(0, "mode")?;
+                let mut notes = vec![];
+                for index in 4..generator_msg.args.len() {
+                    notes.push(get_number_at(&generator_msg, index, "notes")?);
+                }
+                GeneratorSpec::Arpeggio {
+                    mode: match ArpMode::from_str(&mode_name)? {
+                        ArpMode::Random(_) => ArpMode::Random(seed),
+                        mode => mode,
+                    },
+                    steps: get_count_at(&generator_msg, 1, "steps")?,
+                    step_beats: get_beats_at(&generator_msg, 2, "step_beats")?,
+                    arg_name: generator_msg.get_string_at(3, "arg_name")?,
+                    notes,
+                }
+            }
+            other => return Err(format!("Unknown generator: {}", other)),
+        };
+
+        let packets = bundle.get_bundle(2)?.content;
+
+        Ok(GenerateQueueMessage {
+            alias,
+            one_shot: one_shot_flag == 1,
+            generator,
+            packets,
+            outputs: get_route_info(&bundle, 3)?,
+        })
+    }
+}
+
+/*
+    Groove control messages. The alias "*" targets the global groove used by all aliases without one of their own.
+    [/set_swing "my_alias", percent, (optional) resolution]   - MPC-style swing, 50 = straight, default resolution 0.25
+    [/set_groove "my_alias", resolution, offset, offset ...]  - per-step offset table in beats
+    [/clear_groove "my_alias"]
+*/
+pub struct SetGrooveMessage {
+    pub alias: Option<String>,
+    pub groove: Option<Groove>,
+}
+
+impl SetGrooveMessage {
+    pub fn from_message(msg: &OscMessage) -> Result<SetGrooveMessage, String> {
+        let alias_arg = msg.get_string_at(0, "alias")?;
+        let alias = if alias_arg == "*" { None } else { Some(alias_arg) };
+
+        let groove = match msg.addr.as_str() {
+            "/set_swing" => {
+                let percent = get_number_at(msg, 1, "percent")?;
+                let resolution = if msg.args.len() > 2 {
+                    get_beats_at(msg, 2, "resolution")?
+                } else {
+                    BigDecimal::from_str("0.25").unwrap()
+                };
+                Some(Groove::swing(percent, resolution)?)
+            }
+            "/set_groove" => {
+                let resolution = get_beats_at(msg, 1, "resolution")?;
+                let mut offsets = vec![];
+                for index in 2..msg.args.len() {
+                    offsets.push(get_signed_beats_at(msg, index, "offset")?);
+                }
+                Some(Groove::new(resolution, offsets)?)
+            }
+            "/clear_groove" => None,
+            other => return Err(format!("Not a groove message: {}", other)),
+        };
+
+        Ok(SetGrooveMessage { alias, groove })
+    }
+}
+
+/*
+    Per-alias latency compensation, applied when stamping the send time of real time packets.
+    Positive values delay, negative values advance.
+    [/set_nudge "my_alias", milliseconds]
+    [/set_nudge_beats "my_alias", beats]
+    [/clear_nudge "my_alias"]
+*/
+pub struct SetNudgeMessage {
+    pub alias: String,
+    pub nudge: Option<Nudge>,
+}
+
+impl SetNudgeMessage {
+    pub fn from_message(msg: &OscMessage) -> Result<SetNudgeMessage, String> {
+        let alias = msg.get_string_at(0, "alias")?;
+
+        let nudge = match msg.addr.as_str() {
+            "/set_nudge" => Some(Nudge::Millis(get_number_at(msg, 1, "milliseconds")? as f64)),
+            "/set_nudge_beats" => Some(Nudge::Beats(get_signed_beats_at(msg, 1, "beats")?)),
+            "/clear_nudge" => None,
+            other => return Err(format!("Not a nudge message: {}", other)),
+        };
+
+        Ok(SetNudgeMessage { alias, nudge })
+    }
+}
+
+/*
+    Per-alias variables, substituted for $name placeholders in the packets of the alias when they are sent.
+    Names of built-in placeholders (loop, beat, alias) cannot be set.
+    [/set_var "my_alias", "name", value (int, float or string)]
+    [/clear_var "my_alias", "name"]
+*/
+pub struct SetVariableMessage {
+    pub alias: String,
+    pub name: String,
+    pub value: Option<Variable>,
+}
+
+impl SetVariableMessage {
+    pub fn from_message(msg: &OscMessage) -> Result<SetVariableMessage, String> {
+        let alias = msg.get_string_at(0, "alias")?;
+        let name = msg.get_string_at(1, "name")?;
+        if ["loop", "beat", "alias"].contains(&name.as_str()) {
+            return Err(format!("{} is a built-in placeholder", name));
+        }
+
+        let value = match msg.addr.as_str() {
+            "/set_var" => Some(match msg.args.get(2) {
+                Some(OscType::Int(val)) => Variable::Int(*val),
+                Some(OscType::Float(val)) => Variable::Float(*val),
+                Some(OscType::Double(val)) => Variable::Float(*val as f32),
+                Some(OscType::String(val)) => Variable::Text(val.clone()),
+                other => return Err(format!("Expected value as int, float or string, got {:?}", other)),
+            }),
+            "/clear_var" => None,
+            other => return Err(format!("Not a variable message: {}", other)),
+        };
+
+        Ok(SetVariableMessage { alias, name, value })
+    }
+}
+
+/*
+    Per-alias transforms, applied to the packets of the alias when they are sent (see transform.rs).
+    Setting a transform replaces an earlier one of the same kind (and arg, or address prefix).
+    [/transform "my_alias", "transpose", semitones]
+    [/transform "my_alias", "offset", "arg_name", amount]
+    [/transform "my_alias", "scale", "arg_name", factor]
+    [/transform "my_alias", "prefix", "/from", "/to"]
+    [/clear_transforms "my_alias"]
+*/
+pub struct SetTransformMessage {
+    pub alias: String,
+    pub transform: Option<Transform>,
+}
+
+impl SetTransformMessage {
+    pub fn from_message(msg: &OscMessage) -> Result<SetTransformMessage, String> {
+        let alias = msg.get_string_at(0, "alias")?;
+
+        let transform = match msg.addr.as_str() {
+            "/transform" => Some(match msg.get_string_at(1, "kind")?.as_str() {
+                "transpose" => Transform::Transpose(get_number_at(msg, 2, "semitones")?),
+                "offset" => Transform::Offset {
+                    arg: msg.get_string_at(2, "arg")?,
+                    amount: get_number_at(msg, 3, "amount")?,
+                },
+                "scale" => Transform::Scale {
+                    arg: msg.get_string_at(2, "arg")?,
+                    factor: get_number_at(msg, 3, "factor")?,
+                },
+                "prefix" => {
+                    let from = msg.get_string_at(2, "from")?;
+                    let to = msg.get_string_at(3, "to")?;
+                    if !from.starts_with('/') || !to.starts_with('/') {
+                        return Err(format!("Address prefixes must start with /, got {} and {}", from, to));
+                    }
+                    Transform::Prefix { from, to }
+                }
+                other => return Err(format!("Unknown transform: {}", other)),
+            }),
+            "/clear_transforms" => None,
+            other => return Err(format!("Not a transform message: {}", other)),
+        };
+
+        Ok(SetTransformMessage { alias, transform })
+    }
+}
+
+/*
+    Route an alias to one or more of the configured outputs, or back to the default output.
+    [/route "my_alias", "output_name", "output_name" ...]
+    [/clear_route "my_alias"]
+*/
+pub struct RouteMessage {
+    pub alias: String,
+    pub outputs: Option<Vec<String>>,
+}
+
+impl RouteMessage {
+    pub fn from_message(msg: &OscMessage) -> Result<RouteMessage, String> {
+        let alias = msg.get_string_at(0, "alias")?;
+
+        let outputs = match msg.addr.as_str() {
+            "/route" => Some(get_strings_from(msg, 1, "output")?),
+            "/clear_route" => None,
+            other => return Err(format!("Not a route message: {}", other)),
+        };
+
+        Ok(RouteMessage { alias, outputs })
+    }
+}
+
+/*
+    Wrapper for any command that wants a reply. The id is echoed back in an
+        [ack: id] or [error: id, "reason"] message to the sender.
+
+    Bundle example:
+    [bundle_info: "request"]
+    [request_info: id (string or int)]
+    command (message or bundle)
+*/
+pub struct RequestMessage {
+    pub id: OscType,
+    pub packet: OscPacket,
+}
+
+impl RequestMessage {
+    pub fn is_request(bundle: &OscBundle) -> bool {
+        match bundle.content.first() {
+            Some(OscPacket::Message(msg)) => {
+                msg.addr == "/bundle_info" && msg.args.first() == Some(&OscType::String("request".to_string()))
+            }
+            _ => false,
+        }
+    }
+
+    pub fn from_bundle(bundle: &OscBundle) -> Result<RequestMessage, String> {
+        if !RequestMessage::is_request(bundle) {
+            return Err("Attempted to parse a non-request bundle as request".to_string());
+        }
+
+        let id = match bundle.content.get(1) {
+            Some(OscPacket::Message(msg)) if msg.addr == "/request_info" => msg.args.first().cloned()
+                .ok_or("Missing id in request_info".to_string())?,
+            _ => return Err("Missing request_info message in request".to_string()),
+        };
+
+        let packet = bundle.content.get(2).cloned()
+            .ok_or("Missing command in request".to_string())?;
+
+        Ok(RequestMessage { id, packet })
+    }
+}
+
+// Optional /route_info message at the given index of a queue bundle
+fn get_route_info(bundle: &TaggedBundle, index: usize) -> Result<Option<Vec<String>>, String> {
+    match bundle.get_message(index) {
+        Ok(msg) if msg.addr == "/route_info" => Ok(Some(get_strings_from(&msg, 0, "output")?)),
+        _ => Ok(None),
+    }
+}
+
+fn get_strings_from(msg: &OscMessage, start: usize, name: &str) -> Result<Vec<String>, String> {
+    let strings = (start..msg.args.len())
+        .map(|index| msg.get_string_at(index, name))
+        .collect::<Result<Vec<String>, String>>()?;
+
+    if strings.is_empty() {
+        return Err(format!("Expected at least one {}", name));
+    }
+    Ok(strings)
+}
+
+fn get_number_at(msg: &OscMessage, index: usize, name: &str) -> Result<f32, String> {
+    match msg.args.get(index) {
+        Some(OscType::Float(val)) => Ok(*val),
+        Some(OscType::Double(val)) => Ok(*val as f32),
+        Some(OscType::Int(val)) => Ok(*val as f32),
+        other => Err(format!("Expected {} at index {} as a number, got {:?}", name, index, other)),
+    }
+}
+
+fn get_count_at(msg: &OscMessage, index: usize, name: &str) -> Result<u32, String> {
+    let val = msg.get_int_at(index, name)?;
+    if val < 0 {
+        return Err(format!("{} must not be negative, got {}", name, val));
+    }
+    Ok(val as u32)
+}
+
+// Beat values arrive either as strings (exact, e.g. "0.25") or as numbers
+fn get_beats_at(msg: &OscMessage, index: usize, name: &str) -> Result<BigDecimal, String> {
+    let beats = get_signed_beats_at(msg, index, name)?;
+
+    if beats < BigDecimal::zero() {
+        return Err(format!("{} must not be negative", name));
+    }
+
+    Ok(beats)
+}
+
+fn get_signed_beats_at(msg: &OscMessage, index: usize, name: &str) -> Result<BigDecimal, String> {
+    let arg = msg
+        .args
+        .get(index)
+        .ok_or(format!("Missing arg {} at index {}", name, index))?;
+
+    match arg {
+        OscType::String(val) => BigDecimal::from_str(val)
+            .map_err(|e| format!("Could not parse {} '{}' as beats: {}", name, val, e)),
+        OscType::Float(val) => BigDecimal::from_f32(*val).ok_or(format!("Invalid {}: {}", name, val)),
+        OscType::Double(val) => BigDecimal::from_f64(*val).ok_or(format!("Invalid {}: {}", name, val)),
+        OscType::Int(val) => Ok(BigDecimal::from(*val)),
+        _ => Err(format!("Expected {} as string or number, got {:?}", name, arg)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jdw_osc_lib::model::TaggedBundle;
+    use rosc::{OscPacket, OscType};
+
+    use super::UpdateQueueMessage;
+    use crate::client;
+
+    fn parse_with_seed(seed: Option<OscType>) -> Result<UpdateQueueMessage, String> {
+        let mut bundle = match client::update_queue_bundle("drums", vec![], false) {
+            OscPacket::Bundle(bundle) => bundle,
+            _ => unreachable!(),
+        };
+        if let (Some(seed), OscPacket::Message(info)) = (seed, &mut bundle.content[1]) {
+            info.args.push(seed);
+        }
+        UpdateQueueMessage::from_bundle(TaggedBundle::new(&bundle)?)
+    }
+
+    #[test]
+    fn seed_test() {
+        assert_eq!(parse_with_seed(None).unwrap().seed, None);
+        assert_eq!(parse_with_seed(Some(OscType::Int(7))).unwrap().seed, Some(7));
+
+        // A seed that is given but unusable is an error rather than silently replaced
+        assert_eq!(parse_with_seed(Some(OscType::Int(-1))).err().unwrap(), "seed must not be negative, got -1");
+        assert!(parse_with_seed(Some(OscType::String("7".to_string()))).is_err());
+        assert!(parse_with_seed(Some(OscType::Float(7.0))).is_err());
+    }
+}
@@ -0,0 +1,599 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use bigdecimal::BigDecimal;
+use rosc::{decoder, encoder, OscBundle, OscMessage, OscPacket, OscTime, OscType};
+
+use crate::automation::{AutomationLane, AutomationShape};
+use crate::master_sequencer::SequencerStatus;
+use crate::slip::{self, SlipDecoder};
+use crate::transform::Transform;
+
+/*
+
+    Client for controlling a running sequencer over UDP or TCP, building the same bundles bundle_model parses.
+    Replies over UDP must fit in a single datagram of up to 64 KiB; connect_tcp has no such limit, e.g. for
+        the state of a great many sequencers.
+
+    Every command is wrapped in a "request" bundle (see RequestMessage) and waits for the daemon
+        to /ack it, so a rejected or lost command comes back as an error instead of silence.
+
+*/
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(1000);
+// The largest UDP payload
+const MAX_DATAGRAM: usize = 65_536;
+
+// A packet and the number of beats until the next entry, as in a timed_msg bundle
+#[derive(Debug, Clone)]
+pub struct QueueEntry {
+    pub time: BigDecimal,
+    pub packet: OscPacket,
+}
+
+impl QueueEntry {
+    pub fn new(time: BigDecimal, packet: OscPacket) -> QueueEntry {
+        QueueEntry { time, packet }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SequencerState {
+    pub bpm: i32,
+    pub running: bool,
+    pub sequencers: Vec<SequencerStatus>,
+}
+
+enum Transport {
+    Udp(UdpSocket),
+    // SLIP-framed, keeping frames read past the reply being waited for until the next read
+    Tcp(TcpStream, Mutex<(SlipDecoder, Vec<Vec<u8>>)>),
+}
+
+impl Transport {
+    fn send(&self, bytes: &[u8]) -> std::io::Result<()> {
+        match self {
+            Transport::Udp(socket) => socket.send(bytes).map(|_| ()),
+            Transport::Tcp(stream, _) => (&*stream).write_all(&slip::encode(bytes)),
+        }
+    }
+
+    // The next packet, or None if nothing came within the timeout
+    fn recv(&self, timeout: Duration) -> Result<Option<Vec<u8>>, String> {
+        match self {
+            Transport::Udp(socket) => {
+                socket.set_read_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+                let mut buf = vec![0u8; MAX_DATAGRAM];
+                match socket.recv(&mut buf) {
+                    Ok(size) => {
+                        buf.truncate(size);
+                        Ok(Some(buf))
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => Ok(None),
+                    Err(e) => Err(format!("Failed to receive reply: {}", e)),
+                }
+            }
+            Transport::Tcp(stream, state) => {
+                let mut state = state.lock().unwrap();
+                let (decoder, frames) = &mut *state;
+                if frames.is_empty() {
+                    stream.set_read_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+                    let mut buf = [0u8; 8192];
+                    match (&*stream).read(&mut buf) {
+                        Ok(0) => return Err("Sequencer closed the connection".to_string()),
+                        Ok(read) => frames.extend(decoder.push(&buf[..read])),
+                        Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => return Ok(None),
+                        Err(e) => return Err(format!("Failed to receive reply: {}", e)),
+                    }
+                }
+                Ok(if frames.is_empty() { None } else { Some(frames.remove(0)) })
+            }
+        }
+    }
+}
+
+pub struct SequencerClient {
+    transport: Transport,
+    timeout: Duration,
+    next_id: AtomicI32,
+}
+
+impl SequencerClient {
+    // Connect to the daemon UDP port, e.g. "127.0.0.1:14441"
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<SequencerClient, String> {
+        let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| format!("Failed to bind client socket: {}", e))?;
+        socket.connect(addr).map_err(|e| format!("Failed to connect to sequencer: {}", e))?;
+        Ok(SequencerClient::new(Transport::Udp(socket)))
+    }
+
+    // Connect to the daemon TCP port instead, e.g. "127.0.0.1:14442"
+    pub fn connect_tcp<A: ToSocketAddrs>(addr: A) -> Result<SequencerClient, String> {
+        let stream = TcpStream::connect(addr).map_err(|e| format!("Failed to connect to sequencer: {}", e))?;
+        stream.set_nodelay(true).map_err(|e| format!("Failed to connect to sequencer: {}", e))?;
+        Ok(SequencerClient::new(Transport::Tcp(stream, Mutex::new((SlipDecoder::new(), vec![])))))
+    }
+
+    fn new(transport: Transport) -> SequencerClient {
+        SequencerClient {
+            transport,
+            timeout: DEFAULT_TIMEOUT,
+            next_id: AtomicI32::new(1),
+        }
+    }
+
+    // How long to wait for the daemon to reply to a command
+    pub fn with_timeout(mut self, timeout: Duration) -> SequencerClient {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn queue(&self, alias: &str, entries: Vec<QueueEntry>, one_shot: bool) -> Result<(), String> {
+        self.request(update_queue_bundle(alias, entries, one_shot), None).map(|_| ())
+    }
+
+    // As queue, with automation lanes that loop along with the entries
+    pub fn queue_with_lanes(&self, alias: &str, entries: Vec<QueueEntry>, lanes: Vec<AutomationLane<OscPacket>>, one_shot: bool) -> Result<(), String> {
+        self.request(update_queue_bundle_with_lanes(alias, entries, lanes, one_shot), None).map(|_| ())
+    }
+
+    pub fn set_bpm(&self, bpm: i32) -> Result<(), String> {
+        self.request(message("/set_bpm", vec![OscType::Int(bpm)]), None).map(|_| ())
+    }
+
+    // Set a variable of the alias, sent in place of $name placeholders in its packets
+    pub fn set_var(&self, alias: &str, name: &str, value: OscType) -> Result<(), String> {
+        let args = vec![OscType::String(alias.to_string()), OscType::String(name.to_string()), value];
+        self.request(message("/set_var", args), None).map(|_| ())
+    }
+
+    pub fn clear_var(&self, alias: &str, name: &str) -> Result<(), String> {
+        let args = vec![OscType::String(alias.to_string()), OscType::String(name.to_string())];
+        self.request(message("/clear_var", args), None).map(|_| ())
+    }
+
+    // Add a transform to the packets of the alias, replacing one of the same kind
+    pub fn transform(&self, alias: &str, transform: &Transform) -> Result<(), String> {
+        let mut args = vec![OscType::String(alias.to_string())];
+        args.extend(transform_args(transform));
+        self.request(message("/transform", args), None).map(|_| ())
+    }
+
+    pub fn clear_transforms(&self, alias: &str) -> Result<(), String> {
+        self.request(message("/clear_transforms", vec![OscType::String(alias.to_string())]), None).map(|_| ())
+    }
+
+    pub fn stop(&self) -> Result<(), String> {
+        self.request(message("/hard_stop", vec![]), None).map(|_| ())
+    }
+
+    pub fn reset(&self) -> Result<(), String> {
+        self.request(message("/reset_all", vec![]), None).map(|_| ())
+    }
+
+    // Stop the daemon; it replies before shutting down
+    pub fn shutdown(&self) -> Result<(), String> {
+        self.request(message("/shutdown", vec![]), None).map(|_| ())
+    }
+
+    pub fn protocol_version(&self) -> Result<String, String> {
+        let reply = self.query(message("/protocol_version", vec![]), "/protocol_version")?;
+        match reply.args.first() {
+            Some(OscType::String(version)) => Ok(version.clone()),
+            other => Err(format!("Invalid protocol version reply: {:?}", other)),
+        }
+    }
+
+    pub fn state(&self) -> Result<SequencerState, String> {
+        parse_state(&self.query(message("/get_state", vec![]), "/state")?)
+    }
+
+    fn query(&self, packet: OscPacket, result_addr: &str) -> Result<OscMessage, String> {
+        self.request(packet, Some(result_addr))?
+            .ok_or_else(|| format!("No {} reply from sequencer", result_addr))
+    }
+
+    // Send a command as a request and wait for its /ack, collecting the result message with the given address if any
+    fn request(&self, packet: OscPacket, result_addr: Option<&str>) -> Result<Option<OscMessage>, String> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let bytes = encoder::encode(&request_bundle(id, packet))
+            .map_err(|e| format!("Failed to encode request: {}", e))?;
+        self.transport.send(&bytes).map_err(|e| format!("Failed to send request: {}", e))?;
+
+        let deadline = Instant::now() + self.timeout;
+        let mut result: Option<OscMessage> = None;
+
+        loop {
+            let remaining = deadline
+                .checked_duration_since(Instant::now())
+                .filter(|remaining| !remaining.is_zero())
+                .ok_or_else(|| format!("No reply from sequencer to request {} within {:?}", id, self.timeout))?;
+
+            let bytes = match self.transport.recv(remaining)? {
+                Some(bytes) => bytes,
+                None => continue,
+            };
+
+            let msg = match decoder::decode_udp(&bytes) {
+                Ok((_, OscPacket::Message(msg))) => msg,
+                _ => continue,
+            };
+
+            // Replies to earlier, timed out requests may still arrive; only ours count
+            let is_ours = msg.args.first() == Some(&OscType::Int(id));
+            match msg.addr.as_str() {
+                "/ack" if is_ours => return Ok(result),
+                "/error" if is_ours => {
+                    return Err(match msg.args.get(1) {
+                        Some(OscType::String(reason)) => reason.clone(),
+                        _ => "Request failed".to_string(),
+                    })
+                }
+                addr if Some(addr) == result_addr => result = Some(msg),
+                _ => {}
+            }
+        }
+    }
+}
+
+fn message(addr: &str, args: Vec<OscType>) -> OscPacket {
+    OscPacket::Message(OscMessage {
+        addr: addr.to_string(),
+        args,
+    })
+}
+
+fn transform_args(transform: &Transform) -> Vec<OscType> {
+    let string = |val: &str| OscType::String(val.to_string());
+    match transform {
+        Transform::Transpose(semitones) => vec![string("transpose"), OscType::Float(*semitones)],
+        Transform::Offset { arg, amount } => vec![string("offset"), string(arg), OscType::Float(*amount)],
+        Transform::Scale { arg, factor } => vec![string("scale"), string(arg), OscType::Float(*factor)],
+        Transform::Prefix { from, to } => vec![string("prefix"), string(from), string(to)],
+    }
+}
+
+fn bundle(content: Vec<OscPacket>) -> OscPacket {
+    OscPacket::Bundle(OscBundle {
+        timetag: OscTime { seconds: 0, fractional: 1 },
+        content,
+    })
+}
+
+fn tagged_bundle(tag: &str, mut content: Vec<OscPacket>) -> OscPacket {
+    content.insert(0, message("/bundle_info", vec![OscType::String(tag.to_string())]));
+    bundle(content)
+}
+
+pub fn request_bundle(id: i32, packet: OscPacket) -> OscPacket {
+    tagged_bundle("request", vec![message("/request_info", vec![OscType::Int(id)]), packet])
+}
+
+pub fn update_queue_bundle(alias: &str, entries: Vec<QueueEntry>, one_shot: bool) -> OscPacket {
+    update_queue_bundle_with_lanes(alias, entries, vec![], one_shot)
+}
+
+pub fn update_queue_bundle_with_lanes(
+    alias: &str,
+    entries: Vec<QueueEntry>,
+    lanes: Vec<AutomationLane<OscPacket>>,
+    one_shot: bool,
+) -> OscPacket {
+    let timed_messages = entries
+        .into_iter()
+        .map(|entry| {
+            tagged_bundle(
+                "timed_msg",
+                vec![
+                    message("/timed_msg_info", vec![OscType::String(entry.time.to_string())]),
+                    entry.packet,
+                ],
+            )
+        })
+        .collect();
+
+    let mut content = vec![
+        message(
+            "/update_queue_info",
+            vec![OscType::String(alias.to_string()), OscType::Int(if one_shot { 1 } else { 0 })],
+        ),
+        bundle(timed_messages),
+    ];
+    if !lanes.is_empty() {
+        content.push(bundle(lanes.into_iter().map(automation_bundle).collect()));
+    }
+
+    tagged_bundle("update_queue", content)
+}
+
+pub fn automation_bundle(lane: AutomationLane<OscPacket>) -> OscPacket {
+    let shape = match lane.shape {
+        AutomationShape::Breakpoints(points) => message(
+            "/breakpoints",
+            points
+                .into_iter()
+                .flat_map(|(beat, value)| vec![OscType::String(beat.to_string()), OscType::Float(value)])
+                .collect(),
+        ),
+        AutomationShape::Lfo { waveform, period_beats, min, max, phase } => message(
+            "/lfo",
+            vec![
+                OscType::String(waveform.name().to_string()),
+                OscType::String(period_beats.to_string()),
+                OscType::Float(min),
+                OscType::Float(max),
+                OscType::Float(phase),
+            ],
+        ),
+    };
+
+    tagged_bundle(
+        "automation",
+        vec![
+            message("/automation_info", vec![OscType::String(lane.rate_beats.to_string())]),
+            shape,
+            lane.contents,
+        ],
+    )
+}
+
+// Parse a /state reply, see protocol::state_message
+fn parse_state(msg: &OscMessage) -> Result<SequencerState, String> {
+    let invalid = || format!("Invalid state reply: {:?}", msg.args);
+
+    let (bpm, running) = match (msg.args.first(), msg.args.get(1)) {
+        (Some(OscType::Int(bpm)), Some(OscType::Bool(running))) => (*bpm, *running),
+        _ => return Err(invalid()),
+    };
+
+    let sequencers = msg.args[2..]
+        .chunks(6)
+        .map(|args| match args {
+            [OscType::String(alias), OscType::Bool(active), OscType::String(current_beat), OscType::String(end_beat), OscType::Long(loop_index), OscType::Bool(one_shot)] => {
+                Ok(SequencerStatus {
+                    alias: alias.clone(),
+                    active: *active,
+                    current_beat: BigDecimal::from_str(current_beat).map_err(|_| invalid())?,
+                    end_beat: BigDecimal::from_str(end_beat).map_err(|_| invalid())?,
+                    loop_index: *loop_index as u64,
+                    one_shot: *one_shot,
+                })
+            }
+            _ => Err(invalid()),
+        })
+        .collect::<Result<Vec<SequencerStatus>, String>>()?;
+
+    Ok(SequencerState {
+        bpm,
+        running,
+        sequencers,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, UdpSocket};
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use bigdecimal::BigDecimal;
+    use ringbuf::traits::{Consumer, Producer, Split};
+    use ringbuf::HeapRb;
+    use rosc::{OscMessage, OscPacket, OscType};
+
+    use super::{QueueEntry, SequencerClient};
+    use crate::automation::{AutomationLane, AutomationShape, Waveform};
+    use crate::control::ControlHandlers;
+    use crate::config::OutputConfig;
+    use crate::local_messaging::{LocalMessageSender, LocalSequencerMessage};
+    use crate::master_sequencer::SequencerStatus;
+    use crate::osc_communication::OSCClient;
+    use crate::sequencing_daemon::{DaemonStatus, Variable};
+    use crate::transform::Transform;
+    use crate::{tcp_communication, udp_communication};
+
+    fn note(value: i32) -> OscPacket {
+        OscPacket::Message(OscMessage {
+            addr: "/note".to_string(),
+            args: vec![OscType::Int(value)],
+        })
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let (producer, mut consumer) = HeapRb::<LocalSequencerMessage<OscPacket>>::new(16).split();
+        let status = Arc::new(Mutex::new(DaemonStatus::default()));
+        let control = Arc::new(ControlHandlers::new(
+            Arc::new(LocalMessageSender::new(producer, Duration::from_millis(10))),
+            Arc::new(OSCClient::from_outputs("127.0.0.1:0", &[]).unwrap()),
+            status.clone(),
+        ));
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let reader = thread::spawn(move || udp_communication::serve(socket, 4096, control));
+
+        let client = SequencerClient::connect(addr).unwrap();
+
+        let entries = vec![
+            QueueEntry::new(BigDecimal::from_str("0.5").unwrap(), note(60)),
+            QueueEntry::new(BigDecimal::from_str("1.5").unwrap(), note(64)),
+        ];
+        client.queue("bass", entries, true).unwrap();
+
+        match consumer.try_pop() {
+            Some(LocalSequencerMessage::Queue(payload)) => {
+                assert_eq!(payload.sequencer_alias, "bass");
+                assert!(payload.one_shot);
+                assert_eq!(payload.entries.len(), 2);
+                assert_eq!(payload.end_beat, BigDecimal::from_str("2.0").unwrap());
+            }
+            other => panic!("Expected a queue message, got {:?}", other),
+        }
+
+        // A lane longer than the entries sets the length of the sequence
+        let lfo = AutomationShape::Lfo {
+            waveform: Waveform::Triangle,
+            period_beats: BigDecimal::from(4),
+            min: 0.0,
+            max: 1.0,
+            phase: 0.0,
+        };
+        let lane = AutomationLane::new(note(1), lfo.clone(), BigDecimal::from_str("0.125").unwrap()).unwrap();
+        client.queue_with_lanes("filter", vec![QueueEntry::new(BigDecimal::from(1), note(60))], vec![lane], false).unwrap();
+
+        match consumer.try_pop() {
+            Some(LocalSequencerMessage::Queue(payload)) => {
+                assert_eq!(payload.lanes.len(), 1);
+                assert_eq!(payload.lanes[0].shape, lfo);
+                assert_eq!(payload.lanes[0].rate_beats, BigDecimal::from_str("0.125").unwrap());
+                assert_eq!(payload.end_beat, BigDecimal::from(4));
+            }
+            other => panic!("Expected a queue message, got {:?}", other),
+        }
+
+        client.set_bpm(140).unwrap();
+        assert!(matches!(consumer.try_pop(), Some(LocalSequencerMessage::SetBpm(140))));
+
+        client.stop().unwrap();
+        assert!(matches!(consumer.try_pop(), Some(LocalSequencerMessage::HardStop)));
+
+        client.set_var("bass", "transpose", OscType::Int(7)).unwrap();
+        assert!(matches!(
+            consumer.try_pop(),
+            Some(LocalSequencerMessage::SetVariable(alias, name, Some(Variable::Int(7)))) if alias == "bass" && name == "transpose"
+        ));
+        assert!(client.set_var("bass", "loop", OscType::Int(1)).is_err());
+
+        let prefix = Transform::Prefix { from: "/note".to_string(), to: "/bass/note".to_string() };
+        client.transform("bass", &prefix).unwrap();
+        assert!(matches!(
+            consumer.try_pop(),
+            Some(LocalSequencerMessage::SetTransform(alias, Some(transform))) if alias == "bass" && transform == prefix
+        ));
+
+        // Unknown output, rejected by the daemon
+        let route = super::message(
+            "/route",
+            vec![OscType::String("bass".to_string()), OscType::String("nowhere".to_string())],
+        );
+        assert!(client.request(route, None).is_err());
+
+        let sequencer = SequencerStatus {
+            alias: "bass".to_string(),
+            active: true,
+            current_beat: BigDecimal::from_str("1.25").unwrap(),
+            end_beat: BigDecimal::from_str("2.0").unwrap(),
+            loop_index: 3,
+            one_shot: true,
+        };
+        *status.lock().unwrap() = DaemonStatus {
+            bpm: 140,
+            tick: 1,
+            sequencers: vec![sequencer.clone()],
+            ..DaemonStatus::default()
+        };
+
+        let state = client.state().unwrap();
+        assert_eq!(state.bpm, 140);
+        assert!(state.running);
+        assert_eq!(state.sequencers, vec![sequencer]);
+
+        assert_eq!(client.protocol_version().unwrap(), crate::protocol::PROTOCOL_VERSION);
+
+        client.shutdown().unwrap();
+        reader.join().unwrap();
+    }
+
+    #[test]
+    fn large_state_test() {
+        let (producer, _consumer) = HeapRb::<LocalSequencerMessage<OscPacket>>::new(16).split();
+        let sequencers: Vec<SequencerStatus> = (0..200)
+            .map(|i| SequencerStatus {
+                alias: format!("sequencer_{}", i),
+                active: i % 2 == 0,
+                current_beat: BigDecimal::from_str("1.25").unwrap(),
+                end_beat: BigDecimal::from(4),
+                loop_index: i,
+                one_shot: false,
+            })
+            .collect();
+        let status = Arc::new(Mutex::new(DaemonStatus {
+            bpm: 120,
+            sequencers: sequencers.clone(),
+            ..DaemonStatus::default()
+        }));
+        let control = Arc::new(ControlHandlers::new(
+            Arc::new(LocalMessageSender::new(producer, Duration::from_millis(10))),
+            Arc::new(OSCClient::from_outputs("127.0.0.1:0", &[]).unwrap()),
+            status,
+        ));
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let udp_addr = socket.local_addr().unwrap();
+        let udp_control = control.clone();
+        let reader = thread::spawn(move || udp_communication::serve(socket, 4096, udp_control));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp_addr = listener.local_addr().unwrap();
+        thread::spawn(move || tcp_communication::serve(listener, control));
+
+        // Far more than an MTU either way
+        let udp_client = SequencerClient::connect(udp_addr).unwrap();
+        assert_eq!(udp_client.state().unwrap().sequencers, sequencers);
+        let tcp_client = SequencerClient::connect_tcp(tcp_addr).unwrap();
+        assert_eq!(tcp_client.state().unwrap().sequencers, sequencers);
+        assert_eq!(tcp_client.protocol_version().unwrap(), crate::protocol::PROTOCOL_VERSION);
+
+        udp_client.shutdown().unwrap();
+        reader.join().unwrap();
+    }
+
+    #[test]
+    fn batch_route_test() {
+        let output = |name: &str| OutputConfig {
+            name: name.to_string(),
+            host: "127.0.0.1".to_string(),
+            port: 9,
+            protocol: "udp".to_string(),
+            nudge_ms: 0.0,
+        };
+        let (mut producer, mut consumer) = HeapRb::<LocalSequencerMessage<OscPacket>>::new(1).split();
+        producer.try_push(LocalSequencerMessage::Reset).unwrap();
+        let osc_client = Arc::new(OSCClient::from_outputs("127.0.0.1:0", &[output("default"), output("synth")]).unwrap());
+        let control = ControlHandlers::new(
+            Arc::new(LocalMessageSender::new(producer, Duration::from_millis(5))),
+            osc_client.clone(),
+            Arc::new(Mutex::new(DaemonStatus::default())),
+        );
+
+        let routed_queue = |alias: &str, output: &str| super::tagged_bundle("update_queue", vec![
+            super::message("/update_queue_info", vec![OscType::String(alias.to_string()), OscType::Int(0)]),
+            super::bundle(vec![]),
+            super::message("/route_info", vec![OscType::String(output.to_string())]),
+        ]);
+        let batch = |updates: Vec<OscPacket>| super::tagged_bundle("batch_update_queues", vec![
+            super::message("/batch_update_queues_info", vec![OscType::Int(0)]),
+            super::bundle(updates),
+        ]);
+        let bass_output = || osc_client.outputs_for("bass")[0].name.clone();
+
+        // An unknown output anywhere in the batch rejects all of it
+        assert!(control.dispatch(batch(vec![routed_queue("bass", "synth"), routed_queue("drums", "nowhere")])).is_err());
+        assert_eq!(bass_output(), "default");
+
+        // As does a full buffer
+        assert!(control.dispatch(batch(vec![routed_queue("bass", "synth")])).is_err());
+        assert_eq!(bass_output(), "default");
+
+        consumer.try_pop().unwrap();
+        assert!(control.dispatch(batch(vec![routed_queue("bass", "synth")])).is_ok());
+        assert_eq!(bass_output(), "synth");
+        assert!(matches!(consumer.try_pop(), Some(LocalSequencerMessage::BatchQueue(..))));
+    }
+}
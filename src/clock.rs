@@ -0,0 +1,73 @@
+use std::time::{Duration, SystemTime};
+
+/*
+
+    Source of time for the sequencing loop (see start_live_loop).
+    RealTimeClock follows the system clock; VirtualClock only moves when slept on or advanced,
+        which makes a loop deterministic and lets it run as fast as the machine allows.
+
+*/
+pub trait Clock {
+    fn now(&self) -> SystemTime;
+    fn sleep(&mut self, duration: Duration);
+}
+
+pub struct RealTimeClock {
+    sleeper: spin_sleep::SpinSleeper,
+}
+
+impl Default for RealTimeClock {
+    fn default() -> Self {
+        RealTimeClock::new()
+    }
+}
+
+impl RealTimeClock {
+    pub fn new() -> RealTimeClock {
+        RealTimeClock {
+            sleeper: spin_sleep::SpinSleeper::new(100),
+        }
+    }
+}
+
+impl Clock for RealTimeClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        self.sleeper.sleep(duration);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VirtualClock {
+    start: SystemTime,
+    now: SystemTime,
+}
+
+impl VirtualClock {
+    pub fn new(start: SystemTime) -> VirtualClock {
+        VirtualClock { start, now: start }
+    }
+
+    pub fn advance(&mut self, duration: Duration) {
+        self.now += duration;
+    }
+
+    // Time passed since the clock was created
+    pub fn elapsed(&self) -> Duration {
+        self.now.duration_since(self.start).unwrap_or_default()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> SystemTime {
+        self.now
+    }
+
+    // Returns at once, with the time moved on
+    fn sleep(&mut self, duration: Duration) {
+        self.advance(duration);
+    }
+}
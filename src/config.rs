@@ -1,18 +1,90 @@
+use std::convert::TryFrom;
 use std::path::Path;
 use std::sync::OnceLock;
 
 use log::LevelFilter;
+use rosc::{OscMessage, OscPacket, OscType};
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use toml::Value as TomlValue;
 
+use crate::realtime::RealtimeOptions;
+
 static CONFIG: OnceLock<Config> = OnceLock::new();
 static APP_NAME: &str = "sequencer";
 
+/*
+    A named OSC destination. Aliases are routed to one or more outputs by name,
+        see OSCClient. Declared in config as:
+
+    [[outputs]]
+    name = "drums"
+    host = "127.0.0.1"
+    port = 13339
+    protocol = "udp"    # or "tcp" (SLIP-framed)
+    nudge_ms = 0.0
+*/
+#[derive(Deserialize, Debug, Clone)]
+pub struct OutputConfig {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    #[serde(default = "default_protocol")]
+    pub protocol: String,
+    #[serde(default)]
+    pub nudge_ms: f64,
+}
+
+fn default_protocol() -> String {
+    "udp".to_string()
+}
+
+/*
+    A message sent to every output when the daemon shuts down, such as an "all notes off"
+        for targets that would otherwise keep sounding. Declared in config as:
+
+    [[shutdown_messages]]
+    address = "/note_off_all"
+    args = []    # ints, floats, strings or bools
+*/
+#[derive(Deserialize, Debug, Clone)]
+pub struct ShutdownMessageConfig {
+    pub address: String,
+    #[serde(default)]
+    pub args: Vec<TomlValue>,
+}
+
+impl ShutdownMessageConfig {
+    pub fn to_packet(&self) -> Result<OscPacket, String> {
+        let args = self.args.iter()
+            .map(to_osc_arg)
+            .collect::<Result<Vec<OscType>, String>>()
+            .map_err(|e| format!("{} in shutdown message {}", e, self.address))?;
+
+        Ok(OscPacket::Message(OscMessage {
+            addr: self.address.clone(),
+            args,
+        }))
+    }
+}
+
+// OSC arg from a TOML value, for messages declared in config or session files
+pub fn to_osc_arg(value: &TomlValue) -> Result<OscType, String> {
+    match value {
+        TomlValue::Integer(i) => Ok(OscType::Int(*i as i32)),
+        TomlValue::Float(f) => Ok(OscType::Float(*f as f32)),
+        TomlValue::String(s) => Ok(OscType::String(s.clone())),
+        TomlValue::Boolean(b) => Ok(OscType::Bool(*b)),
+        other => Err(format!("Unsupported arg {}", other)),
+    }
+}
+
 #[derive(Deserialize)]
 pub struct Config {
     pub log_level: String,
     pub application_ip: String,
     pub application_in_port: i32,
+    pub application_in_tcp_port: i32,
     pub application_out_port: i32,
     pub application_out_socket_port: i32,
     pub tick_time_us: u64,
@@ -21,8 +93,18 @@ pub struct Config {
     pub real_time_mode: bool,
     pub midi_sync: bool,
     pub ringbuf_capacity: usize,
+    pub control_push_timeout_ms: u64,
     pub default_bpm: i32,
     pub buffer_size: usize,
+    pub output_nudge_ms: f64,
+    pub outputs: Vec<OutputConfig>,
+    pub http_port: i32,
+    pub http_event_interval_ms: u64,
+    pub metrics_port: i32,
+    pub realtime_priority: i32,
+    pub lock_memory: bool,
+    pub cpu_core: i32,
+    pub shutdown_messages: Vec<ShutdownMessageConfig>,
 }
 
 impl Default for Config {
@@ -31,6 +113,7 @@ impl Default for Config {
             log_level: "info".to_string(),
             application_ip: "127.0.0.1".to_string(),
             application_in_port: 14441,
+            application_in_tcp_port: 14442,
             application_out_port: 13339,
             application_out_socket_port: 14444,
             tick_time_us: 5000,
@@ -39,8 +122,18 @@ impl Default for Config {
             real_time_mode: true,
             midi_sync: false,
             ringbuf_capacity: 100,
+            control_push_timeout_ms: 100,
             default_bpm: 120,
             buffer_size: 333072,
+            output_nudge_ms: 0.0,
+            outputs: vec![],
+            http_port: 0,
+            http_event_interval_ms: 50,
+            metrics_port: 0,
+            realtime_priority: 0,
+            lock_memory: false,
+            cpu_core: -1,
+            shutdown_messages: vec![],
         }
     }
 }
@@ -50,6 +143,23 @@ impl Config {
         CONFIG.get().expect("Config not initialized — call config::init() first")
     }
 
+    /*
+        Declared outputs, or if none are declared a single "default" output
+            built from application_ip, application_out_port and output_nudge_ms.
+    */
+    pub fn resolved_outputs(&self) -> Vec<OutputConfig> {
+        if !self.outputs.is_empty() {
+            return self.outputs.clone();
+        }
+        vec![OutputConfig {
+            name: "default".to_string(),
+            host: self.application_ip.clone(),
+            port: self.application_out_port as u16,
+            protocol: default_protocol(),
+            nudge_ms: self.output_nudge_ms,
+        }]
+    }
+
     pub fn log_level_filter(&self) -> LevelFilter {
         match self.log_level.to_lowercase().as_str() {
             "off" | "disable" => LevelFilter::Off,
@@ -61,6 +171,15 @@ impl Config {
             _ => LevelFilter::Info,
         }
     }
+
+    // Zero priority and a negative core disable those options
+    pub fn realtime_options(&self) -> RealtimeOptions {
+        RealtimeOptions {
+            priority: if self.realtime_priority > 0 { Some(self.realtime_priority) } else { None },
+            lock_memory: self.lock_memory,
+            cpu: usize::try_from(self.cpu_core).ok(),
+        }
+    }
 }
 
 fn central_config_path() -> String {
@@ -112,16 +231,32 @@ fn merge_usize(base: &mut usize, overlay: &TomlValue, key: &str) {
     }
 }
 
+fn merge_f64(base: &mut f64, overlay: &TomlValue, key: &str) {
+    if let Some(v) = overlay.get(key).and_then(|v| v.as_float().or(v.as_integer().map(|i| i as f64))) {
+        *base = v;
+    }
+}
+
 fn merge_bool(base: &mut bool, overlay: &TomlValue, key: &str) {
     if let Some(v) = overlay.get(key).and_then(|v| v.as_bool()) {
         *base = v;
     }
 }
 
+fn merge_list<T: DeserializeOwned>(base: &mut Vec<T>, overlay: &TomlValue, key: &str) {
+    if let Some(v) = overlay.get(key) {
+        match v.clone().try_into::<Vec<T>>() {
+            Ok(list) => *base = list,
+            Err(e) => eprintln!("Warning: Could not parse '{}' in config: {}", key, e),
+        }
+    }
+}
+
 fn merge_config(base: &mut Config, overlay: &TomlValue) {
     merge_str(&mut base.log_level, overlay, "log_level");
     merge_str(&mut base.application_ip, overlay, "application_ip");
     merge_i32(&mut base.application_in_port, overlay, "application_in_port");
+    merge_i32(&mut base.application_in_tcp_port, overlay, "application_in_tcp_port");
     merge_i32(&mut base.application_out_port, overlay, "application_out_port");
     merge_i32(&mut base.application_out_socket_port, overlay, "application_out_socket_port");
     merge_u64(&mut base.tick_time_us, overlay, "tick_time_us");
@@ -130,8 +265,18 @@ fn merge_config(base: &mut Config, overlay: &TomlValue) {
     merge_bool(&mut base.real_time_mode, overlay, "real_time_mode");
     merge_bool(&mut base.midi_sync, overlay, "midi_sync");
     merge_usize(&mut base.ringbuf_capacity, overlay, "ringbuf_capacity");
+    merge_u64(&mut base.control_push_timeout_ms, overlay, "control_push_timeout_ms");
     merge_i32(&mut base.default_bpm, overlay, "default_bpm");
     merge_usize(&mut base.buffer_size, overlay, "buffer_size");
+    merge_f64(&mut base.output_nudge_ms, overlay, "output_nudge_ms");
+    merge_list(&mut base.outputs, overlay, "outputs");
+    merge_i32(&mut base.http_port, overlay, "http_port");
+    merge_u64(&mut base.http_event_interval_ms, overlay, "http_event_interval_ms");
+    merge_i32(&mut base.metrics_port, overlay, "metrics_port");
+    merge_i32(&mut base.realtime_priority, overlay, "realtime_priority");
+    merge_bool(&mut base.lock_memory, overlay, "lock_memory");
+    merge_i32(&mut base.cpu_core, overlay, "cpu_core");
+    merge_list(&mut base.shutdown_messages, overlay, "shutdown_messages");
 }
 
 impl Config {
@@ -0,0 +1,372 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use jdw_osc_lib::model::TaggedBundle;
+use log::{info, warn};
+use rosc::{OscMessage, OscPacket, OscType};
+
+use crate::bundle_model::{
+    BatchUpdateQueuesMessage, GenerateQueueMessage, RequestMessage, RouteMessage, SetGrooveMessage,
+    SetNudgeMessage, SetTransformMessage, SetVariableMessage, UpdateQueueMessage,
+};
+use crate::local_messaging::{LocalMessageSender, LocalQueuePayload, LocalSequencerMessage, SenderMetrics};
+use crate::osc_communication::OSCClient;
+use crate::protocol;
+use crate::sequencing_daemon::{self, DaemonStatus};
+use crate::stats::DaemonStats;
+use crate::timeline;
+
+/*
+
+    Handlers for incoming control messages and bundles, translating them into LocalSequencerMessages
+        for the sequencing daemon. Shared by all transports (UDP, TCP and HTTP).
+
+    Everything is validated against the protocol description first (see protocol.rs).
+    Commands wrapped in a "request" bundle (see RequestMessage) get an /ack or /error reply,
+        which the transport sends back to wherever the command came from, as do queries.
+
+*/
+pub struct ControlHandlers {
+    sender: Arc<LocalMessageSender<OscPacket>>,
+    osc_client: Arc<OSCClient>,
+    status: Arc<Mutex<DaemonStatus>>,
+    shutdown: AtomicBool,
+}
+
+impl ControlHandlers {
+    pub fn new(
+        sender: Arc<LocalMessageSender<OscPacket>>,
+        osc_client: Arc<OSCClient>,
+        status: Arc<Mutex<DaemonStatus>>,
+    ) -> ControlHandlers {
+        ControlHandlers {
+            sender,
+            osc_client,
+            status,
+            shutdown: AtomicBool::new(false),
+        }
+    }
+
+    // Ask the transports to stop reading, after which the daemon shuts down (see lib.rs run)
+    pub fn request_shutdown(&self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::Relaxed)
+    }
+
+    // Timing statistics of the daemon as of its last tick, and counts of the control messages sent to it
+    pub fn stats(&self) -> (DaemonStats, SenderMetrics) {
+        (self.status.lock().unwrap().stats, self.sender.metrics())
+    }
+
+    // Handle a received packet, returning the replies to send back (query results, and /ack or /error if requested)
+    pub fn handle(&self, packet: OscPacket) -> Vec<OscPacket> {
+        let request = match &packet {
+            OscPacket::Bundle(bundle) if RequestMessage::is_request(bundle) => Some(RequestMessage::from_bundle(bundle)),
+            _ => None,
+        };
+
+        match request {
+            Some(Ok(request)) => match self.dispatch(request.packet) {
+                Ok(mut replies) => {
+                    replies.push(reply(request.id, Ok(())));
+                    replies
+                }
+                Err(e) => {
+                    warn!("Request {:?} failed: {}", request.id, e);
+                    vec![reply(request.id, Err(e))]
+                }
+            },
+            Some(Err(e)) => {
+                // Without a readable id there is nothing to reply to
+                warn!("Failed to parse request: {}", e);
+                vec![]
+            }
+            None => self.dispatch(packet).unwrap_or_else(|e| {
+                warn!("{}", e);
+                vec![]
+            }),
+        }
+    }
+
+    // Validate against the protocol description and pass on to the handlers. Returns query results.
+    pub fn dispatch(&self, packet: OscPacket) -> Result<Vec<OscPacket>, String> {
+        match packet {
+            OscPacket::Message(msg) => {
+                protocol::validate_message(&msg)?;
+                match self.query(&msg) {
+                    Some(result) => Ok(vec![result]),
+                    None => self.on_message(&msg).map(|()| vec![]),
+                }
+            }
+            OscPacket::Bundle(bundle) => {
+                protocol::validate_bundle(&bundle)?;
+                match TaggedBundle::new(&bundle) {
+                    Ok(tbundle) => self.on_tbundle(tbundle).map(|()| vec![]),
+                    Err(_) => {
+                        // Plain bundles are just containers
+                        let mut replies = vec![];
+                        for content in bundle.content {
+                            replies.extend(self.dispatch(content)?);
+                        }
+                        Ok(replies)
+                    }
+                }
+            }
+        }
+    }
+
+    // Messages answered directly rather than passed on to the daemon
+    fn query(&self, msg: &OscMessage) -> Option<OscPacket> {
+        match msg.addr.as_str() {
+            "/protocol_version" => Some(protocol::version_message()),
+            "/get_state" => Some(protocol::state_message(&self.status.lock().unwrap())),
+            "/stats" => {
+                let (stats, sender) = self.stats();
+                Some(protocol::stats_message(&stats, &sender))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn on_message(&self, msg: &OscMessage) -> Result<(), String> {
+        match msg.addr.as_str() {
+            "/set_bpm" => self.set_bpm(msg),
+            "/reset_all" => self.reset_all(),
+            "/hard_stop" => self.hard_stop(),
+            "/wipe_on_finish" => self.wipe_on_finish(),
+            "/shutdown" => self.shutdown(),
+            "/route" | "/clear_route" => self.route(msg),
+            "/set_nudge" | "/set_nudge_beats" | "/clear_nudge" => self.nudge(msg),
+            "/set_swing" | "/set_groove" | "/clear_groove" => self.groove(msg),
+            "/set_var" | "/clear_var" => self.variable(msg),
+            "/transform" | "/clear_transforms" => self.transform(msg),
+            other => Err(format!("No handler for message address {}", other)),
+        }
+    }
+
+    pub fn on_tbundle(&self, tbundle: TaggedBundle) -> Result<(), String> {
+        match tbundle.bundle_tag.as_str() {
+            "batch_update_queues" => self.batch_update_queues(tbundle),
+            "update_queue" => self.update_queue(tbundle),
+            "generate_queue" => self.generate_queue(tbundle),
+            other => Err(format!("No handler for bundle tag {}", other)),
+        }
+    }
+
+    pub fn push(&self, msg: LocalSequencerMessage<OscPacket>) -> Result<(), String> {
+        self.sender.send(msg)
+    }
+
+    pub fn set_bpm(&self, msg: &OscMessage) -> Result<(), String> {
+        let bpm = msg
+            .args
+            .first()
+            .ok_or("Unable to parse set_bpm message (missing arg)".to_string())?
+            .clone()
+            .int()
+            .ok_or("set_bpm arg not an int".to_string())?;
+        timeline::check_bpm(bpm)?;
+
+        info!("SET BPM");
+        self.push(LocalSequencerMessage::SetBpm(bpm))
+    }
+
+    pub fn reset_all(&self) -> Result<(), String> {
+        info!("RESET ALL");
+        self.push(LocalSequencerMessage::Reset)
+    }
+
+    pub fn hard_stop(&self) -> Result<(), String> {
+        info!("HARD STOP");
+        self.push(LocalSequencerMessage::HardStop)
+    }
+
+    pub fn wipe_on_finish(&self) -> Result<(), String> {
+        info!("WIPE ON FINISH");
+        self.push(LocalSequencerMessage::EndAfterFinish)
+    }
+
+    pub fn shutdown(&self) -> Result<(), String> {
+        info!("SHUTDOWN");
+        self.request_shutdown();
+        Ok(())
+    }
+
+    pub fn route(&self, msg: &OscMessage) -> Result<(), String> {
+        let route_msg = RouteMessage::from_message(msg)
+            .map_err(|e| format!("Failed to parse route message: {}", e))?;
+        self.apply_route(&route_msg.alias, route_msg.outputs)
+    }
+
+    pub fn nudge(&self, msg: &OscMessage) -> Result<(), String> {
+        let nudge_msg = SetNudgeMessage::from_message(msg)
+            .map_err(|e| format!("Failed to parse nudge message: {}", e))?;
+        info!("SET NUDGE {}: {:?}", nudge_msg.alias, nudge_msg.nudge);
+        self.push(LocalSequencerMessage::SetNudge(nudge_msg.alias, nudge_msg.nudge))
+    }
+
+    pub fn variable(&self, msg: &OscMessage) -> Result<(), String> {
+        let var_msg = SetVariableMessage::from_message(msg)
+            .map_err(|e| format!("Failed to parse variable message: {}", e))?;
+        info!("SET VAR {} {}: {:?}", var_msg.alias, var_msg.name, var_msg.value);
+        self.push(LocalSequencerMessage::SetVariable(var_msg.alias, var_msg.name, var_msg.value))
+    }
+
+    pub fn transform(&self, msg: &OscMessage) -> Result<(), String> {
+        let transform_msg = SetTransformMessage::from_message(msg)
+            .map_err(|e| format!("Failed to parse transform message: {}", e))?;
+        info!("SET TRANSFORM {}: {:?}", transform_msg.alias, transform_msg.transform);
+        self.push(LocalSequencerMessage::SetTransform(transform_msg.alias, transform_msg.transform))
+    }
+
+    pub fn groove(&self, msg: &OscMessage) -> Result<(), String> {
+        let groove_msg = SetGrooveMessage::from_message(msg)
+            .map_err(|e| format!("Failed to parse groove message: {}", e))?;
+        info!("SET GROOVE {:?}: {:?}", groove_msg.alias, groove_msg.groove);
+        self.push(LocalSequencerMessage::SetGroove(groove_msg.alias, groove_msg.groove))
+    }
+
+    /*
+        The whole batch is parsed and its routes checked before anything is sent, and all queues go to the daemon
+            as a single BatchQueue so that it is applied in one go (and takes one buffer slot).
+        Routes only change once the daemon has the batch, so a rejected batch changes nothing.
+    */
+    pub fn batch_update_queues(&self, tbundle: TaggedBundle) -> Result<(), String> {
+        let batch_update_msg = BatchUpdateQueuesMessage::from_bundle(tbundle)
+            .map_err(|e| format!("Failed to parse batch update queue message: {}", e))?;
+
+        let routes: Vec<(String, Vec<String>)> = batch_update_msg.update_queue_messages.iter()
+            .filter_map(|msg| msg.outputs.clone().map(|outputs| (msg.alias.clone(), outputs)))
+            .collect();
+        for (alias, outputs) in &routes {
+            self.check_route(alias, outputs)?;
+        }
+
+        let mut payloads: Vec<LocalQueuePayload<OscPacket>> = vec![];
+        for update_queue_msg in batch_update_msg.update_queue_messages {
+            let payload = to_payload(update_queue_msg);
+            // A later update for the same alias supersedes an earlier one
+            payloads.retain(|existing| existing.sequencer_alias != payload.sequencer_alias);
+            payloads.push(payload);
+        }
+
+        info!("QUEUE CHANGED ({} aliases, stop missing: {})", payloads.len(), batch_update_msg.stop_missing);
+        self.push(LocalSequencerMessage::BatchQueue(payloads, batch_update_msg.stop_missing))?;
+
+        for (alias, outputs) in routes {
+            self.apply_route(&alias, Some(outputs))?;
+        }
+        Ok(())
+    }
+
+    pub fn update_queue(&self, tbundle: TaggedBundle) -> Result<(), String> {
+        let update_queue_msg = UpdateQueueMessage::from_bundle(tbundle)
+            .map_err(|e| format!("Failed to parse update_queue message: {}", e))?;
+        self.queue_update(update_queue_msg)
+    }
+
+    fn queue_update(&self, update_queue_msg: UpdateQueueMessage) -> Result<(), String> {
+        let alias = update_queue_msg.alias.clone();
+        let outputs = update_queue_msg.outputs.clone();
+        if let Some(outputs) = &outputs {
+            self.check_route(&alias, outputs)?;
+        }
+
+        self.push(LocalSequencerMessage::Queue(to_payload(update_queue_msg)))?;
+
+        if outputs.is_some() {
+            self.apply_route(&alias, outputs)?;
+        }
+        Ok(())
+    }
+
+    pub fn generate_queue(&self, tbundle: TaggedBundle) -> Result<(), String> {
+        let generate_msg = GenerateQueueMessage::from_bundle(tbundle)
+            .map_err(|e| format!("Failed to parse generate_queue message: {}", e))?;
+        let sequence = generate_msg
+            .generator
+            .generate(&generate_msg.packets)
+            .map_err(|e| format!("Failed to generate queue: {}", e))?;
+
+        info!("Generated queue for {}: {:?}", &generate_msg.alias, generate_msg.generator);
+
+        if let Some(outputs) = &generate_msg.outputs {
+            self.check_route(&generate_msg.alias, outputs)?;
+        }
+
+        self.push(LocalSequencerMessage::Queue(LocalQueuePayload {
+            sequencer_alias: generate_msg.alias.clone(),
+            entries: sequence.entries,
+            lanes: vec![],
+            end_beat: sequence.end_beat,
+            one_shot: generate_msg.one_shot,
+            seed: None,
+        }))?;
+
+        if generate_msg.outputs.is_some() {
+            self.apply_route(&generate_msg.alias, generate_msg.outputs)?;
+        }
+        Ok(())
+    }
+
+    // Check that a route can be applied, before sending anything it goes with
+    fn check_route(&self, alias: &str, outputs: &[String]) -> Result<(), String> {
+        self.osc_client
+            .check_outputs(outputs)
+            .map_err(|e| format!("Failed to route {}: {}", alias, e))
+    }
+
+    // Route an alias to the given outputs, or back to the default output if None
+    fn apply_route(&self, alias: &str, outputs: Option<Vec<String>>) -> Result<(), String> {
+        match outputs {
+            Some(names) => {
+                self.osc_client
+                    .set_route(alias, names.clone())
+                    .map_err(|e| format!("Failed to route {}: {}", alias, e))?;
+                info!("ROUTE {} -> {:?}", alias, names);
+            }
+            None => {
+                info!("CLEAR ROUTE {}", alias);
+                self.osc_client.clear_route(alias);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn to_payload(update_queue_msg: UpdateQueueMessage) -> LocalQueuePayload<OscPacket> {
+    let alias = update_queue_msg.alias.clone();
+    let payload = sequencing_daemon::to_sequence(update_queue_msg.messages);
+
+    info!("Updating queue for {}", &alias);
+
+    // Lanes longer than the entries (or without any entries) make the sequence as long as themselves
+    let end_beat = update_queue_msg.lanes.iter()
+        .map(|lane| lane.length_beats())
+        .fold(payload.end_beat, |longest, length| longest.max(length));
+
+    LocalQueuePayload {
+        sequencer_alias: alias,
+        entries: payload.message_sequence,
+        lanes: update_queue_msg.lanes,
+        end_beat,
+        one_shot: update_queue_msg.one_shot,
+        seed: update_queue_msg.seed,
+    }
+}
+
+fn reply(id: OscType, result: Result<(), String>) -> OscPacket {
+    OscPacket::Message(match result {
+        Ok(()) => OscMessage {
+            addr: "/ack".to_string(),
+            args: vec![id],
+        },
+        Err(reason) => OscMessage {
+            addr: "/error".to_string(),
+            args: vec![id, OscType::String(reason)],
+        },
+    })
+}
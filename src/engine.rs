@@ -0,0 +1,269 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+use bigdecimal::BigDecimal;
+use ringbuf::traits::Split;
+use ringbuf::HeapRb;
+
+use crate::clock::{Clock, RealTimeClock};
+use crate::local_messaging::{LocalMessageSender, LocalQueuePayload, LocalSequencerMessage};
+use crate::master_sequencer::{MasterSequencer, SequencerResetMode, SequencerStartMode};
+use crate::realtime::RealtimeOptions;
+use crate::sequencer::SequencerEntry;
+use crate::sequencing_daemon::{self, DaemonStatus, OutgoingEvent, SequencingDaemon};
+use crate::timeline;
+
+/*
+
+    The sequencing engine as a library: no config file, no sockets, no logger.
+    Events are handed to the output callback given to start, and commands go in through the
+        same LocalSequencerMessages the OSC handlers use, so the engine works for any packet type.
+
+    let engine = EngineBuilder::new().bpm(140)?.start(|events, _tick_time| play(events));
+    engine.queue("drums", entries, end_beat, false)?;
+    engine.shutdown();
+
+*/
+pub struct EngineBuilder {
+    bpm: i32,
+    start_mode: SequencerStartMode,
+    reset_mode: SequencerResetMode,
+    tick_time: Duration,
+    buffer_capacity: usize,
+    push_timeout: Duration,
+    realtime: RealtimeOptions,
+}
+
+impl Default for EngineBuilder {
+    fn default() -> Self {
+        EngineBuilder::new()
+    }
+}
+
+impl EngineBuilder {
+    // Same defaults as the daemon config
+    pub fn new() -> EngineBuilder {
+        EngineBuilder {
+            bpm: 120,
+            start_mode: SequencerStartMode::WithLongestSequence,
+            reset_mode: SequencerResetMode::Individual,
+            tick_time: Duration::from_micros(5000),
+            buffer_capacity: 100,
+            push_timeout: Duration::from_millis(100),
+            realtime: RealtimeOptions::default(),
+        }
+    }
+
+    pub fn bpm(mut self, bpm: i32) -> Result<EngineBuilder, String> {
+        timeline::check_bpm(bpm)?;
+        self.bpm = bpm;
+        Ok(self)
+    }
+
+    pub fn start_mode(mut self, start_mode: SequencerStartMode) -> EngineBuilder {
+        self.start_mode = start_mode;
+        self
+    }
+
+    pub fn reset_mode(mut self, reset_mode: SequencerResetMode) -> EngineBuilder {
+        self.reset_mode = reset_mode;
+        self
+    }
+
+    pub fn tick_time(mut self, tick_time: Duration) -> EngineBuilder {
+        self.tick_time = tick_time;
+        self
+    }
+
+    // Room for commands not yet picked up by the engine thread
+    pub fn buffer_capacity(mut self, buffer_capacity: usize) -> EngineBuilder {
+        self.buffer_capacity = buffer_capacity;
+        self
+    }
+
+    // How long a command may wait for room in a full buffer before it is dropped
+    pub fn push_timeout(mut self, push_timeout: Duration) -> EngineBuilder {
+        self.push_timeout = push_timeout;
+        self
+    }
+
+    // Realtime priority, memory locking and CPU pinning for the engine thread, none by default
+    pub fn realtime(mut self, realtime: RealtimeOptions) -> EngineBuilder {
+        self.realtime = realtime;
+        self
+    }
+
+    pub fn start<T, F>(self, output: F) -> Engine<T>
+    where
+        T: 'static + Clone + Send,
+        F: 'static + Send + Fn(Vec<OutgoingEvent<T>>, SystemTime),
+    {
+        self.start_with_clock(RealTimeClock::new(), output)
+    }
+
+    // Run on the given clock instead, e.g. a VirtualClock to play through sequences faster than real time
+    pub fn start_with_clock<T, F, C>(self, clock: C, output: F) -> Engine<T>
+    where
+        T: 'static + Clone + Send,
+        F: 'static + Send + Fn(Vec<OutgoingEvent<T>>, SystemTime),
+        C: 'static + Send + Clock,
+    {
+        let (producer, consumer) = HeapRb::<LocalSequencerMessage<T>>::new(self.buffer_capacity).split();
+        let status = Arc::new(Mutex::new(DaemonStatus {
+            bpm: self.bpm,
+            ..DaemonStatus::default()
+        }));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let daemon = SequencingDaemon::new(
+            MasterSequencer::new(self.start_mode, self.reset_mode),
+            self.bpm,
+            self.tick_time,
+            consumer,
+            status.clone(),
+            output,
+        );
+        let handle = sequencing_daemon::start_live_loop(daemon, clock, stop.clone(), self.realtime);
+
+        Engine {
+            sender: Arc::new(LocalMessageSender::new(producer, self.push_timeout)),
+            status,
+            stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+/*
+    Handle to a running engine. Dropping it stops the engine thread as well.
+*/
+pub struct Engine<T: Clone> {
+    sender: Arc<LocalMessageSender<T>>,
+    status: Arc<Mutex<DaemonStatus>>,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl<T: Clone> Engine<T> {
+    pub fn send(&self, msg: LocalSequencerMessage<T>) -> Result<(), String> {
+        self.sender.send(msg)
+    }
+
+    pub fn queue(&self, alias: &str, entries: Vec<SequencerEntry<T>>, end_beat: BigDecimal, one_shot: bool) -> Result<(), String> {
+        self.send(LocalSequencerMessage::Queue(LocalQueuePayload {
+            sequencer_alias: alias.to_string(),
+            entries,
+            lanes: vec![],
+            end_beat,
+            one_shot,
+            seed: None,
+        }))
+    }
+
+    pub fn set_bpm(&self, bpm: i32) -> Result<(), String> {
+        timeline::check_bpm(bpm)?;
+        self.send(LocalSequencerMessage::SetBpm(bpm))
+    }
+
+    pub fn stop(&self) -> Result<(), String> {
+        self.send(LocalSequencerMessage::HardStop)
+    }
+
+    pub fn reset(&self) -> Result<(), String> {
+        self.send(LocalSequencerMessage::Reset)
+    }
+
+    // Snapshot as of the last tick
+    pub fn status(&self) -> DaemonStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    // Shared command sender, for handing to other threads (e.g. the OSC control handlers)
+    pub fn sender(&self) -> Arc<LocalMessageSender<T>> {
+        self.sender.clone()
+    }
+
+    pub fn status_handle(&self) -> Arc<Mutex<DaemonStatus>> {
+        self.status.clone()
+    }
+
+    // Stop the engine thread after its current tick and wait for it to finish
+    pub fn shutdown(mut self) {
+        self.join();
+    }
+
+    fn join(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl<T: Clone> Drop for Engine<T> {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use bigdecimal::BigDecimal;
+
+    use super::EngineBuilder;
+    use crate::sequencing_daemon::OutgoingEvent;
+    use crate::master_sequencer::SequencerStartMode;
+    use crate::sequencer::SequencerEntry;
+
+    #[test]
+    fn engine_test() {
+        let played = Arc::new(Mutex::new(Vec::<String>::new()));
+        let output = played.clone();
+
+        let engine = EngineBuilder::new()
+            .bpm(600)
+            .unwrap()
+            .start_mode(SequencerStartMode::Immediate)
+            .tick_time(Duration::from_millis(1))
+            .start(move |events, _| {
+                output.lock().unwrap().extend(events.into_iter().map(|outgoing| outgoing.event.contents));
+            });
+
+        let entries = vec![
+            SequencerEntry::new(BigDecimal::from_str("0.0").unwrap(), "one".to_string()),
+            SequencerEntry::new(BigDecimal::from_str("0.5").unwrap(), "two".to_string()),
+        ];
+        engine.queue("test", entries, BigDecimal::from_str("1.0").unwrap(), true).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while played.lock().unwrap().len() < 2 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        assert_eq!(*played.lock().unwrap(), vec!["one".to_string(), "two".to_string()]);
+        assert_eq!(engine.status().bpm, 600);
+
+        engine.shutdown();
+    }
+
+    #[test]
+    fn bpm_test() {
+        assert!(EngineBuilder::new().bpm(0).is_err());
+        assert!(EngineBuilder::new().bpm(-1).is_err());
+
+        let engine = EngineBuilder::new().tick_time(Duration::from_millis(1)).start(|_: Vec<OutgoingEvent<String>>, _| {});
+        assert_eq!(engine.set_bpm(0), Err("BPM must be positive, got 0".to_string()));
+        assert_eq!(engine.set_bpm(-1), Err("BPM must be positive, got -1".to_string()));
+
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(engine.status().bpm, 120);
+        engine.shutdown();
+    }
+}
@@ -0,0 +1,373 @@
+use std::str::FromStr;
+
+use bigdecimal::BigDecimal;
+use rosc::{OscPacket, OscType};
+
+use crate::packet_utils;
+use crate::random::SeededRandom;
+use crate::sequencer::SequencerEntry;
+
+/*
+
+    Algorithmic sequence generators.
+
+    Each generator produces a ready timeline of entries plus the end beat of the sequence,
+        i.e. the same shape that Sequencer::queue expects. Clients can thus ask for e.g.
+        "euclid 5/16 of this message" instead of computing the timeline themselves.
+
+*/
+
+// Most steps (or notes, or polyrhythm divisions in total) a generator produces, bounding what one request can allocate
+pub const MAX_STEPS: u32 = 65_536;
+
+fn check_steps(steps: u32, what: &str) -> Result<(), String> {
+    if steps > MAX_STEPS {
+        return Err(format!("{} cannot have more than {} steps, got {}", what, MAX_STEPS, steps));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct GeneratedSequence<T: Clone> {
+    pub entries: Vec<SequencerEntry<T>>,
+    pub end_beat: BigDecimal,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArpMode {
+    Up,
+    Down,
+    UpDown,
+    AsPlayed,
+    Random(u64), // Seed; parsed as 0, see bundle_model::GenerateQueueMessage for where it is set
+}
+
+impl FromStr for ArpMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "up" => Ok(ArpMode::Up),
+            "down" => Ok(ArpMode::Down),
+            "updown" => Ok(ArpMode::UpDown),
+            "played" => Ok(ArpMode::AsPlayed),
+            "random" => Ok(ArpMode::Random(0)),
+            _ => Err(format!("Unknown arpeggio mode: {}", s)),
+        }
+    }
+}
+
+/*
+    Spread hits as evenly as possible over steps (Bresenham style, equivalent to Bjorklund up to rotation).
+    Rotation shifts the pattern that many steps earlier, wrapping onsets before the start around to the end.
+*/
+pub fn euclidean_pattern(hits: u32, steps: u32, rotation: u32) -> Vec<bool> {
+    (0..steps)
+        .map(|i| {
+            let rotated = (i as u64 + rotation as u64) % steps as u64;
+            (rotated * hits as u64) % (steps as u64) < hits as u64
+        })
+        .collect()
+}
+
+pub fn euclidean<T: Clone>(
+    hits: u32,
+    steps: u32,
+    rotation: u32,
+    step_beats: &BigDecimal,
+    contents: T,
+) -> Result<GeneratedSequence<T>, String> {
+    if steps == 0 {
+        return Err("Euclidean rhythm needs at least one step".to_string());
+    }
+    check_steps(steps, "Euclidean rhythm")?;
+    if hits > steps {
+        return Err(format!("Euclidean rhythm cannot fit {} hits in {} steps", hits, steps));
+    }
+
+    let entries = euclidean_pattern(hits, steps, rotation)
+        .iter()
+        .enumerate()
+        .filter(|(_, hit)| **hit)
+        .map(|(i, _)| SequencerEntry::new(step_beats * BigDecimal::from(i as u32), contents.clone()))
+        .collect();
+
+    Ok(GeneratedSequence {
+        entries,
+        end_beat: step_beats * BigDecimal::from(steps),
+    })
+}
+
+/*
+    Several evenly spaced layers sharing the same total length, e.g. 3 against 4.
+    Each layer is (divisions, contents).
+*/
+pub fn polyrhythm<T: Clone>(
+    length_beats: &BigDecimal,
+    layers: Vec<(u32, T)>,
+) -> Result<GeneratedSequence<T>, String> {
+    if layers.is_empty() {
+        return Err("Polyrhythm needs at least one layer".to_string());
+    }
+
+    let total = layers.iter().map(|(divisions, _)| *divisions as u64).sum::<u64>();
+    check_steps(total.min(u32::MAX as u64) as u32, "Polyrhythm")?;
+
+    let mut entries: Vec<SequencerEntry<T>> = vec![];
+    for (divisions, contents) in layers {
+        if divisions == 0 {
+            return Err("Polyrhythm layers need at least one division".to_string());
+        }
+        let step = length_beats / BigDecimal::from(divisions);
+        for i in 0..divisions {
+            entries.push(SequencerEntry::new(&step * BigDecimal::from(i), contents.clone()));
+        }
+    }
+
+    entries.sort_by(|a, b| a.trigger_beat.cmp(&b.trigger_beat));
+
+    Ok(GeneratedSequence {
+        entries,
+        end_beat: length_beats.clone(),
+    })
+}
+
+/*
+    Each step triggers with the given density (0.0 - 1.0).
+    The same seed always produces the same pattern.
+*/
+pub fn random_density<T: Clone>(
+    steps: u32,
+    step_beats: &BigDecimal,
+    density: f32,
+    seed: u64,
+    contents: T,
+) -> Result<GeneratedSequence<T>, String> {
+    if steps == 0 {
+        return Err("Random pattern needs at least one step".to_string());
+    }
+    check_steps(steps, "Random pattern")?;
+
+    let mut rng = SeededRandom::new(seed);
+    let entries = (0..steps)
+        .filter(|_| rng.next_f32() < density)
+        .map(|i| SequencerEntry::new(step_beats * BigDecimal::from(i), contents.clone()))
+        .collect();
+
+    Ok(GeneratedSequence {
+        entries,
+        end_beat: step_beats * BigDecimal::from(steps),
+    })
+}
+
+pub fn arpeggio_order(notes: &[f32], mode: &ArpMode) -> Vec<f32> {
+    let mut sorted = notes.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+    match mode {
+        ArpMode::Up => sorted,
+        ArpMode::Down => sorted.into_iter().rev().collect(),
+        ArpMode::UpDown => {
+            // Top and bottom notes are not repeated on the turn
+            let mut order = sorted.clone();
+            if sorted.len() > 2 {
+                order.extend(sorted[1..sorted.len() - 1].iter().rev());
+            }
+            order
+        }
+        ArpMode::AsPlayed => notes.to_vec(),
+        ArpMode::Random(seed) => {
+            let mut rng = SeededRandom::new(*seed);
+            (0..notes.len()).map(|_| notes[rng.next_index(notes.len())]).collect()
+        }
+    }
+}
+
+/*
+    Cycle through the note order for the given amount of steps (or once through if steps is 0),
+        creating contents for each note with the provided function.
+*/
+pub fn arpeggio<T: Clone, F: Fn(f32) -> T>(
+    notes: &[f32],
+    mode: &ArpMode,
+    steps: u32,
+    step_beats: &BigDecimal,
+    make_contents: F,
+) -> Result<GeneratedSequence<T>, String> {
+    if notes.is_empty() {
+        return Err("Arpeggio needs at least one note".to_string());
+    }
+    check_steps(notes.len().min(u32::MAX as usize) as u32, "Arpeggio")?;
+    check_steps(steps, "Arpeggio")?;
+
+    let order = arpeggio_order(notes, mode);
+    let step_count = if steps == 0 { order.len() as u32 } else { steps };
+
+    let entries = (0..step_count)
+        .map(|i| {
+            let note = order[i as usize % order.len()];
+            SequencerEntry::new(step_beats * BigDecimal::from(i), make_contents(note))
+        })
+        .collect();
+
+    Ok(GeneratedSequence {
+        entries,
+        end_beat: step_beats * BigDecimal::from(step_count),
+    })
+}
+
+/*
+    Generator parameters as received over OSC, see bundle_model::GenerateQueueMessage.
+*/
+#[derive(Debug, Clone)]
+pub enum GeneratorSpec {
+    Euclid {
+        hits: u32,
+        steps: u32,
+        rotation: u32,
+        step_beats: BigDecimal,
+    },
+    Polyrhythm {
+        length_beats: BigDecimal,
+        divisions: Vec<u32>,
+    },
+    RandomDensity {
+        steps: u32,
+        step_beats: BigDecimal,
+        density: f32,
+        seed: u64,
+    },
+    Arpeggio {
+        mode: ArpMode,
+        steps: u32,
+        step_beats: BigDecimal,
+        arg_name: String,
+        notes: Vec<f32>,
+    },
+}
+
+impl GeneratorSpec {
+    /*
+        Generate a sequence of the provided packets.
+        Polyrhythm layers use one packet each (cycling if fewer packets than layers are given),
+            all other generators use the first packet.
+    */
+    pub fn generate(&self, packets: &[OscPacket]) -> Result<GeneratedSequence<OscPacket>, String> {
+        let first = packets
+            .first()
+            .cloned()
+            .ok_or("Generator needs at least one packet".to_string())?;
+
+        match self {
+            GeneratorSpec::Euclid { hits, steps, rotation, step_beats } => {
+                euclidean(*hits, *steps, *rotation, step_beats, first)
+            }
+            GeneratorSpec::Polyrhythm { length_beats, divisions } => {
+                let layers = divisions
+                    .iter()
+                    .enumerate()
+                    .map(|(i, div)| (*div, packets[i % packets.len()].clone()))
+                    .collect();
+                polyrhythm(length_beats, layers)
+            }
+            GeneratorSpec::RandomDensity { steps, step_beats, density, seed } => {
+                random_density(*steps, step_beats, *density, *seed, first)
+            }
+            GeneratorSpec::Arpeggio { mode, steps, step_beats, arg_name, notes } => {
+                arpeggio(notes, mode, *steps, step_beats, |note| {
+                    packet_utils::set_named_arg(&first, arg_name, OscType::Float(note))
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bigdecimal::BigDecimal;
+
+    use super::{arpeggio, arpeggio_order, euclidean, euclidean_pattern, polyrhythm, random_density, ArpMode, MAX_STEPS};
+
+    fn big(inp: &str) -> BigDecimal {
+        BigDecimal::from_str(inp).unwrap()
+    }
+
+    #[test]
+    fn euclidean_pattern_test() {
+        let tresillo: Vec<bool> = "x..x..x.".chars().map(|c| c == 'x').collect();
+        assert_eq!(euclidean_pattern(3, 8, 0), tresillo);
+        assert_eq!(euclidean_pattern(5, 16, 0).iter().filter(|hit| **hit).count(), 5);
+        assert_eq!(euclidean_pattern(0, 4, 0), vec![false, false, false, false]);
+
+        let rotated: Vec<bool> = "..x..x.x".chars().map(|c| c == 'x').collect();
+        assert_eq!(euclidean_pattern(3, 8, 1), rotated);
+    }
+
+    #[test]
+    fn euclidean_rotation_test() {
+        let onsets = |rotation: u32| -> Vec<usize> {
+            euclidean_pattern(3, 8, rotation).iter().enumerate().filter(|(_, hit)| **hit).map(|(i, _)| i).collect()
+        };
+        assert_eq!(onsets(0), vec![0, 3, 6]);
+        // Each onset moves one step earlier, the one on step 0 wrapping around to the end
+        assert_eq!(onsets(1), vec![2, 5, 7]);
+        assert_eq!(onsets(8), onsets(0));
+    }
+
+    #[test]
+    fn step_limit_test() {
+        assert!(euclidean(1, MAX_STEPS + 1, 0, &big("0.25"), "hat").is_err());
+        assert!(random_density(u32::MAX, &big("0.25"), 0.5, 1, "hit").is_err());
+        assert!(polyrhythm(&big("4.0"), vec![(MAX_STEPS, "a"), (1, "b")]).is_err());
+        assert!(arpeggio(&[60.0], &ArpMode::Up, MAX_STEPS + 1, &big("0.5"), |note| note).is_err());
+    }
+
+    #[test]
+    fn euclidean_sequence_test() {
+        let generated = euclidean(3, 8, 0, &big("0.25"), "hat").unwrap();
+        let beats: Vec<BigDecimal> = generated.entries.iter().map(|e| e.trigger_beat.clone()).collect();
+        assert_eq!(beats, vec![big("0.0"), big("0.75"), big("1.5")]);
+        assert_eq!(generated.end_beat, big("2.0"));
+
+        assert!(euclidean(5, 4, 0, &big("0.25"), "hat").is_err());
+        assert!(euclidean(0, 0, 0, &big("0.25"), "hat").is_err());
+    }
+
+    #[test]
+    fn polyrhythm_test() {
+        let generated = polyrhythm(&big("3.0"), vec![(3, "three"), (2, "two")]).unwrap();
+        let contents: Vec<&str> = generated.entries.iter().map(|e| e.contents).collect();
+        assert_eq!(contents, vec!["three", "two", "three", "two", "three"]);
+        assert_eq!(generated.entries[3].trigger_beat, big("1.5"));
+        assert_eq!(generated.end_beat, big("3.0"));
+    }
+
+    #[test]
+    fn random_density_test() {
+        let first = random_density(64, &big("0.25"), 0.5, 1234, "hit").unwrap();
+        let second = random_density(64, &big("0.25"), 0.5, 1234, "hit").unwrap();
+        let first_beats: Vec<BigDecimal> = first.entries.iter().map(|e| e.trigger_beat.clone()).collect();
+        let second_beats: Vec<BigDecimal> = second.entries.iter().map(|e| e.trigger_beat.clone()).collect();
+        assert_eq!(first_beats, second_beats);
+        assert!(!first.entries.is_empty() && first.entries.len() < 64);
+
+        assert_eq!(random_density(16, &big("0.25"), 1.0, 1, "hit").unwrap().entries.len(), 16);
+        assert_eq!(random_density(16, &big("0.25"), 0.0, 1, "hit").unwrap().entries.len(), 0);
+    }
+
+    #[test]
+    fn arpeggio_test() {
+        let notes = vec![64.0, 60.0, 67.0];
+        assert_eq!(arpeggio_order(&notes, &ArpMode::Up), vec![60.0, 64.0, 67.0]);
+        assert_eq!(arpeggio_order(&notes, &ArpMode::Down), vec![67.0, 64.0, 60.0]);
+        assert_eq!(arpeggio_order(&notes, &ArpMode::UpDown), vec![60.0, 64.0, 67.0, 64.0]);
+        assert_eq!(arpeggio_order(&notes, &ArpMode::AsPlayed), notes);
+
+        let generated = arpeggio(&notes, &ArpMode::Up, 5, &big("0.5"), |note| note).unwrap();
+        let contents: Vec<f32> = generated.entries.iter().map(|e| e.contents).collect();
+        assert_eq!(contents, vec![60.0, 64.0, 67.0, 60.0, 64.0]);
+        assert_eq!(generated.end_beat, big("2.5"));
+    }
+}
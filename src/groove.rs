@@ -0,0 +1,67 @@
+use bigdecimal::{BigDecimal, FromPrimitive, Zero};
+
+use crate::ticks::{self, Ticks};
+
+/*
+
+    Groove templates: a table of timing offsets (in beats), one per step of the given resolution.
+    Steps are counted from the start of the sequence, and the table repeats.
+
+    E.g. a 16th note swing is resolution 0.25 with offsets [0.0, 0.08], delaying every
+        second 16th note by 0.08 beats.
+
+*/
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Groove {
+    pub resolution: BigDecimal,
+    pub offsets: Vec<BigDecimal>,
+}
+
+impl Groove {
+    pub fn new(resolution: BigDecimal, offsets: Vec<BigDecimal>) -> Result<Groove, String> {
+        if resolution <= BigDecimal::zero() {
+            return Err(format!("Groove resolution must be positive, got {}", resolution));
+        }
+        if offsets.is_empty() {
+            return Err("Groove needs at least one offset".to_string());
+        }
+        Ok(Groove { resolution, offsets })
+    }
+
+    /*
+        MPC-style swing percentage: 50 is straight, ~66 is a triplet feel.
+        Every second step is delayed by the corresponding share of a step.
+    */
+    pub fn swing(percent: f32, resolution: BigDecimal) -> Result<Groove, String> {
+        if !(0.0..100.0).contains(&percent) {
+            return Err(format!("Swing percentage must be within 0-100, got {}", percent));
+        }
+        let share = BigDecimal::from_f32(percent * 2.0 / 100.0 - 1.0).unwrap_or_default();
+        let delay = &resolution * share;
+        Groove::new(resolution, vec![BigDecimal::zero(), delay])
+    }
+
+    pub fn to_ticks(&self) -> TickGroove {
+        TickGroove {
+            // A resolution finer than a tick still needs whole steps
+            resolution: ticks::from_beats(&self.resolution).max(1),
+            offsets: self.offsets.iter().map(ticks::from_beats).collect(),
+        }
+    }
+}
+
+// A groove converted to ticks, once when set rather than for every entry on every loop
+#[derive(Debug, Clone, PartialEq)]
+pub struct TickGroove {
+    resolution: Ticks,
+    offsets: Vec<Ticks>,
+}
+
+impl TickGroove {
+    // Offset for an entry at the given tick, based on the step it falls within
+    pub fn offset_for(&self, tick: Ticks) -> Ticks {
+        let step = tick.div_euclid(self.resolution);
+        self.offsets[step.rem_euclid(self.offsets.len() as i64) as usize]
+    }
+}
@@ -0,0 +1,516 @@
+use std::convert::TryFrom;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bigdecimal::{BigDecimal, ToPrimitive, Zero};
+use log::{info, warn};
+use rosc::{OscMessage, OscPacket, OscType};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tungstenite::{handshake::derive_accept_key, protocol::Role, Message, WebSocket};
+
+use crate::control::ControlHandlers;
+use crate::local_messaging::{LocalQueuePayload, LocalSequencerMessage};
+use crate::sequencer::SequencerEntry;
+use crate::sequencing_daemon::DaemonStatus;
+use crate::timeline;
+
+/*
+
+    Embedded HTTP gateway for browser control surfaces (behind the http_gateway feature).
+    JSON requests are translated into the same LocalSequencerMessages as the OSC handlers push.
+
+        GET  /state   current bpm and sequencer positions
+        POST /bpm     {"bpm": 140}
+        POST /stop    hard stop
+        POST /reset   reset all sequencers
+        POST /queue   {"alias": "drums", "one_shot": false, "seed": 1,
+                       "entries": [{"time": 0.5, "address": "/note_on", "args": ["kick", 1.0]}]}
+        GET  /events  WebSocket stream of "transport" and "position" events
+
+    Queue entries follow the OSC update_queue format: "time" is the beats until the next entry.
+    Event streams check the status every event_interval (http_event_interval_ms in the config).
+
+    Each connection serves one request on its own thread (no keep-alive). Event streams read the socket
+        between updates, answering pings and closes, and end when the client goes away or on /shutdown.
+
+*/
+
+// How long a connection may take to send its request, or to take in what is written to it
+const IO_TIMEOUT: Duration = Duration::from_secs(1);
+// Queue requests with many entries are the largest
+const MAX_REQUEST_SIZE: usize = 1 << 20;
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Deserialize)]
+struct BpmRequest {
+    bpm: i32,
+}
+
+#[derive(Deserialize)]
+struct QueueRequest {
+    alias: String,
+    #[serde(default)]
+    one_shot: bool,
+    seed: Option<u64>,
+    entries: Vec<QueueEntryRequest>,
+}
+
+#[derive(Deserialize)]
+struct QueueEntryRequest {
+    time: Value,
+    address: String,
+    #[serde(default)]
+    args: Vec<Value>,
+}
+
+// Bind and serve on a thread of its own, returning the bound address (e.g. the port picked for port 0)
+pub fn start_server(
+    addr: String,
+    control: Arc<ControlHandlers>,
+    status: Arc<Mutex<DaemonStatus>>,
+    event_interval: Duration,
+) -> SocketAddr {
+    let listener = match TcpListener::bind(&addr) {
+        Ok(listener) => listener,
+        Err(e) => panic!("Failed to start HTTP gateway on {}: {}", addr, e),
+    };
+    let local_addr = listener.local_addr().unwrap_or_else(|e| panic!("Failed to start HTTP gateway on {}: {}", addr, e));
+
+    info!("STARTING HTTP GATEWAY ON {}", local_addr);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let control = control.clone();
+                    let status = status.clone();
+                    thread::spawn(move || handle_connection(stream, &control, &status, event_interval));
+                }
+                Err(e) => warn!("Failed to accept HTTP connection: {}", e),
+            }
+        }
+    });
+
+    local_addr
+}
+
+fn handle_connection(
+    mut stream: TcpStream,
+    control: &ControlHandlers,
+    status: &Arc<Mutex<DaemonStatus>>,
+    event_interval: Duration,
+) {
+    let request = stream
+        .set_read_timeout(Some(IO_TIMEOUT))
+        .and_then(|()| stream.set_write_timeout(Some(IO_TIMEOUT)))
+        .map_err(|e| e.to_string())
+        .and_then(|()| read_request(&mut stream));
+    let request = match request {
+        Ok(request) => request,
+        Err(e) => {
+            respond(&mut stream, 400, json!({"error": e}));
+            return;
+        }
+    };
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/state") => {
+            let snapshot = status.lock().unwrap().clone();
+            respond(&mut stream, 200, state_json(&snapshot));
+        }
+        ("GET", "/events") => open_event_stream(stream, &request, control, status, event_interval),
+        ("POST", path) => {
+            let body = String::from_utf8_lossy(&request.body);
+            match to_local_message(path, &body) {
+                Ok(msg) => {
+                    info!("HTTP {}", path);
+                    match control.push(msg) {
+                        Ok(()) => respond(&mut stream, 200, json!({"ok": true})),
+                        Err(e) => respond(&mut stream, 503, json!({"error": e})),
+                    }
+                }
+                Err(e) => {
+                    warn!("Rejected HTTP request to {}: {}", path, e);
+                    respond(&mut stream, 400, json!({"error": e}));
+                }
+            }
+        }
+        (method, path) => respond(&mut stream, 404, json!({"error": format!("No route for {} {}", method, path)})),
+    }
+}
+
+// Request line and headers, then as much body as Content-Length gives
+fn read_request(stream: &mut TcpStream) -> Result<HttpRequest, String> {
+    let mut data = vec![];
+    let mut buf = [0u8; 8192];
+    let header_end = loop {
+        if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        if data.len() > MAX_REQUEST_SIZE {
+            return Err("Request headers too large".to_string());
+        }
+        match stream.read(&mut buf).map_err(|e| e.to_string())? {
+            0 => return Err("Connection closed before the end of the request".to_string()),
+            read => data.extend_from_slice(&buf[..read]),
+        }
+    };
+
+    let head = String::from_utf8_lossy(&data[..header_end]).to_string();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or("").split_whitespace();
+    let method = request_line.next().unwrap_or("").to_string();
+    let path = request_line.next().unwrap_or("").split('?').next().unwrap_or("").to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(field, value)| (field.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    let mut request = HttpRequest { method, path, headers, body: data.split_off(header_end + 4) };
+    let length = match request.header("Content-Length") {
+        Some(length) => length.parse::<usize>().map_err(|e| format!("Invalid Content-Length '{}': {}", length, e))?,
+        None => 0,
+    };
+    if length > MAX_REQUEST_SIZE {
+        return Err(format!("Request body of {} bytes is larger than {}", length, MAX_REQUEST_SIZE));
+    }
+    while request.body.len() < length {
+        match stream.read(&mut buf).map_err(|e| e.to_string())? {
+            0 => return Err("Connection closed before the end of the request body".to_string()),
+            read => request.body.extend_from_slice(&buf[..read]),
+        }
+    }
+    request.body.truncate(length);
+    Ok(request)
+}
+
+fn respond(stream: &mut TcpStream, status_code: u16, body: Value) {
+    let reason = match status_code {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        503 => "Service Unavailable",
+        _ => "",
+    };
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status_code,
+        reason,
+        body.len(),
+        body
+    );
+    if let Err(e) = stream.write_all(response.as_bytes()) {
+        warn!("Failed to send HTTP response: {}", e);
+    }
+}
+
+// Translate the body of a POST request into the corresponding sequencer message
+pub fn to_local_message(path: &str, body: &str) -> Result<LocalSequencerMessage<OscPacket>, String> {
+    match path {
+        "/bpm" => {
+            let req: BpmRequest = serde_json::from_str(body).map_err(|e| e.to_string())?;
+            timeline::check_bpm(req.bpm)?;
+            Ok(LocalSequencerMessage::SetBpm(req.bpm))
+        }
+        "/stop" => Ok(LocalSequencerMessage::HardStop),
+        "/reset" => Ok(LocalSequencerMessage::Reset),
+        "/queue" => {
+            let req: QueueRequest = serde_json::from_str(body).map_err(|e| e.to_string())?;
+
+            let mut entries: Vec<SequencerEntry<OscPacket>> = vec![];
+            let mut timeline = BigDecimal::zero();
+            for entry in req.entries {
+                let args = entry.args.iter().map(to_osc_arg).collect::<Result<Vec<OscType>, String>>()?;
+                entries.push(SequencerEntry::new(
+                    timeline.clone(),
+                    OscPacket::Message(OscMessage { addr: entry.address, args }),
+                ));
+                timeline += to_beats(&entry.time)?;
+            }
+
+            Ok(LocalSequencerMessage::Queue(LocalQueuePayload {
+                sequencer_alias: req.alias,
+                entries,
+                lanes: vec![],
+                end_beat: timeline,
+                one_shot: req.one_shot,
+                seed: req.seed,
+            }))
+        }
+        other => Err(format!("No route for POST {}", other)),
+    }
+}
+
+// Beats are given as a number or a decimal string, parsed from text to avoid float rounding
+fn to_beats(value: &Value) -> Result<BigDecimal, String> {
+    let text = match value {
+        Value::Number(number) => number.to_string(),
+        Value::String(text) => text.clone(),
+        other => return Err(format!("Expected beats as a number or string, got {}", other)),
+    };
+    let beats = BigDecimal::from_str(&text).map_err(|e| format!("Invalid beats '{}': {}", text, e))?;
+    if beats < BigDecimal::zero() {
+        return Err(format!("Beats must not be negative, got {}", beats));
+    }
+    Ok(beats)
+}
+
+// Whole numbers become ints and other numbers floats, as they would from a JDW client
+fn to_osc_arg(value: &Value) -> Result<OscType, String> {
+    match value {
+        Value::String(text) => Ok(OscType::String(text.clone())),
+        Value::Bool(flag) => Ok(OscType::Bool(*flag)),
+        Value::Number(number) => match number.as_i64().and_then(|int| i32::try_from(int).ok()) {
+            Some(int) => Ok(OscType::Int(int)),
+            None => number
+                .as_f64()
+                .map(|float| OscType::Float(float as f32))
+                .ok_or(format!("Unsupported number arg {}", number)),
+        },
+        other => Err(format!("Unsupported arg {}", other)),
+    }
+}
+
+fn state_json(status: &DaemonStatus) -> Value {
+    json!({
+        "bpm": status.bpm,
+        "running": status.is_running(),
+        "sequencers": position_json(status),
+    })
+}
+
+fn position_json(status: &DaemonStatus) -> Value {
+    status
+        .sequencers
+        .iter()
+        .map(|seq| {
+            json!({
+                "alias": seq.alias,
+                "active": seq.active,
+                "beat": seq.current_beat.to_f64().unwrap_or(0.0),
+                "end_beat": seq.end_beat.to_f64().unwrap_or(0.0),
+                "loop": seq.loop_index,
+                "one_shot": seq.one_shot,
+            })
+        })
+        .collect()
+}
+
+fn open_event_stream(
+    mut stream: TcpStream,
+    request: &HttpRequest,
+    control: &ControlHandlers,
+    status: &Arc<Mutex<DaemonStatus>>,
+    interval: Duration,
+) {
+    let key = match request.header("Sec-WebSocket-Key") {
+        Some(key) => key,
+        None => {
+            respond(&mut stream, 400, json!({"error": "Expected a WebSocket upgrade"}));
+            return;
+        }
+    };
+
+    let handshake = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        derive_accept_key(key.as_bytes())
+    );
+    // Reads return at least every interval, so that waiting on the client never holds up an update
+    let ready = stream
+        .write_all(handshake.as_bytes())
+        .and_then(|()| stream.set_read_timeout(Some(interval.max(Duration::from_millis(1)))));
+    if let Err(e) = ready {
+        warn!("Failed to open event stream: {}", e);
+        return;
+    }
+
+    let mut socket = WebSocket::from_raw_socket(stream, Role::Server, None);
+
+    let mut last_transport: Option<(i32, bool)> = None;
+    let mut last_tick: Option<u64> = None;
+    let mut next_update = Instant::now();
+
+    info!("WEBSOCKET CLIENT CONNECTED");
+
+    loop {
+        if control.is_shutdown() {
+            let _ = socket.close(None);
+            let _ = socket.flush();
+            break;
+        }
+
+        // Pings are answered and closes acknowledged by tungstenite itself, on the next write or flush
+        match socket.read() {
+            Ok(Message::Close(_)) => {
+                let _ = socket.flush();
+                break;
+            }
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+            Err(_) => break,
+        }
+
+        if Instant::now() >= next_update {
+            next_update = Instant::now() + interval;
+
+            let snapshot = status.lock().unwrap().clone();
+            let transport = (snapshot.bpm, snapshot.is_running());
+
+            let mut events = vec![];
+            if last_transport != Some(transport) {
+                events.push(json!({"type": "transport", "bpm": transport.0, "running": transport.1}));
+                last_transport = Some(transport);
+            }
+            if transport.1 && last_tick != Some(snapshot.tick) {
+                events.push(json!({"type": "position", "sequencers": position_json(&snapshot)}));
+                last_tick = Some(snapshot.tick);
+            }
+
+            for event in events {
+                if let Err(e) = socket.write(Message::Text(event.to_string())) {
+                    warn!("Failed to send event: {}", e);
+                    break;
+                }
+            }
+        }
+
+        if socket.flush().is_err() {
+            break;
+        }
+    }
+
+    info!("WEBSOCKET CLIENT DISCONNECTED");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use bigdecimal::BigDecimal;
+    use ringbuf::traits::{Consumer, Split};
+    use ringbuf::HeapRb;
+    use rosc::{OscPacket, OscType};
+    use serde_json::{json, Value};
+    use tungstenite::Message;
+
+    use crate::control::ControlHandlers;
+    use crate::local_messaging::{LocalMessageSender, LocalSequencerMessage};
+    use crate::osc_communication::OSCClient;
+    use crate::sequencing_daemon::DaemonStatus;
+
+    use super::{start_server, to_local_message};
+
+    // Send a whole request and read the response until the server closes the connection
+    fn http(addr: SocketAddr, request: &str) -> (String, Value) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_string(), serde_json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn loopback_test() {
+        let (producer, mut consumer) = HeapRb::<LocalSequencerMessage<OscPacket>>::new(16).split();
+        let status = Arc::new(Mutex::new(DaemonStatus { bpm: 120, ..DaemonStatus::default() }));
+        let control = Arc::new(ControlHandlers::new(
+            Arc::new(LocalMessageSender::new(producer, Duration::from_millis(10))),
+            Arc::new(OSCClient::from_outputs("127.0.0.1:0", &[]).unwrap()),
+            status.clone(),
+        ));
+        let addr = start_server("127.0.0.1:0".to_string(), control.clone(), status, Duration::from_millis(10));
+
+        let body = r#"{"bpm": 140}"#;
+        let (status_line, reply) = http(
+            addr,
+            &format!("POST /bpm HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", body.len(), body),
+        );
+        assert_eq!(status_line, "HTTP/1.1 200 OK");
+        assert_eq!(reply, json!({"ok": true}));
+        assert!(matches!(consumer.try_pop(), Some(LocalSequencerMessage::SetBpm(140))));
+
+        let (status_line, state) = http(addr, "GET /state HTTP/1.1\r\n\r\n");
+        assert_eq!(status_line, "HTTP/1.1 200 OK");
+        assert_eq!(state, json!({"bpm": 120, "running": false, "sequencers": []}));
+
+        let (status_line, _) = http(addr, "GET /nope HTTP/1.1\r\n\r\n");
+        assert_eq!(status_line, "HTTP/1.1 404 Not Found");
+
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let (mut socket, _) = tungstenite::client(format!("ws://{}/events", addr), stream).unwrap();
+        match socket.read().unwrap() {
+            Message::Text(text) => assert_eq!(
+                serde_json::from_str::<Value>(&text).unwrap(),
+                json!({"type": "transport", "bpm": 120, "running": false})
+            ),
+            other => panic!("Expected a transport event, got {:?}", other),
+        }
+
+        // Nothing else happens while stopped, so the next message is the answer to the ping
+        socket.send(Message::Ping(vec![1, 2])).unwrap();
+        assert_eq!(socket.read().unwrap(), Message::Pong(vec![1, 2]));
+
+        // The stream closes on shutdown
+        control.request_shutdown();
+        assert!(matches!(socket.read(), Ok(Message::Close(_))));
+    }
+
+    #[test]
+    fn queue_request_test() {
+        let body = r#"{"alias": "drums", "entries": [
+            {"time": 0.5, "address": "/note_on", "args": ["kick", 1, 0.25]},
+            {"time": "1.5", "address": "/note_on", "args": ["snare"]}
+        ]}"#;
+
+        match to_local_message("/queue", body).unwrap() {
+            LocalSequencerMessage::Queue(payload) => {
+                assert_eq!(payload.sequencer_alias, "drums");
+                assert!(!payload.one_shot);
+                assert_eq!(payload.end_beat, BigDecimal::from_str("2.0").unwrap());
+                assert_eq!(payload.entries[1].trigger_beat, BigDecimal::from_str("0.5").unwrap());
+                match &payload.entries[0].contents {
+                    OscPacket::Message(msg) => assert_eq!(
+                        msg.args,
+                        vec![OscType::String("kick".to_string()), OscType::Int(1), OscType::Float(0.25)]
+                    ),
+                    _ => panic!("Expected a message"),
+                }
+            }
+            _ => panic!("Expected a queue message"),
+        }
+    }
+
+    #[test]
+    fn invalid_request_test() {
+        assert!(to_local_message("/bpm", r#"{"bpm": -1}"#).is_err());
+        assert!(to_local_message("/queue", r#"{"alias": "x", "entries": [{"time": -1, "address": "/a"}]}"#).is_err());
+        assert!(to_local_message("/nope", "").is_err());
+    }
+}
@@ -1,33 +1,68 @@
 #![feature(result_flattening, proc_macro_hygiene, decl_macro)]
 
+pub mod automation;
 pub mod bundle_model;
+pub mod client;
+pub mod clock;
 pub mod config;
+pub mod control;
+pub mod engine;
+pub mod generators;
+pub mod groove;
+#[cfg(feature = "http_gateway")]
+pub mod http_gateway;
 pub mod local_messaging;
 pub mod master_sequencer;
 pub mod midi_utils;
 pub mod osc_communication;
+pub mod packet_utils;
+pub mod protocol;
+pub mod random;
+pub mod realtime;
+pub mod render;
 pub mod sequencer;
 pub mod sequencing_daemon;
+pub mod slip;
+pub mod stats;
+pub mod tcp_communication;
+pub mod ticks;
+pub mod timeline;
+pub mod transform;
+pub mod udp_communication;
 
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
-use local_messaging::{LocalQueuePayload, LocalSequencerMessage};
 use log::{info, warn};
-use master_sequencer::MasterSequencer;
-use ringbuf::traits::{Producer, Split};
-use ringbuf::HeapRb;
 use rosc::{OscBundle, OscMessage, OscPacket, OscTime, OscType};
 use std::convert::TryFrom;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use chrono::{DateTime, Utc};
 
-use bundle_model::UpdateQueueMessage;
-
-use crate::bundle_model::BatchUpdateQueuesMessage;
+use crate::control::ControlHandlers;
+use crate::engine::EngineBuilder;
 use crate::osc_communication::OSCClient;
-use jdw_osc_lib::osc_stack::OSCStack;
+use crate::sequencing_daemon::OutgoingEvent;
+
+// Wrap a packet in the timed bundle format expected by the OSC router
+fn real_time_bundle(packet: OscPacket, send_time: SystemTime) -> OscPacket {
+    OscPacket::Bundle(OscBundle {
+        timetag: OscTime::try_from(send_time).unwrap(),
+        content: vec![
+            OscPacket::Message(OscMessage {
+                addr: "/bundle_info".to_string(),
+                args: vec![OscType::String("real_time_packet".to_string())],
+            }),
+            OscPacket::Message(OscMessage {
+                addr: "/info_msg".to_string(),
+                args: vec![OscType::Time(OscTime::try_from(send_time).unwrap())],
+            }),
+            packet,
+        ],
+    })
+}
 
-/// Run the jdw-sequencer daemon. Blocks the calling thread indefinitely.
+/// Run the jdw-sequencer daemon. Blocks the calling thread until shutdown
+/// (a `/shutdown` message, SIGINT or SIGTERM).
 ///
 /// * `config_path` – path to the per-app `config.toml`.
 /// * `quiet`       – suppress non-error log output.
@@ -47,12 +82,8 @@ pub fn run(config_path: &str, quiet: bool) {
         })
         .init();
 
-    let osc_pipe = HeapRb::<LocalSequencerMessage<OscPacket>>::new(cfg.ringbuf_capacity);
-    let (osc_pub, osc_sub) = osc_pipe.split();
-
-    let osc_pub_mutex = Arc::new(Mutex::new(osc_pub));
-
-    let osc_client = OSCClient::new();
+    let osc_client = Arc::new(OSCClient::new());
+    let sender_client = osc_client.clone();
 
     let start_mode = match cfg.sequencer_start_mode {
         0 => master_sequencer::SequencerStartMode::WithNearestSequence,
@@ -64,168 +95,86 @@ pub fn run(config_path: &str, quiet: bool) {
         _ => master_sequencer::SequencerResetMode::Individual,
     };
 
-    let master = MasterSequencer::new(start_mode, reset_mode);
-
-    sequencing_daemon::start_live_loop::<OscPacket, _>(
-        master,
-        cfg.default_bpm,
-        osc_sub,
-        move |packets_to_send, tick_time| {
-            if !packets_to_send.is_empty() {
-                info!("TICK! {:?}", tick_time);
-
-                let send_packets = packets_to_send.iter().map(|pct| {
-                    if cfg.real_time_mode {
-                        OscPacket::Bundle(OscBundle {
-                            timetag: OscTime::try_from(tick_time).unwrap(),
-                            content: vec![
-                                OscPacket::Message(OscMessage {
-                                    addr: "/bundle_info".to_string(),
-                                    args: vec![OscType::String("real_time_packet".to_string())],
-                                }),
-                                OscPacket::Message(OscMessage {
-                                    addr: "/info_msg".to_string(),
-                                    args: vec![OscType::Time(
-                                        OscTime::try_from(tick_time).unwrap(),
-                                    )],
-                                }),
-                                pct.clone(),
-                            ],
-                        })
+    let output = move |events: Vec<OutgoingEvent<OscPacket>>, tick_time: SystemTime| {
+        if !events.is_empty() {
+            info!("TICK! {:?}", tick_time);
+
+            for outgoing in events {
+                let event = &outgoing.event;
+                let pct = packet_utils::event_packet(event, outgoing.variables.as_deref(), outgoing.transforms.as_deref());
+
+                for output in sender_client.outputs_for(&event.alias) {
+                    let packet = if cfg.real_time_mode {
+                        let send_time = sequencing_daemon::offset_time(
+                            outgoing.send_time,
+                            (output.nudge_ms * 1_000_000.0) as i64,
+                        );
+                        let datetime: DateTime<Utc> = send_time.into();
+                        info!("MY MAN SENDTIME {}", datetime.format("%d/%m/%Y %T"));
+
+                        real_time_bundle(pct.clone(), send_time)
                     } else {
                         pct.clone()
-                    }
-                });
+                    };
 
-                for packet in send_packets {
-                    if let OscPacket::Bundle(o) = packet.clone() {
-                        let sys: SystemTime = o.timetag.into();
-                        let datetime: DateTime<Utc> = sys.into();
-                        info!("MY MAN SENDTIME {}", datetime.format("%d/%m/%Y %T"));
-                    }
-
-                    osc_client.send(packet);
-                }
-            }
-        },
-    );
-
-    let addr = config::get_addr(cfg.application_in_port);
-    info!("STARTING OSC READER");
-
-    OSCStack::init(addr)
-        .on_message("/set_bpm", &|msg| {
-            let args = msg.clone().args;
-            let arg = args.get(0).clone();
-            match arg {
-                None => {
-                    warn!("Unable to parse set_bpm message (missing arg)")
-                }
-                Some(val) => match val.clone().int() {
-                    None => {
-                        warn!("set_bpm arg not an int")
-                    }
-                    Some(contained_val) => {
-                        info!("SET BPM");
-                        osc_pub_mutex
-                            .lock()
-                            .unwrap()
-                            .try_push(LocalSequencerMessage::SetBpm(contained_val))
-                            .unwrap();
-                    }
-                },
-            }
-        })
-        .on_message("/reset_all", &|_msg| {
-            info!("RESET ALL");
-            osc_pub_mutex
-                .lock()
-                .unwrap()
-                .try_push(LocalSequencerMessage::Reset)
-                .unwrap();
-        })
-        .on_message("/hard_stop", &|_msg| {
-            info!("HARD STOP");
-            osc_pub_mutex
-                .lock()
-                .unwrap()
-                .try_push(LocalSequencerMessage::HardStop)
-                .unwrap();
-        })
-        .on_message("/wipe_on_finish", &|_msg| {
-            info!("WIPE ON FINISH");
-            osc_pub_mutex
-                .lock()
-                .unwrap()
-                .try_push(LocalSequencerMessage::EndAfterFinish)
-                .unwrap();
-        })
-        .on_tbundle("batch_update_queues", &|tbundle| {
-            match BatchUpdateQueuesMessage::from_bundle(tbundle) {
-                Ok(batch_update_msg) => {
-                    if batch_update_msg.stop_missing {
-                        info!("END AFTER FINISH");
-                        osc_pub_mutex
-                            .lock()
-                            .unwrap()
-                            .try_push(LocalSequencerMessage::EndAfterFinish)
-                            .unwrap();
-                    }
-
-                    for update_queue_msg in batch_update_msg.update_queue_messages {
-                        let alias = update_queue_msg.alias.clone();
-
-                        let payload = sequencing_daemon::to_sequence(update_queue_msg.messages);
-
-                        info!("Updating queue for {}", &alias);
-
-                        let payload_local = LocalSequencerMessage::Queue(LocalQueuePayload {
-                            sequencer_alias: alias,
-                            entries: payload.message_sequence,
-                            end_beat: payload.end_beat,
-                            one_shot: update_queue_msg.one_shot,
-                        });
-
-                        info!("QUEUE CHANGED");
-                        osc_pub_mutex
-                            .lock()
-                            .unwrap()
-                            .try_push(payload_local)
-                            .unwrap();
-                    }
-                }
-                Err(e) => {
-                    warn!("Failed to parse batch update queue message: {}", e);
+                    sender_client.send_to(output, &packet);
                 }
             }
-        })
-        .on_tbundle(
-            "update_queue",
-            &|tbundle| match UpdateQueueMessage::from_bundle(tbundle) {
-                Ok(update_queue_msg) => {
-                    let alias = update_queue_msg.alias.clone();
-
-                    let payload = sequencing_daemon::to_sequence(update_queue_msg.messages);
-
-                    info!("Updating queue for {}", &alias);
-
-                    let payload_local = LocalSequencerMessage::Queue(LocalQueuePayload {
-                        sequencer_alias: alias,
-                        entries: payload.message_sequence,
-                        end_beat: payload.end_beat,
-                        one_shot: update_queue_msg.one_shot,
-                    });
-
-                    osc_pub_mutex
-                        .lock()
-                        .unwrap()
-                        .try_push(payload_local)
-                        .unwrap();
-                }
-                Err(e) => {
-                    warn!("Failed to parse update_queue message: {}", e);
-                }
-            },
-        )
-        .begin();
+        }
+    };
+
+    let engine = EngineBuilder::new()
+        .bpm(cfg.default_bpm)
+        .unwrap_or_else(|e| panic!("Invalid default_bpm in config: {}", e))
+        .start_mode(start_mode)
+        .reset_mode(reset_mode)
+        .tick_time(Duration::from_micros(cfg.tick_time_us))
+        .buffer_capacity(cfg.ringbuf_capacity)
+        .push_timeout(Duration::from_millis(cfg.control_push_timeout_ms))
+        .realtime(cfg.realtime_options())
+        .start(output);
+
+    let control = Arc::new(ControlHandlers::new(engine.sender(), osc_client.clone(), engine.status_handle()));
+
+    if cfg.application_in_tcp_port > 0 {
+        tcp_communication::start_listener(config::get_addr(cfg.application_in_tcp_port), control.clone());
+    }
+
+    #[cfg(feature = "http_gateway")]
+    if cfg.http_port > 0 {
+        http_gateway::start_server(
+            config::get_addr(cfg.http_port),
+            control.clone(),
+            engine.status_handle(),
+            Duration::from_millis(cfg.http_event_interval_ms),
+        );
+    }
+
+    if cfg.metrics_port > 0 {
+        stats::start_metrics_server(cfg.metrics_port, control.clone());
+    }
+
+    // A second signal while shutting down exits right away
+    let signal_control = control.clone();
+    if let Err(e) = ctrlc::set_handler(move || {
+        if signal_control.is_shutdown() {
+            std::process::exit(1);
+        }
+        signal_control.request_shutdown();
+    }) {
+        warn!("Failed to install signal handler: {}", e);
+    }
+
+    udp_communication::listen(config::get_addr(cfg.application_in_port), cfg.buffer_size, control);
+
+    info!("SHUTTING DOWN");
+    engine.shutdown();
+
+    for msg in &cfg.shutdown_messages {
+        match msg.to_packet() {
+            Ok(packet) => osc_client.send_to_all(&packet),
+            Err(e) => warn!("{}", e),
+        }
+    }
+    osc_client.flush(Duration::from_millis(500));
 }
@@ -1,6 +1,17 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
 use bigdecimal::BigDecimal;
+use log::warn;
+use ringbuf::{storage::Heap, traits::Producer, wrap::caching::Caching, SharedRb};
 
+use crate::automation::AutomationLane;
+use crate::groove::Groove;
 use crate::sequencer::SequencerEntry;
+use crate::sequencing_daemon::{Nudge, Variable};
+use crate::transform::Transform;
 
 #[derive(Debug, Clone)]
 pub enum LocalSequencerMessage<T: Clone> {
@@ -9,13 +20,200 @@ pub enum LocalSequencerMessage<T: Clone> {
     SetBpm(i32),
     EndAfterFinish,
     Queue(LocalQueuePayload<T>),
-    BatchQueue(Vec<LocalQueuePayload<T>>),
+    BatchQueue(Vec<LocalQueuePayload<T>>, bool), // Queues, and whether aliases missing from them should end after finishing
+    SetGroove(Option<String>, Option<Groove>), // Alias (or global if None) and groove (or clear if None)
+    SetNudge(String, Option<Nudge>), // Alias and nudge (or clear if None)
+    SetVariable(String, String, Option<Variable>), // Alias, name and value (or clear if None)
+    SetTransform(String, Option<Transform>), // Alias and transform to add (or clear all if None)
+}
+
+impl<T: Clone> LocalSequencerMessage<T> {
+    // True if applying this message makes an earlier, not yet applied, other message pointless
+    pub fn supersedes(&self, other: &LocalSequencerMessage<T>) -> bool {
+        match (self, other) {
+            (LocalSequencerMessage::Queue(new), LocalSequencerMessage::Queue(old)) => {
+                new.sequencer_alias == old.sequencer_alias
+            }
+            (LocalSequencerMessage::SetBpm(_), LocalSequencerMessage::SetBpm(_)) => true,
+            (LocalSequencerMessage::SetGroove(new, _), LocalSequencerMessage::SetGroove(old, _)) => new == old,
+            (LocalSequencerMessage::SetNudge(new, _), LocalSequencerMessage::SetNudge(old, _)) => new == old,
+            (LocalSequencerMessage::SetVariable(new_alias, new_name, _), LocalSequencerMessage::SetVariable(old_alias, old_name, _)) => {
+                new_alias == old_alias && new_name == old_name
+            }
+            (LocalSequencerMessage::SetTransform(new_alias, new), LocalSequencerMessage::SetTransform(old_alias, old)) => {
+                new_alias == old_alias && match (new, old) {
+                    (Some(new), Some(old)) => new.replaces(old),
+                    (Some(_), None) => false,
+                    (None, _) => true,
+                }
+            }
+            (LocalSequencerMessage::HardStop, LocalSequencerMessage::HardStop) => true,
+            (LocalSequencerMessage::Reset, LocalSequencerMessage::Reset) => true,
+            (LocalSequencerMessage::EndAfterFinish, LocalSequencerMessage::EndAfterFinish) => true,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct LocalQueuePayload<T: Clone> {
     pub sequencer_alias: String,
     pub entries: Vec<SequencerEntry<T>>,
+    pub lanes: Vec<AutomationLane<T>>,
     pub end_beat: BigDecimal, 
     pub one_shot: bool,
-}
\ No newline at end of file
+    pub seed: Option<u64>,
+}
+
+pub type LocalMessageProducer<T> = Caching<Arc<SharedRb<Heap<LocalSequencerMessage<T>>>>, true, false>;
+pub type LocalMessageConsumer<T> = Caching<Arc<SharedRb<Heap<LocalSequencerMessage<T>>>>, false, true>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SenderMetrics {
+    pub sent: u64, // Pushed to the daemon
+    pub coalesced: u64, // Replaced by a later message while waiting for room
+    pub dropped: u64, // Gave up on after the timeout
+    pub retries: u64, // Times a sender had to wait for room
+}
+
+struct SenderState<T: Clone> {
+    producer: LocalMessageProducer<T>,
+    pending: VecDeque<(u64, LocalSequencerMessage<T>)>,
+    next_ticket: u64,
+    metrics: SenderMetrics,
+}
+
+impl<T: Clone> SenderState<T> {
+    // Move as many pending messages as there is room for into the ring buffer, oldest first
+    fn flush(&mut self) {
+        while let Some((ticket, msg)) = self.pending.pop_front() {
+            match self.producer.try_push(msg) {
+                Ok(()) => self.metrics.sent += 1,
+                Err(msg) => {
+                    self.pending.push_front((ticket, msg));
+                    break;
+                }
+            }
+        }
+    }
+
+    fn is_pending(&self, ticket: u64) -> bool {
+        self.pending.iter().any(|(pending_ticket, _)| *pending_ticket == ticket)
+    }
+}
+
+/*
+    Thread-safe front for the daemon ring buffer, shared by all control transports.
+
+    When the buffer is full, messages wait in order in a pending list and the sender retries
+        until there is room or the timeout passes, in which case the message is dropped and an
+        error returned. A waiting message superseded by a later one (see supersedes) is replaced,
+        which counts as delivered for the original sender.
+*/
+pub struct LocalMessageSender<T: Clone> {
+    state: Mutex<SenderState<T>>,
+    timeout: Duration,
+}
+
+impl<T: Clone> LocalMessageSender<T> {
+    pub fn new(producer: LocalMessageProducer<T>, timeout: Duration) -> LocalMessageSender<T> {
+        LocalMessageSender {
+            state: Mutex::new(SenderState {
+                producer,
+                pending: VecDeque::new(),
+                next_ticket: 0,
+                metrics: SenderMetrics::default(),
+            }),
+            timeout,
+        }
+    }
+
+    pub fn send(&self, msg: LocalSequencerMessage<T>) -> Result<(), String> {
+        let deadline = Instant::now() + self.timeout;
+
+        let ticket = {
+            let mut state = self.state.lock().unwrap();
+
+            let before = state.pending.len();
+            state.pending.retain(|(_, pending)| !msg.supersedes(pending));
+            state.metrics.coalesced += (before - state.pending.len()) as u64;
+
+            let ticket = state.next_ticket;
+            state.next_ticket += 1;
+            state.pending.push_back((ticket, msg));
+            ticket
+        };
+
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                state.flush();
+
+                if !state.is_pending(ticket) {
+                    return Ok(());
+                }
+
+                if Instant::now() >= deadline {
+                    state.pending.retain(|(pending_ticket, _)| *pending_ticket != ticket);
+                    state.metrics.dropped += 1;
+                    warn!("Dropped sequencer message after {:?}, buffer full", self.timeout);
+                    return Err("Sequencer message buffer is full".to_string());
+                }
+
+                state.metrics.retries += 1;
+            }
+
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    pub fn metrics(&self) -> SenderMetrics {
+        self.state.lock().unwrap().metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use bigdecimal::BigDecimal;
+    use ringbuf::traits::{Consumer, Split};
+    use ringbuf::HeapRb;
+
+    use super::{LocalMessageSender, LocalQueuePayload, LocalSequencerMessage};
+
+    fn queue(alias: &str) -> LocalSequencerMessage<String> {
+        LocalSequencerMessage::Queue(LocalQueuePayload {
+            sequencer_alias: alias.to_string(),
+            entries: vec![],
+            lanes: vec![],
+            end_beat: BigDecimal::from_str("4.0").unwrap(),
+            one_shot: false,
+            seed: None,
+        })
+    }
+
+    #[test]
+    fn full_buffer_test() {
+        let (producer, mut consumer) = HeapRb::<LocalSequencerMessage<String>>::new(1).split();
+        let sender = LocalMessageSender::new(producer, Duration::from_millis(5));
+
+        assert!(sender.send(queue("one")).is_ok());
+        assert!(sender.send(queue("two")).is_err());
+        assert_eq!(sender.metrics().dropped, 1);
+
+        // Room again after the daemon has read
+        consumer.try_pop().unwrap();
+        assert!(sender.send(queue("three")).is_ok());
+        assert_eq!(sender.metrics().sent, 2);
+    }
+
+    #[test]
+    fn supersedes_test() {
+        assert!(queue("one").supersedes(&queue("one")));
+        assert!(!queue("one").supersedes(&queue("two")));
+        assert!(LocalSequencerMessage::<String>::SetBpm(120).supersedes(&LocalSequencerMessage::SetBpm(90)));
+        assert!(!LocalSequencerMessage::<String>::Reset.supersedes(&queue("one")));
+    }
+}
@@ -1,5 +1,10 @@
 #![feature(result_flattening, proc_macro_hygiene, decl_macro)]
 
+use std::time::Duration;
+
+use bigdecimal::BigDecimal;
+use jdw_sequencer::render;
+
 fn main() {
     let mut args = std::env::args().skip(1).peekable();
     let mut config_path = "config.toml".to_string();
@@ -7,6 +12,18 @@ fn main() {
     while let Some(arg) = args.next() {
         if arg == "-q" || arg == "--quiet" {
             quiet = true;
+        } else if arg == "--protocol" {
+            print!("{}", jdw_sequencer::protocol::reference_markdown());
+            return;
+        } else if arg == "--protocol-spec" {
+            print!("{}", jdw_sequencer::protocol::spec_toml());
+            return;
+        } else if arg == "render" {
+            if let Err(e) = render_session(args.collect()) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+            return;
         } else {
             config_path = arg;
             break;
@@ -15,3 +32,56 @@ fn main() {
 
     jdw_sequencer::run(&config_path, quiet);
 }
+
+/*
+    jdw-sequencer render [--bars 8] [--beats-per-bar 4] [--format json|csv|score] [--out file] [--tick-us 1000] input.session
+
+    The format defaults to the extension of --out (.csv, .osc or .score for an NRT score), otherwise json.
+*/
+fn render_session(args: Vec<String>) -> Result<(), String> {
+    let mut bars: u32 = 8;
+    let mut beats_per_bar: u32 = 4;
+    let mut format: Option<String> = None;
+    let mut out: Option<String> = None;
+    let mut tick_us: u64 = 1000;
+    let mut input: Option<String> = None;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("Missing value for {}", name));
+        match arg.as_str() {
+            "--bars" => bars = value("--bars")?.parse().map_err(|e| format!("Invalid --bars: {}", e))?,
+            "--beats-per-bar" => beats_per_bar = value("--beats-per-bar")?.parse().map_err(|e| format!("Invalid --beats-per-bar: {}", e))?,
+            "--format" => format = Some(value("--format")?),
+            "--out" => out = Some(value("--out")?),
+            "--tick-us" => tick_us = value("--tick-us")?.parse().map_err(|e| format!("Invalid --tick-us: {}", e))?,
+            _ => input = Some(arg),
+        }
+    }
+
+    let input = input.ok_or("Missing session file")?;
+    let format = format.unwrap_or_else(|| match out.as_deref() {
+        Some(path) if path.ends_with(".csv") => "csv".to_string(),
+        Some(path) if path.ends_with(".osc") || path.ends_with(".score") => "score".to_string(),
+        _ => "json".to_string(),
+    });
+
+    let session = render::Session::load(&input)?;
+    let length_beats = BigDecimal::from(bars * beats_per_bar);
+    let events = render::render(&session, &length_beats, Duration::from_micros(tick_us.max(1)))?;
+
+    let bytes = match format.as_str() {
+        "json" => render::to_json(&events).into_bytes(),
+        "csv" => render::to_csv(&events).into_bytes(),
+        "score" => render::to_score(&events, render::beats_to_seconds(&length_beats, session.bpm))?,
+        other => return Err(format!("Unknown format {}, expected json, csv or score", other)),
+    };
+
+    match out {
+        Some(path) => std::fs::write(&path, bytes).map_err(|e| format!("Failed to write {}: {}", path, e)),
+        None => {
+            use std::io::Write;
+            std::io::stdout().write_all(&bytes).map_err(|e| e.to_string())
+        }
+    }
+}
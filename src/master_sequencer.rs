@@ -5,13 +5,17 @@
 
 */
 
-use std::{collections::HashMap, hash::Hash, str::FromStr};
+use std::{collections::HashMap, hash::Hash};
 
 use bigdecimal::BigDecimal;
 use bigdecimal::num_traits::one;
 use log::debug;
 
-use crate::sequencer::{Sequencer, SequencerEntry};
+use crate::automation::AutomationLane;
+use crate::groove::Groove;
+use crate::random::seed_from_name;
+use crate::sequencer::{Sequencer, SequencerEntry, SequencerEvent};
+use crate::ticks::Ticks;
 
 #[derive(Debug, Clone, PartialEq)]
 enum SequencerFinishAction {
@@ -45,12 +49,25 @@ impl<T: Clone> SequencerData<T> {
     }
 }
 
+// Snapshot of a single sequencer, for status queries
+#[derive(Debug, Clone, PartialEq)]
+pub struct SequencerStatus {
+    pub alias: String,
+    pub active: bool,
+    pub current_beat: BigDecimal,
+    pub end_beat: BigDecimal,
+    pub loop_index: u64,
+    pub one_shot: bool,
+}
+
 /* 
     Manages a set of underlying sequencers. 
 */
 pub struct MasterSequencer<T: Clone> {
     active_sequencers: HashMap<String, SequencerData<T>>,
     inactive_sequencers: HashMap<String, SequencerData<T>>,
+    global_groove: Option<Groove>,
+    alias_grooves: HashMap<String, Groove>, // Per-alias grooves, overriding the global one
     pub sequencer_start_mode: SequencerStartMode,
     pub sequencer_reset_mode: SequencerResetMode
 }
@@ -60,18 +77,65 @@ impl<T: Clone> MasterSequencer<T> {
         MasterSequencer { 
             active_sequencers: HashMap::new(),
             inactive_sequencers: HashMap::new(),
+            global_groove: None,
+            alias_grooves: HashMap::new(),
             sequencer_start_mode: start_mode,
             sequencer_reset_mode: reset_mode
         }
     }
 
-    pub fn tick(&mut self, beats: BigDecimal) -> Vec<T> {
+    pub fn tick(&mut self, ticks: Ticks) -> Vec<T> {
+        self.tick_events(ticks).into_iter().map(|event| event.contents).collect()
+    }
+
+    pub fn tick_events(&mut self, ticks: Ticks) -> Vec<SequencerEvent<T>> {
         self.active_sequencers.iter_mut()
-            .flat_map(|seq| seq.1.sequencer.tick(beats.clone()))
+            .flat_map(|(alias, data)| {
+                data.sequencer.tick_events(ticks).into_iter().map(move |mut event| {
+                    event.alias = alias.clone();
+                    event
+                })
+            })
             .collect()
         
     }
 
+    // Set the seed for per-entry randomness of the given sequencer, if it exists
+    pub fn set_seed(&mut self, sequencer_alias: &str, seed: u64) {
+        let existing = self.active_sequencers.get_mut(sequencer_alias).or(
+            self.inactive_sequencers.get_mut(sequencer_alias)
+        );
+        if let Some(data) = existing {
+            data.sequencer.seed = seed;
+        }
+    }
+
+    /*
+        Set (or with None, clear) the groove for a given alias, or the global groove if no alias is given. 
+        Takes effect immediately for running sequencers. 
+    */
+    pub fn set_groove(&mut self, sequencer_alias: Option<&str>, groove: Option<Groove>) {
+        match (sequencer_alias, groove) {
+            (Some(alias), Some(groove)) => { self.alias_grooves.insert(alias.to_string(), groove); },
+            (Some(alias), None) => { self.alias_grooves.remove(alias); },
+            (None, groove) => self.global_groove = groove,
+        }
+
+        let global_groove = &self.global_groove;
+        let alias_grooves = &self.alias_grooves;
+        self.active_sequencers.iter_mut()
+            .chain(self.inactive_sequencers.iter_mut())
+            .filter(|(alias, _)| sequencer_alias.map(|target| target == alias.as_str()).unwrap_or(true))
+            .for_each(|(alias, data)| {
+                let groove = alias_grooves.get(alias).or(global_groove.as_ref()).cloned();
+                data.sequencer.set_groove(groove);
+            });
+    }
+
+    fn groove_for(&self, sequencer_alias: &str) -> Option<Groove> {
+        self.alias_grooves.get(sequencer_alias).or(self.global_groove.as_ref()).cloned()
+    }
+
     pub fn force_wipe(&mut self) {
         self.active_sequencers = HashMap::new();
         self.inactive_sequencers = HashMap::new();
@@ -80,7 +144,7 @@ impl<T: Clone> MasterSequencer<T> {
 
     pub fn force_reset(&mut self) {
         self.active_sequencers.iter_mut()
-        .for_each(|seq| seq.1.sequencer.reset(BigDecimal::from_str("0.0").unwrap()));
+        .for_each(|seq| seq.1.sequencer.reset(0));
     }
 
     // Set all sequencers to wipe after they finish 
@@ -90,6 +154,20 @@ impl<T: Clone> MasterSequencer<T> {
         self.active_sequencers.iter_mut().for_each(|entry| entry.1.finish_action = SequencerFinishAction::Wipe);
     }
 
+    // Set all sequencers not among the given aliases to wipe after they finish, e.g. for songs replacing each other
+    pub fn end_missing_after_finish(&mut self, kept_aliases: &[&str]) {
+        let missing: Vec<&String> = self.active_sequencers.keys()
+            .chain(self.inactive_sequencers.keys())
+            .filter(|alias| !kept_aliases.contains(&alias.as_str()))
+            .collect();
+        debug!("[sequencer] EndAfterFinish on sequencers missing from batch: {:?}", missing);
+
+        self.active_sequencers.iter_mut()
+            .chain(self.inactive_sequencers.iter_mut())
+            .filter(|(alias, _)| !kept_aliases.contains(&alias.as_str()))
+            .for_each(|(_, data)| data.finish_action = SequencerFinishAction::Wipe);
+    }
+
     pub fn reset_check(&mut self) {
 
         /*
@@ -102,7 +180,7 @@ impl<T: Clone> MasterSequencer<T> {
             SequencerResetMode::AllAfterLongestSequenceFinished => {
                 for (alias, data) in &self.active_sequencers {
                     if data.sequencer.is_finished() {
-                        debug!("[sequencer] {} finished (action={:?}, beat={}, end={})", alias, data.finish_action, data.sequencer.current_beat, data.sequencer.end_beat);
+                        debug!("[sequencer] {} finished (action={:?}, tick={}, end={})", alias, data.finish_action, data.sequencer.current_tick(), data.sequencer.end_tick());
                     }
                 }
                 if self.longest_sequence_finished() {
@@ -113,7 +191,7 @@ impl<T: Clone> MasterSequencer<T> {
                     let overshoot = self.get_longest_sequence_overshoot();
                     self.active_sequencers.iter_mut()
                         .filter(|seq| seq.1.sequencer.is_finished())
-                        .for_each(|seq| seq.1.sequencer.reset(overshoot.clone()));
+                        .for_each(|seq| seq.1.sequencer.reset(overshoot));
                 }
             },
             SequencerResetMode::Individual => {
@@ -129,7 +207,7 @@ impl<T: Clone> MasterSequencer<T> {
 
                 let resetting: Vec<String> = self.active_sequencers.iter()
                     .filter(|(_, seq)| seq.sequencer.is_finished())
-                    .map(|(a, seq)| format!("{} (beat={}, end={})", a, seq.sequencer.current_beat, seq.sequencer.end_beat))
+                    .map(|(a, seq)| format!("{} (tick={}, end={})", a, seq.sequencer.current_tick(), seq.sequencer.end_tick()))
                     .collect();
                 if !resetting.is_empty() {
                     debug!("[sequencer] resetting finished looping sequencers: {:?}", resetting);
@@ -150,6 +228,11 @@ impl<T: Clone> MasterSequencer<T> {
         Queue the entries for the given sequencer alias, creating a new inactive sequencer if necessary
     */
     pub fn queue(&mut self, sequencer_alias: &str, entries: Vec<SequencerEntry<T>>, end_beat: BigDecimal, one_shot: bool) {
+        self.queue_with_lanes(sequencer_alias, entries, vec![], end_beat, one_shot);
+    }
+
+    // As queue, with automation lanes for the alias (see automation.rs)
+    pub fn queue_with_lanes(&mut self, sequencer_alias: &str, entries: Vec<SequencerEntry<T>>, lanes: Vec<AutomationLane<T>>, end_beat: BigDecimal, one_shot: bool) {
 
         let existing = self.active_sequencers.get_mut(sequencer_alias).or(
             self.inactive_sequencers.get_mut(sequencer_alias)
@@ -161,12 +244,14 @@ impl<T: Clone> MasterSequencer<T> {
 
         if existing.is_some() {
             existing.map(|seq| {
-                seq.sequencer.queue(entries, end_beat);
+                seq.sequencer.queue_with_lanes(entries, lanes, end_beat);
                 seq.finish_action = finish_action;
             });
         } else {
             let mut new_seq = Sequencer::new();
-            new_seq.queue(entries, end_beat);
+            new_seq.seed = seed_from_name(sequencer_alias);
+            new_seq.set_groove(self.groove_for(sequencer_alias));
+            new_seq.queue_with_lanes(entries, lanes, end_beat);
             let data = SequencerData::new(new_seq, finish_action);
             self.inactive_sequencers.insert(sequencer_alias.to_string(), data);
         }
@@ -176,17 +261,13 @@ impl<T: Clone> MasterSequencer<T> {
 
         // Avoid expensive checks if there is nothing to start
         if !self.inactive_sequencers.is_empty() {
-            let start_mode_ok = match self.sequencer_start_mode {
-                SequencerStartMode::WithLongestSequence => self.longest_sequence_finished() || self.count_started() == 0,
-                SequencerStartMode::WithNearestSequence => self.count_finished() > 0 || self.count_started() == 0,
-                SequencerStartMode::Immediate => true,
-            };
+            let start_mode_ok = self.start_mode_ok();
 
             let start_overshoot = match self.sequencer_start_mode {
                 SequencerStartMode::WithLongestSequence => self.get_longest_sequence_overshoot(),
                 // TODO: Not 100% safe with this, here or in the reset check. Should we grab the most recently finished overshoot?
-                SequencerStartMode::WithNearestSequence => BigDecimal::from_str("0.0").unwrap(),
-                SequencerStartMode::Immediate => BigDecimal::from_str("0.0").unwrap(),
+                SequencerStartMode::WithNearestSequence => 0,
+                SequencerStartMode::Immediate => 0,
             };
 
             debug!("[sequencer] start_check: {} inactive, start_mode_ok={}, overshoot={}", self.inactive_sequencers.len(), start_mode_ok, start_overshoot);
@@ -199,7 +280,7 @@ impl<T: Clone> MasterSequencer<T> {
                     // Avoid other reset-check rules for starting sequencers
                     // Crap - I think offset is important here
                     debug!("TODO: Experimental immediate-start-reset triggered - possible source of overshoot bug");
-                    starting_sequencer.sequencer.reset(start_overshoot.clone());
+                    starting_sequencer.sequencer.reset(start_overshoot);
                     self.active_sequencers.insert(entry.0.to_string(), starting_sequencer);
                 }
                 self.inactive_sequencers.clear();
@@ -208,6 +289,53 @@ impl<T: Clone> MasterSequencer<T> {
 
     }
 
+    /*
+        Ticks until something is due: the next entry or sequence end among running sequencers.
+        Zero if the next start_check or reset_check would start or reset sequencers, None if nothing is due at all. 
+    */
+    pub fn ticks_until_due(&self) -> Option<Ticks> {
+        let start_pending = !self.inactive_sequencers.is_empty() && self.start_mode_ok();
+        let reset_pending = match self.sequencer_reset_mode {
+            SequencerResetMode::AllAfterLongestSequenceFinished => self.count_started() > 0 && self.longest_sequence_finished(),
+            SequencerResetMode::Individual => self.count_finished() > 0,
+        };
+        if start_pending || reset_pending {
+            return Some(0);
+        }
+
+        self.active_sequencers.values()
+            .filter_map(|data| data.sequencer.ticks_until_due())
+            .min()
+    }
+
+    // Status of all sequencers, active and waiting to start, sorted by alias
+    pub fn status(&self) -> Vec<SequencerStatus> {
+        let active = self.active_sequencers.iter().map(|entry| (entry, true));
+        let inactive = self.inactive_sequencers.iter().map(|entry| (entry, false));
+
+        let mut statuses: Vec<SequencerStatus> = active.chain(inactive)
+            .map(|((alias, data), active)| SequencerStatus {
+                alias: alias.clone(),
+                active,
+                current_beat: data.sequencer.current_beat(),
+                end_beat: data.sequencer.end_beat(),
+                loop_index: data.sequencer.loop_index,
+                one_shot: data.finish_action == SequencerFinishAction::Wipe,
+            })
+            .collect();
+
+        statuses.sort_by(|a, b| a.alias.cmp(&b.alias));
+        statuses
+    }
+
+    fn start_mode_ok(&self) -> bool {
+        match self.sequencer_start_mode {
+            SequencerStartMode::WithLongestSequence => self.longest_sequence_finished() || self.count_started() == 0,
+            SequencerStartMode::WithNearestSequence => self.count_finished() > 0 || self.count_started() == 0,
+            SequencerStartMode::Immediate => true,
+        }
+    }
+
     fn count_finished(&self) -> usize{
         self.active_sequencers.iter().filter(|seq| seq.1.sequencer.is_finished()).count()
     }
@@ -218,30 +346,33 @@ impl<T: Clone> MasterSequencer<T> {
 
     fn longest_sequence_finished(&self) -> bool {
         let longest_sequence = self.active_sequencers.iter()
-            .max_by(|seq1, seq2| seq1.1.sequencer.end_beat.cmp(&seq2.1.sequencer.end_beat))
+            .max_by_key(|seq| seq.1.sequencer.end_tick())
             .map(|seq| seq.1);
 
         longest_sequence.map(|seq| seq.sequencer.is_finished()).unwrap_or(true)
 
     }
 
-    fn get_longest_sequence_overshoot(&self) -> BigDecimal {
+    fn get_longest_sequence_overshoot(&self) -> Ticks {
         let longest_sequence = self.active_sequencers.iter()
-            .max_by(|seq1, seq2| seq1.1.sequencer.end_beat.cmp(&seq2.1.sequencer.end_beat))
+            .max_by_key(|seq| seq.1.sequencer.end_tick())
             .map(|seq| seq.1);
 
-        longest_sequence.map(|seq| seq.sequencer.get_overshoot()).unwrap_or(BigDecimal::from_str("0.0").unwrap())
+        longest_sequence.map(|seq| seq.sequencer.get_overshoot()).unwrap_or(0)
 
     }
 
 
 }
 
+#[cfg(test)]
 mod tests {
     use std::str::FromStr;
 
     use bigdecimal::BigDecimal;
 
+    use crate::ticks::{self, Ticks};
+
     use crate::{sequencer::SequencerEntry, master_sequencer::{SequencerStartMode, SequencerResetMode}};
 
     use super::MasterSequencer;
@@ -250,6 +381,10 @@ mod tests {
         BigDecimal::from_str(inp).unwrap()
     }
 
+    fn ticks(inp: &str) -> Ticks {
+        ticks::from_beats(&big(inp))
+    }
+
     // TODO: More start mode tests 
 
     #[test]
@@ -273,21 +408,21 @@ mod tests {
         ms.reset_check();
         assert_eq!(ms.count_finished(), 0);
 
-        ms.tick(big("1.0"));
+        ms.tick(ticks("1.0"));
         ms.start_check();
         ms.reset_check();
         assert_eq!(ms.active_sequencers.len(), 1);
         assert_eq!(ms.inactive_sequencers.len(), 2);
         assert_eq!(ms.count_finished(), 0);
         
-        ms.tick(big("1.9"));
+        ms.tick(ticks("1.9"));
         ms.start_check();
         ms.reset_check();
         assert_eq!(ms.active_sequencers.len(), 1);
         assert_eq!(ms.inactive_sequencers.len(), 2);
         assert_eq!(ms.count_finished(), 0);
     
-        ms.tick(big("0.1"));
+        ms.tick(ticks("0.1"));
         ms.start_check();
         ms.reset_check();
         assert_eq!(ms.active_sequencers.len(), 3);
@@ -328,7 +463,7 @@ mod tests {
         assert_eq!(ms.count_finished(), 2); // Without reset, the queue end beat has not yet become the regular end beat
         ms.reset_check();
         assert_eq!(ms.count_finished(), 0);
-        ms.tick(big("1.0"));
+        ms.tick(ticks("1.0"));
         assert_eq!(ms.count_finished(), 1); 
         ms.reset_check();
         assert_eq!(ms.count_finished(), 0); 
@@ -354,21 +489,21 @@ mod tests {
         assert_eq!(ms.count_finished(), 3); // Without reset, the queue end beat has not yet become the regular end beat
         ms.reset_check();
         assert_eq!(ms.count_finished(), 0);
-        ms.tick(big("1.0"));
+        ms.tick(ticks("1.0"));
         assert_eq!(ms.count_finished(), 1); 
         ms.reset_check();
         assert_eq!(ms.count_finished(), 1); 
-        ms.tick(big("1.0"));
+        ms.tick(ticks("1.0"));
         assert_eq!(ms.count_finished(), 2);
         ms.reset_check();
         assert_eq!(ms.count_finished(), 2);
-        ms.tick(big("1.2")); // Here, the longest finishes with an overshoot of 0.2 
+        ms.tick(ticks("1.2")); // Here, the longest finishes with an overshoot of 0.2 
         assert_eq!(ms.count_finished(), 3);
         ms.reset_check();
         assert_eq!(ms.count_finished(), 0);
 
         for sequence in ms.active_sequencers.iter() {
-            assert_eq!(sequence.1.sequencer.current_beat, big("0.2"));
+            assert_eq!(sequence.1.sequencer.current_beat(), big("0.2"));
         }
         
     }
@@ -376,6 +511,25 @@ mod tests {
 
 
 
+    #[test]
+    fn end_missing_after_finish_test() {
+        let mut ms: MasterSequencer<&str> = MasterSequencer::new(SequencerStartMode::Immediate, SequencerResetMode::Individual);
+        let entries1 = vec![
+            SequencerEntry::new(big("0.0"), "one"),
+        ];
+
+        ms.queue("kept", entries1.clone(), big("1.0"), false);
+        ms.queue("missing", entries1.clone(), big("1.0"), false);
+        ms.start_check();
+        ms.reset_check();
+
+        ms.end_missing_after_finish(&["kept"]);
+        ms.tick(ticks("1.0"));
+        ms.reset_check();
+        assert!(ms.active_sequencers.contains_key("kept"));
+        assert!(!ms.active_sequencers.contains_key("missing"));
+    }
+
     #[test]
     fn create_or_find_queue_test() {
 
@@ -1,40 +1,288 @@
 extern crate rosc;
 
-use std::net::{SocketAddrV4, UdpSocket};
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::{SocketAddr, SocketAddrV4, TcpStream, ToSocketAddrs, UdpSocket};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use log::warn;
 use rosc::{OscPacket};
 use rosc::encoder;
 use crate::config;
+use crate::config::OutputConfig;
+use crate::slip;
 
+const TCP_QUEUE_CAPACITY: usize = 1024;
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+const TCP_WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+const TCP_MIN_BACKOFF: Duration = Duration::from_millis(100);
+const TCP_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+enum Transport {
+    Udp,
+    Tcp(TcpWriter),
+}
+
+/*
+    Sends to a TCP output from a thread of its own, so that a slow or unreachable output never
+        holds up the sequencing thread. Packets are queued up to TCP_QUEUE_CAPACITY and dropped beyond that.
+    The writer connects lazily and, after a failure, waits with a growing backoff before reconnecting.
+        Packets arriving while it waits are dropped rather than sent late.
+*/
+struct TcpWriter {
+    queue: SyncSender<Vec<u8>>,
+    pending: Arc<AtomicUsize>, // Queued and not yet written (or dropped) by the writer
+}
+
+impl TcpWriter {
+    fn start(name: String, addr: SocketAddr) -> TcpWriter {
+        let (queue, packets) = mpsc::sync_channel::<Vec<u8>>(TCP_QUEUE_CAPACITY);
+        let pending = Arc::new(AtomicUsize::new(0));
+        let writer_pending = pending.clone();
+
+        thread::spawn(move || {
+            let mut stream: Option<TcpStream> = None;
+            let mut backoff = TCP_MIN_BACKOFF;
+            let mut retry_at = Instant::now();
+
+            // Ends once the output and with it the queue is dropped
+            for bytes in packets {
+                if stream.is_none() && Instant::now() >= retry_at {
+                    match connect(addr) {
+                        Ok(connected) => {
+                            stream = Some(connected);
+                            backoff = TCP_MIN_BACKOFF;
+                        }
+                        Err(e) => {
+                            warn!("Failed to connect to output {}, retrying in {:?}: {}", name, backoff, e);
+                            retry_at = Instant::now() + backoff;
+                            backoff = (backoff * 2).min(TCP_MAX_BACKOFF);
+                        }
+                    }
+                }
+
+                if let Some(connected) = stream.as_mut() {
+                    if let Err(e) = connected.write_all(&slip::encode(&bytes)) {
+                        warn!("Failed to send to output {}: {}", name, e);
+                        stream = None;
+                        retry_at = Instant::now() + backoff;
+                    }
+                }
+
+                writer_pending.fetch_sub(1, Ordering::AcqRel);
+            }
+        });
+
+        TcpWriter { queue, pending }
+    }
+
+    fn send(&self, name: &str, bytes: Vec<u8>) {
+        self.pending.fetch_add(1, Ordering::AcqRel);
+        if let Err(e) = self.queue.try_send(bytes) {
+            self.pending.fetch_sub(1, Ordering::AcqRel);
+            match e {
+                TrySendError::Full(_) => warn!("Output {} is not keeping up, dropped a packet", name),
+                TrySendError::Disconnected(_) => warn!("Writer for output {} has stopped", name),
+            }
+        }
+    }
+}
+
+fn connect(addr: SocketAddr) -> std::io::Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(&addr, TCP_CONNECT_TIMEOUT)?;
+    stream.set_write_timeout(Some(TCP_WRITE_TIMEOUT))?;
+    let _ = stream.set_nodelay(true);
+    Ok(stream)
+}
+
+pub struct OscOutput {
+    pub name: String,
+    pub nudge_ms: f64,
+    addr: SocketAddr,
+    transport: Transport,
+}
+
+impl OscOutput {
+    fn from_config(output_cfg: &OutputConfig) -> Result<OscOutput, String> {
+        let addr = (output_cfg.host.as_str(), output_cfg.port)
+            .to_socket_addrs()
+            .map_err(|e| format!("Invalid address for output {}: {}", output_cfg.name, e))?
+            .next()
+            .ok_or(format!("No address found for output {}", output_cfg.name))?;
+
+        let transport = match output_cfg.protocol.as_str() {
+            "udp" => Transport::Udp,
+            "tcp" => Transport::Tcp(TcpWriter::start(output_cfg.name.clone(), addr)),
+            other => return Err(format!("Unsupported protocol '{}' for output {}", other, output_cfg.name)),
+        };
+
+        Ok(OscOutput {
+            name: output_cfg.name.clone(),
+            nudge_ms: output_cfg.nudge_ms,
+            addr,
+            transport,
+        })
+    }
+}
+
+/*
+    Sends packets to the configured outputs.
+    Each alias is routed to one or more outputs by name; unrouted aliases go to
+        the output named "default", or the first declared output if there is none by that name.
+*/
 pub struct OSCClient {
     socket: UdpSocket,
-    out_addr: SocketAddrV4,
+    outputs: Vec<OscOutput>,
+    routes: Mutex<HashMap<String, Vec<String>>>,
 }
 
 impl OSCClient {
     pub fn new() -> OSCClient {
         let cfg = config::Config::get();
-        let addr = match SocketAddrV4::from_str(&config::get_addr(cfg.application_out_socket_port)) {
-            Ok(addr) => addr,
+        match OSCClient::from_outputs(&config::get_addr(cfg.application_out_socket_port), &cfg.resolved_outputs()) {
+            Ok(client) => client,
             Err(e) => panic!("{}", e),
-        };
+        }
+    }
 
-        let sock = UdpSocket::bind(addr).unwrap();
+    // Client sending from the given local address to the given outputs, without reading config
+    pub fn from_outputs(bind_addr: &str, output_cfgs: &[OutputConfig]) -> Result<OSCClient, String> {
+        let addr = SocketAddrV4::from_str(bind_addr).map_err(|e| format!("Invalid address {}: {}", bind_addr, e))?;
+        let sock = UdpSocket::bind(addr).map_err(|e| format!("Failed to bind {}: {}", bind_addr, e))?;
 
-        let addr_out = match SocketAddrV4::from_str(&config::get_addr(cfg.application_out_port)) {
-            Ok(addr) => addr,
-            Err(e) => panic!("{}", e),
-        };
+        let outputs = output_cfgs.iter()
+            .map(OscOutput::from_config)
+            .collect::<Result<Vec<OscOutput>, String>>()?;
 
-        OSCClient {
+        Ok(OSCClient {
             socket: sock,
-            out_addr: addr_out
+            outputs,
+            routes: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Err naming the first output that is not configured, if any
+    pub fn check_outputs(&self, output_names: &[String]) -> Result<(), String> {
+        match output_names.iter().find(|name| !self.outputs.iter().any(|o| &o.name == *name)) {
+            Some(unknown) => Err(format!("Unknown output: {}", unknown)),
+            None => Ok(()),
         }
     }
 
+    pub fn set_route(&self, alias: &str, output_names: Vec<String>) -> Result<(), String> {
+        self.check_outputs(&output_names)?;
+        self.routes.lock().unwrap().insert(alias.to_string(), output_names);
+        Ok(())
+    }
+
+    pub fn clear_route(&self, alias: &str) {
+        self.routes.lock().unwrap().remove(alias);
+    }
+
+    pub fn outputs_for(&self, alias: &str) -> Vec<&OscOutput> {
+        match self.routes.lock().unwrap().get(alias) {
+            Some(names) => self.outputs.iter().filter(|o| names.contains(&o.name)).collect(),
+            None => self.default_output().into_iter().collect(),
+        }
+    }
+
+    fn default_output(&self) -> Option<&OscOutput> {
+        self.outputs.iter().find(|o| o.name == "default").or(self.outputs.first())
+    }
+
+    pub fn send_to(&self, output: &OscOutput, packet: &OscPacket) {
+        match encoder::encode(packet) {
+            Ok(bytes) => match &output.transport {
+                Transport::Udp => {
+                    let _ = self.socket.send_to(&bytes, output.addr);
+                }
+                Transport::Tcp(writer) => writer.send(&output.name, bytes),
+            },
+            Err(e) => warn!("Failed to encode packet for {}: {}", output.name, e),
+        }
+    }
+
+    pub fn send_to_all(&self, packet: &OscPacket) {
+        for output in &self.outputs {
+            self.send_to(output, packet);
+        }
+    }
+
+    // Wait up to the timeout for packets queued on TCP outputs to be written, e.g. before exiting
+    pub fn flush(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let is_pending = |output: &OscOutput| match &output.transport {
+            Transport::Tcp(writer) => writer.pending.load(Ordering::Acquire) > 0,
+            Transport::Udp => false,
+        };
+
+        while self.outputs.iter().any(is_pending) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
 
     pub fn send(&self, packet: OscPacket) {
-        let _ = self.socket.send_to(&encoder::encode(&packet).unwrap(), self.out_addr);
+        if let Some(output) = self.default_output() {
+            self.send_to(output, &packet);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::time::{Duration, Instant};
+
+    use rosc::{OscMessage, OscPacket};
+
+    use super::OSCClient;
+    use crate::config::OutputConfig;
+    use crate::slip::SlipDecoder;
+
+    fn tcp_output(name: &str, port: u16) -> OutputConfig {
+        OutputConfig {
+            name: name.to_string(),
+            host: "127.0.0.1".to_string(),
+            port,
+            protocol: "tcp".to_string(),
+            nudge_ms: 0.0,
+        }
+    }
+
+    #[test]
+    fn tcp_output_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Nothing listens on the port of a dropped listener
+        let closed_port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+        let client = OSCClient::from_outputs(
+            "127.0.0.1:0",
+            &[tcp_output("default", port), tcp_output("unreachable", closed_port)],
+        ).unwrap();
+        let packet = OscPacket::Message(OscMessage { addr: "/note_on".to_string(), args: vec![] });
+
+        // Sending never waits on the connection, reachable or not
+        let started = Instant::now();
+        client.send_to_all(&packet);
+        assert!(started.elapsed() < Duration::from_millis(50));
+        client.flush(Duration::from_secs(2));
+
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let mut decoder = SlipDecoder::new();
+        let mut buf = [0u8; 256];
+        let mut frames = vec![];
+        while frames.is_empty() {
+            let read = stream.read(&mut buf).unwrap();
+            frames.extend(decoder.push(&buf[..read]));
+        }
+        assert_eq!(rosc::decoder::decode_udp(&frames[0]).unwrap().1, packet);
     }
 }
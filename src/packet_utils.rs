@@ -0,0 +1,267 @@
+use rosc::{OscMessage, OscPacket, OscType};
+
+use crate::random::SeededRandom;
+use crate::sequencer::SequencerEvent;
+use crate::sequencing_daemon::{Variable, Variables};
+use crate::ticks::TICKS_PER_BEAT;
+use crate::transform::{self, Transforms};
+
+/*
+
+    Helpers for manipulating the contents of outgoing OSC packets.
+
+    JDW messages carry most of their arguments as named pairs, e.g.
+        /note_on_timed "gentle" "gentle_180" 3.2 "freq" 180.0 "relT" 0.5
+    where the value of "freq" is the argument directly following the string "freq".
+
+    String args of the form $name are placeholders, replaced when the packet is sent:
+        $loop   - the loop index of the sequence (int)
+        $beat   - the beat of the event within its sequence (float)
+        $alias  - the alias (string)
+        $<name> - the variable of that name set for the alias with /set_var
+    Placeholders without a value are sent as they are.
+
+*/
+
+// Find the value index of a named arg, i.e. the index after the matching name string
+fn named_arg_index(msg: &OscMessage, name: &str) -> Option<usize> {
+    msg.args
+        .iter()
+        .position(|arg| match arg {
+            OscType::String(val) => val == name,
+            _ => false,
+        })
+        .map(|pos| pos + 1)
+        .filter(|pos| *pos < msg.args.len())
+}
+
+pub fn get_named_arg(msg: &OscMessage, name: &str) -> Option<OscType> {
+    named_arg_index(msg, name).map(|index| msg.args[index].clone())
+}
+
+/*
+    Set the value of a named arg in the packet.
+    A plain message that does not yet carry the arg gets it appended as a new name/value pair.
+    Bundles are searched recursively, but only messages already carrying the arg are changed,
+        so that info messages such as /bundle_info are left alone.
+*/
+pub fn set_named_arg(packet: &OscPacket, name: &str, value: OscType) -> OscPacket {
+    replace_named_arg(packet, name, value, true)
+}
+
+fn replace_named_arg(packet: &OscPacket, name: &str, value: OscType, append_missing: bool) -> OscPacket {
+    match packet {
+        OscPacket::Message(msg) => {
+            let mut new_msg = msg.clone();
+            match named_arg_index(msg, name) {
+                Some(index) => new_msg.args[index] = value,
+                None => {
+                    if append_missing {
+                        new_msg.args.push(OscType::String(name.to_string()));
+                        new_msg.args.push(value);
+                    }
+                }
+            }
+            OscPacket::Message(new_msg)
+        }
+        OscPacket::Bundle(bun) => {
+            let mut new_bun = bun.clone();
+            new_bun.content = bun
+                .content
+                .iter()
+                .map(|pct| replace_named_arg(pct, name, value.clone(), false))
+                .collect();
+            OscPacket::Bundle(new_bun)
+        }
+    }
+}
+
+/*
+    Offset numeric named args by a random amount within +/- their given range.
+    The roll decides the offsets, so the same roll always produces the same values.
+*/
+pub fn randomize_args(packet: &OscPacket, ranges: &[(String, f32)], roll: u64) -> OscPacket {
+    let mut rng = SeededRandom::new(roll);
+    ranges.iter().fold(packet.clone(), |pct, (name, range)| {
+        let offset = (rng.next_f32() * 2.0 - 1.0) * range;
+        map_named_arg(&pct, name, &|value| match value {
+            OscType::Float(val) => OscType::Float(val + offset),
+            OscType::Double(val) => OscType::Double(val + offset as f64),
+            OscType::Int(val) => OscType::Int(val + offset.round() as i32),
+            other => other.clone(),
+        })
+    })
+}
+
+/*
+    The packet to send for an event: its contents with placeholders substituted, args randomized,
+        the transforms of its alias applied and any automation value appended.
+*/
+pub fn event_packet(event: &SequencerEvent<OscPacket>, variables: Option<&Variables>, transforms: Option<&Transforms>) -> OscPacket {
+    let packet = substitute_placeholders(&event.contents, event, variables);
+    let packet = randomize_args(&packet, &event.arg_randomization, event.roll);
+    let packet = match transforms {
+        Some(transforms) => transform::apply_all(transforms, &packet),
+        None => packet,
+    };
+    match event.value {
+        Some(value) => append_arg(&packet, OscType::Float(value)),
+        None => packet,
+    }
+}
+
+pub fn substitute_placeholders(packet: &OscPacket, event: &SequencerEvent<OscPacket>, variables: Option<&Variables>) -> OscPacket {
+    match packet {
+        OscPacket::Message(msg) => {
+            let mut new_msg = msg.clone();
+            for arg in new_msg.args.iter_mut() {
+                let value = match arg {
+                    OscType::String(text) => text.strip_prefix('$').and_then(|name| placeholder_value(name, event, variables)),
+                    _ => None,
+                };
+                if let Some(value) = value {
+                    *arg = value;
+                }
+            }
+            OscPacket::Message(new_msg)
+        }
+        OscPacket::Bundle(bun) => {
+            let mut new_bun = bun.clone();
+            new_bun.content = bun
+                .content
+                .iter()
+                .map(|pct| substitute_placeholders(pct, event, variables))
+                .collect();
+            OscPacket::Bundle(new_bun)
+        }
+    }
+}
+
+fn placeholder_value(name: &str, event: &SequencerEvent<OscPacket>, variables: Option<&Variables>) -> Option<OscType> {
+    match name {
+        "loop" => Some(OscType::Int(event.loop_index.min(i32::MAX as u64) as i32)),
+        "beat" => Some(OscType::Float(event.trigger_tick as f32 / TICKS_PER_BEAT as f32)),
+        "alias" => Some(OscType::String(event.alias.clone())),
+        _ => variables.and_then(|vars| vars.get(name)).map(|value| match value {
+            Variable::Int(val) => OscType::Int(*val),
+            Variable::Float(val) => OscType::Float(*val),
+            Variable::Text(val) => OscType::String(val.clone()),
+        }),
+    }
+}
+
+// Add an arg to the end of the message, or of every message in a bundle
+pub fn append_arg(packet: &OscPacket, arg: OscType) -> OscPacket {
+    match packet {
+        OscPacket::Message(msg) => {
+            let mut new_msg = msg.clone();
+            new_msg.args.push(arg);
+            OscPacket::Message(new_msg)
+        }
+        OscPacket::Bundle(bun) => {
+            let mut new_bun = bun.clone();
+            new_bun.content = bun
+                .content
+                .iter()
+                .map(|pct| append_arg(pct, arg.clone()))
+                .collect();
+            OscPacket::Bundle(new_bun)
+        }
+    }
+}
+
+// Replace the value of a named arg wherever it exists in the packet, based on its current value
+pub fn map_named_arg(packet: &OscPacket, name: &str, operation: &dyn Fn(&OscType) -> OscType) -> OscPacket {
+    match packet {
+        OscPacket::Message(msg) => {
+            let mut new_msg = msg.clone();
+            if let Some(index) = named_arg_index(msg, name) {
+                new_msg.args[index] = operation(&msg.args[index]);
+            }
+            OscPacket::Message(new_msg)
+        }
+        OscPacket::Bundle(bun) => {
+            let mut new_bun = bun.clone();
+            new_bun.content = bun
+                .content
+                .iter()
+                .map(|pct| map_named_arg(pct, name, operation))
+                .collect();
+            OscPacket::Bundle(new_bun)
+        }
+    }
+}
+
+// Rewrite message addresses wherever the operation returns a new one
+pub fn map_address(packet: &OscPacket, operation: &dyn Fn(&str) -> Option<String>) -> OscPacket {
+    match packet {
+        OscPacket::Message(msg) => {
+            let mut new_msg = msg.clone();
+            if let Some(addr) = operation(&msg.addr) {
+                new_msg.addr = addr;
+            }
+            OscPacket::Message(new_msg)
+        }
+        OscPacket::Bundle(bun) => {
+            let mut new_bun = bun.clone();
+            new_bun.content = bun
+                .content
+                .iter()
+                .map(|pct| map_address(pct, operation))
+                .collect();
+            OscPacket::Bundle(new_bun)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rosc::{OscMessage, OscPacket, OscType};
+
+    use super::event_packet;
+    use crate::sequencer::SequencerEvent;
+    use crate::sequencing_daemon::Variable;
+    use crate::ticks::TICKS_PER_BEAT;
+
+    #[test]
+    fn placeholders_test() {
+        let contents = OscPacket::Message(OscMessage {
+            addr: "/note_on".to_string(),
+            args: vec![
+                OscType::String("$alias".to_string()),
+                OscType::String("$loop".to_string()),
+                OscType::String("$beat".to_string()),
+                OscType::String("transpose".to_string()),
+                OscType::String("$transpose".to_string()),
+                OscType::String("$unknown".to_string()),
+            ],
+        });
+        let event = SequencerEvent {
+            alias: "bass".to_string(),
+            entry_index: 0,
+            trigger_tick: TICKS_PER_BEAT * 3 / 2,
+            late_ticks: 0,
+            contents,
+            loop_index: 4,
+            roll: 0,
+            arg_randomization: vec![],
+            value: None,
+        };
+        let variables = HashMap::from([("transpose".to_string(), Variable::Int(-12))]);
+
+        let expected = vec![
+            OscType::String("bass".to_string()),
+            OscType::Int(4),
+            OscType::Float(1.5),
+            OscType::String("transpose".to_string()),
+            OscType::Int(-12),
+            OscType::String("$unknown".to_string()),
+        ];
+        match event_packet(&event, Some(&variables), None) {
+            OscPacket::Message(msg) => assert_eq!(msg.args, expected),
+            other => panic!("Expected a message, got {:?}", other),
+        }
+    }
+}
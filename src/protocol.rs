@@ -0,0 +1,650 @@
+use rosc::{OscBundle, OscMessage, OscPacket, OscType};
+use serde::Serialize;
+
+use crate::local_messaging::SenderMetrics;
+use crate::sequencing_daemon::DaemonStatus;
+use crate::stats::{DaemonStats, BUCKET_BOUNDS_US};
+
+/*
+
+    Machine-readable description of the wire protocol: every control address and bundle tag,
+        with argument names and types. Incoming packets are validated against it before parsing,
+        so that clients get precise errors (which argument, what was expected).
+
+    Bump the minor version for additions and the major version for anything that breaks clients.
+
+*/
+
+pub const PROTOCOL_VERSION: &str = "1.7";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArgType {
+    Int,
+    #[serde(rename = "positive_int")]
+    PositiveInt,
+    Number, // Int, float or double
+    String,
+    Beats, // Decimal string such as "0.25" (exact) or a number
+    Any,
+}
+
+impl ArgType {
+    pub fn accepts(&self, arg: &OscType) -> bool {
+        match self {
+            ArgType::Int => matches!(arg, OscType::Int(_)),
+            ArgType::PositiveInt => matches!(arg, OscType::Int(val) if *val > 0),
+            ArgType::Number => matches!(arg, OscType::Int(_) | OscType::Float(_) | OscType::Double(_)),
+            ArgType::String => matches!(arg, OscType::String(_)),
+            ArgType::Beats => {
+                matches!(arg, OscType::String(_) | OscType::Int(_) | OscType::Float(_) | OscType::Double(_))
+            }
+            ArgType::Any => true,
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            ArgType::Int => "int",
+            ArgType::PositiveInt => "positive int",
+            ArgType::Number => "number",
+            ArgType::String => "string",
+            ArgType::Beats => "beats (decimal string or number)",
+            ArgType::Any => "any",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ArgSpec {
+    pub name: &'static str,
+    #[serde(rename = "type")]
+    pub arg_type: ArgType,
+    pub optional: bool,
+    pub repeated: bool, // Zero or more trailing args of this type; always last
+}
+
+const fn arg(name: &'static str, arg_type: ArgType) -> ArgSpec {
+    ArgSpec { name, arg_type, optional: false, repeated: false }
+}
+
+const fn optional(name: &'static str, arg_type: ArgType) -> ArgSpec {
+    ArgSpec { name, arg_type, optional: true, repeated: false }
+}
+
+const fn repeated(name: &'static str, arg_type: ArgType) -> ArgSpec {
+    ArgSpec { name, arg_type, optional: true, repeated: true }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageSpec {
+    pub address: &'static str,
+    pub description: &'static str,
+    pub args: &'static [ArgSpec],
+}
+
+impl MessageSpec {
+    pub fn validate(&self, msg: &OscMessage) -> Result<(), String> {
+        let mut index = 0;
+
+        for spec in self.args {
+            if spec.repeated {
+                for (offset, arg) in msg.args[index.min(msg.args.len())..].iter().enumerate() {
+                    check_arg(self.address, index + offset, spec, arg)?;
+                }
+                return Ok(());
+            }
+
+            match msg.args.get(index) {
+                Some(arg) => check_arg(self.address, index, spec, arg)?,
+                None if spec.optional => return Ok(()),
+                None => {
+                    return Err(format!(
+                        "{}: missing argument {} ({}), expected {}",
+                        self.address, index, spec.name, spec.arg_type.describe()
+                    ))
+                }
+            }
+            index += 1;
+        }
+
+        if msg.args.len() > index {
+            return Err(format!(
+                "{}: unexpected argument {} ({:?}), takes at most {}",
+                self.address, index, msg.args[index], index
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn check_arg(address: &str, index: usize, spec: &ArgSpec, arg: &OscType) -> Result<(), String> {
+    if spec.arg_type.accepts(arg) {
+        Ok(())
+    } else {
+        Err(format!(
+            "{}: argument {} ({}) should be {}, got {:?}",
+            address, index, spec.name, spec.arg_type.describe(), arg
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BundleSpec {
+    pub tag: &'static str,
+    pub description: &'static str,
+    pub info_address: &'static str,
+    pub layout: &'static [&'static str], // Contents after /bundle_info, for documentation
+    pub payload_indexes: &'static [usize], // Content indexes holding client packets, which are not validated
+}
+
+pub static MESSAGES: &[MessageSpec] = &[
+    MessageSpec {
+        address: "/protocol_version",
+        description: "Query the protocol version. Replied to with /protocol_version version.",
+        args: &[],
+    },
+    MessageSpec {
+        address: "/get_state",
+        description: "Query the daemon state. Replied to with /state bpm running, followed by \
+            alias active current_beat end_beat loop_index one_shot for each sequencer (beats as decimal strings).",
+        args: &[],
+    },
+    MessageSpec {
+        address: "/stats",
+        description: "Query timing statistics. Replied to with /stats followed by name value pairs: event lateness, \
+            tick duration and their histogram buckets in microseconds, tick overruns, ring buffer depth and control message counts.",
+        args: &[],
+    },
+    MessageSpec {
+        address: "/set_bpm",
+        description: "Set the tempo.",
+        args: &[arg("bpm", ArgType::PositiveInt)],
+    },
+    MessageSpec {
+        address: "/reset_all",
+        description: "Restart all sequencers from the beginning.",
+        args: &[],
+    },
+    MessageSpec {
+        address: "/hard_stop",
+        description: "Stop and remove all sequencers immediately.",
+        args: &[],
+    },
+    MessageSpec {
+        address: "/wipe_on_finish",
+        description: "Remove all sequencers once they finish their current loop.",
+        args: &[],
+    },
+    MessageSpec {
+        address: "/shutdown",
+        description: "Stop the daemon, sending the configured shutdown messages to every output.",
+        args: &[],
+    },
+    MessageSpec {
+        address: "/route",
+        description: "Send an alias to one or more of the configured outputs.",
+        args: &[arg("alias", ArgType::String), arg("output", ArgType::String), repeated("outputs", ArgType::String)],
+    },
+    MessageSpec {
+        address: "/clear_route",
+        description: "Send an alias back to the default output.",
+        args: &[arg("alias", ArgType::String)],
+    },
+    MessageSpec {
+        address: "/set_nudge",
+        description: "Shift the send time of an alias, in milliseconds. Positive values delay.",
+        args: &[arg("alias", ArgType::String), arg("milliseconds", ArgType::Number)],
+    },
+    MessageSpec {
+        address: "/set_nudge_beats",
+        description: "Shift the send time of an alias, in beats. Positive values delay.",
+        args: &[arg("alias", ArgType::String), arg("beats", ArgType::Beats)],
+    },
+    MessageSpec {
+        address: "/clear_nudge",
+        description: "Remove the nudge of an alias.",
+        args: &[arg("alias", ArgType::String)],
+    },
+    MessageSpec {
+        address: "/set_var",
+        description: "Set a variable of an alias, sent in place of $name placeholders in its packets.",
+        args: &[arg("alias", ArgType::String), arg("name", ArgType::String), arg("value", ArgType::Any)],
+    },
+    MessageSpec {
+        address: "/clear_var",
+        description: "Remove a variable of an alias.",
+        args: &[arg("alias", ArgType::String), arg("name", ArgType::String)],
+    },
+    MessageSpec {
+        address: "/transform",
+        description: "Add a transform to the packets of an alias: transpose semitones, offset arg amount, scale arg factor or prefix from to.",
+        args: &[
+            arg("alias", ArgType::String),
+            arg("kind", ArgType::String),
+            arg("value", ArgType::Any),
+            repeated("values", ArgType::Any),
+        ],
+    },
+    MessageSpec {
+        address: "/clear_transforms",
+        description: "Remove all transforms of an alias.",
+        args: &[arg("alias", ArgType::String)],
+    },
+    MessageSpec {
+        address: "/set_swing",
+        description: "MPC-style swing for an alias (\"*\" for global). 50 is straight.",
+        args: &[
+            arg("alias", ArgType::String),
+            arg("percent", ArgType::Number),
+            optional("resolution", ArgType::Beats),
+        ],
+    },
+    MessageSpec {
+        address: "/set_groove",
+        description: "Per-step timing offsets for an alias (\"*\" for global).",
+        args: &[
+            arg("alias", ArgType::String),
+            arg("resolution", ArgType::Beats),
+            arg("offset", ArgType::Beats),
+            repeated("offsets", ArgType::Beats),
+        ],
+    },
+    MessageSpec {
+        address: "/clear_groove",
+        description: "Remove the groove of an alias (\"*\" for global).",
+        args: &[arg("alias", ArgType::String)],
+    },
+    MessageSpec {
+        address: "/batch_update_queues_info",
+        description: "Header of a batch_update_queues bundle. stop_missing 1 ends aliases not in the batch.",
+        args: &[arg("stop_missing", ArgType::Int)],
+    },
+    MessageSpec {
+        address: "/update_queue_info",
+        description: "Header of an update_queue bundle.",
+        args: &[
+            arg("alias", ArgType::String),
+            optional("one_shot", ArgType::Int),
+            optional("seed", ArgType::Int),
+        ],
+    },
+    MessageSpec {
+        address: "/timed_msg_info",
+        description: "Header of a timed_msg bundle; time is the beats until the next entry.",
+        args: &[arg("time", ArgType::Beats)],
+    },
+    MessageSpec {
+        address: "/entry_attributes",
+        description: "Name/value pairs: prob, every, every_offset, skip_first, first_only, humanize, rand_<arg>.",
+        args: &[repeated("attributes", ArgType::Any)],
+    },
+    MessageSpec {
+        address: "/route_info",
+        description: "Outputs to route a queued alias to.",
+        args: &[arg("output", ArgType::String), repeated("outputs", ArgType::String)],
+    },
+    MessageSpec {
+        address: "/automation_info",
+        description: "Header of an automation bundle; the lane is sampled every rate_beats.",
+        args: &[arg("rate_beats", ArgType::Beats)],
+    },
+    MessageSpec {
+        address: "/breakpoints",
+        description: "Automation through beat/value breakpoints, linear in between.",
+        args: &[arg("beat", ArgType::Beats), arg("value", ArgType::Number), repeated("points", ArgType::Any)],
+    },
+    MessageSpec {
+        address: "/lfo",
+        description: "Automation by LFO (waveform: sine, triangle, saw, square), phase in cycles.",
+        args: &[
+            arg("waveform", ArgType::String),
+            arg("period_beats", ArgType::Beats),
+            arg("min", ArgType::Number),
+            arg("max", ArgType::Number),
+            optional("phase", ArgType::Number),
+        ],
+    },
+    MessageSpec {
+        address: "/generate_queue_info",
+        description: "Header of a generate_queue bundle. The seed is used by random arpeggios.",
+        args: &[
+            arg("alias", ArgType::String),
+            optional("one_shot", ArgType::Int),
+            optional("seed", ArgType::Int),
+        ],
+    },
+    MessageSpec {
+        address: "/euclid",
+        description: "Euclidean rhythm generator.",
+        args: &[
+            arg("hits", ArgType::Int),
+            arg("steps", ArgType::Int),
+            arg("rotation", ArgType::Int),
+            arg("step_beats", ArgType::Beats),
+        ],
+    },
+    MessageSpec {
+        address: "/polyrhythm",
+        description: "Polyrhythm generator, one division per packet.",
+        args: &[arg("length_beats", ArgType::Beats), repeated("divisions", ArgType::Int)],
+    },
+    MessageSpec {
+        address: "/random_density",
+        description: "Seeded random pattern generator.",
+        args: &[
+            arg("steps", ArgType::Int),
+            arg("step_beats", ArgType::Beats),
+            arg("density", ArgType::Number),
+            optional("seed", ArgType::Int),
+        ],
+    },
+    MessageSpec {
+        address: "/arpeggio",
+        description: "Arpeggio generator (mode: up, down, updown, played, random).",
+        args: &[
+            arg("mode", ArgType::String),
+            arg("steps", ArgType::Int),
+            arg("step_beats", ArgType::Beats),
+            arg("arg_name", ArgType::String),
+            repeated("notes", ArgType::Number),
+        ],
+    },
+    MessageSpec {
+        address: "/request_info",
+        description: "Header of a request bundle; the id is echoed in /ack id or /error id reason.",
+        args: &[arg("id", ArgType::Any)],
+    },
+];
+
+pub static BUNDLES: &[BundleSpec] = &[
+    BundleSpec {
+        tag: "batch_update_queues",
+        description: "Replace several queues at once, applied on a single tick.",
+        info_address: "/batch_update_queues_info",
+        layout: &["/batch_update_queues_info", "bundle of update_queue bundles"],
+        payload_indexes: &[],
+    },
+    BundleSpec {
+        tag: "update_queue",
+        description: "Replace the queue of an alias.",
+        info_address: "/update_queue_info",
+        layout: &[
+            "/update_queue_info",
+            "bundle of timed_msg bundles",
+            "(optional) /route_info",
+            "(optional) bundle of automation bundles",
+        ],
+        payload_indexes: &[],
+    },
+    BundleSpec {
+        tag: "automation",
+        description: "An automation lane, looping with the queue of its alias.",
+        info_address: "/automation_info",
+        layout: &["/automation_info", "shape message: /breakpoints or /lfo", "packet to send with each sampled value appended"],
+        payload_indexes: &[3],
+    },
+    BundleSpec {
+        tag: "timed_msg",
+        description: "A packet and the beats until the next one.",
+        info_address: "/timed_msg_info",
+        layout: &["/timed_msg_info", "packet to play", "(optional) /entry_attributes"],
+        payload_indexes: &[2],
+    },
+    BundleSpec {
+        tag: "generate_queue",
+        description: "Build the queue of an alias with a generator.",
+        info_address: "/generate_queue_info",
+        layout: &[
+            "/generate_queue_info",
+            "generator message: /euclid, /polyrhythm, /random_density or /arpeggio",
+            "bundle of packets to play",
+            "(optional) /route_info",
+        ],
+        payload_indexes: &[3],
+    },
+    BundleSpec {
+        tag: "request",
+        description: "Wraps any command to get an /ack or /error reply.",
+        info_address: "/request_info",
+        layout: &["/request_info", "command (message or bundle)"],
+        payload_indexes: &[2],
+    },
+];
+
+pub fn message_spec(address: &str) -> Option<&'static MessageSpec> {
+    MESSAGES.iter().find(|spec| spec.address == address)
+}
+
+pub fn bundle_spec(tag: &str) -> Option<&'static BundleSpec> {
+    BUNDLES.iter().find(|spec| spec.tag == tag)
+}
+
+pub fn validate_message(msg: &OscMessage) -> Result<(), String> {
+    message_spec(&msg.addr)
+        .ok_or(format!("Unknown address {}", msg.addr))?
+        .validate(msg)
+}
+
+// Validate a tagged bundle and everything in it except client packets. Untagged bundles are checked content by content.
+pub fn validate_bundle(bundle: &OscBundle) -> Result<(), String> {
+    let tag = match bundle.content.first() {
+        Some(OscPacket::Message(msg)) if msg.addr == "/bundle_info" => match msg.args.first() {
+            Some(OscType::String(tag)) => tag.clone(),
+            other => return Err(format!("/bundle_info: argument 0 (tag) should be string, got {:?}", other)),
+        },
+        _ => {
+            return bundle.content.iter().try_for_each(validate_packet);
+        }
+    };
+
+    let spec = bundle_spec(&tag).ok_or(format!("Unknown bundle tag {}", tag))?;
+
+    match bundle.content.get(1) {
+        Some(OscPacket::Message(msg)) if msg.addr == spec.info_address => {}
+        _ => return Err(format!("{} bundle: expected {} after /bundle_info", tag, spec.info_address)),
+    }
+
+    bundle
+        .content
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter(|(index, _)| !spec.payload_indexes.contains(index))
+        .try_for_each(|(_, packet)| validate_packet(packet))
+        .map_err(|e| format!("{} bundle: {}", tag, e))
+}
+
+fn validate_packet(packet: &OscPacket) -> Result<(), String> {
+    match packet {
+        OscPacket::Message(msg) => validate_message(msg),
+        OscPacket::Bundle(bundle) => validate_bundle(bundle),
+    }
+}
+
+pub fn version_message() -> OscPacket {
+    OscPacket::Message(OscMessage {
+        addr: "/protocol_version".to_string(),
+        args: vec![OscType::String(PROTOCOL_VERSION.to_string())],
+    })
+}
+
+pub fn state_message(status: &DaemonStatus) -> OscPacket {
+    let mut args = vec![OscType::Int(status.bpm), OscType::Bool(status.is_running())];
+    for seq in &status.sequencers {
+        args.extend(vec![
+            OscType::String(seq.alias.clone()),
+            OscType::Bool(seq.active),
+            OscType::String(seq.current_beat.to_string()),
+            OscType::String(seq.end_beat.to_string()),
+            OscType::Long(seq.loop_index as i64),
+            OscType::Bool(seq.one_shot),
+        ]);
+    }
+
+    OscPacket::Message(OscMessage {
+        addr: "/state".to_string(),
+        args,
+    })
+}
+
+// Name value pairs, so that clients can pick what they know and skip the rest
+pub fn stats_message(stats: &DaemonStats, sender: &SenderMetrics) -> OscPacket {
+    let mut args = vec![];
+    let mut pair = |name: String, value: OscType| {
+        args.push(OscType::String(name));
+        args.push(value);
+    };
+
+    for (prefix, histogram) in [("late", &stats.lateness), ("tick", &stats.tick_duration)] {
+        pair(format!("{}_count", prefix), OscType::Long(histogram.count as i64));
+        pair(format!("{}_mean_us", prefix), OscType::Double(histogram.mean_us()));
+        pair(format!("{}_max_us", prefix), OscType::Long(histogram.max_us as i64));
+        for (index, count) in histogram.buckets.iter().enumerate() {
+            let bound = BUCKET_BOUNDS_US.get(index).map(|us| us.to_string()).unwrap_or_else(|| "inf".to_string());
+            pair(format!("{}_le_{}", prefix, bound), OscType::Long(*count as i64));
+        }
+    }
+
+    pair("overruns".to_string(), OscType::Long(stats.overruns as i64));
+    pair("queue_depth".to_string(), OscType::Long(stats.queue_depth as i64));
+    pair("queue_depth_max".to_string(), OscType::Long(stats.max_queue_depth as i64));
+    pair("messages_sent".to_string(), OscType::Long(sender.sent as i64));
+    pair("messages_coalesced".to_string(), OscType::Long(sender.coalesced as i64));
+    pair("messages_dropped".to_string(), OscType::Long(sender.dropped as i64));
+    pair("message_retries".to_string(), OscType::Long(sender.retries as i64));
+
+    OscPacket::Message(OscMessage {
+        addr: "/stats".to_string(),
+        args,
+    })
+}
+
+#[derive(Serialize)]
+struct ProtocolDescription {
+    version: &'static str,
+    messages: &'static [MessageSpec],
+    bundles: &'static [BundleSpec],
+}
+
+// The whole description as TOML, for generating client code
+pub fn spec_toml() -> String {
+    toml::to_string(&ProtocolDescription {
+        version: PROTOCOL_VERSION,
+        messages: MESSAGES,
+        bundles: BUNDLES,
+    })
+    .unwrap_or_else(|e| format!("# Failed to serialize protocol: {}", e))
+}
+
+// Human-readable reference, generated from the same description
+pub fn reference_markdown() -> String {
+    let mut doc = format!("# jdw-sequencer protocol {}\n\n## Messages\n\n", PROTOCOL_VERSION);
+
+    for spec in MESSAGES {
+        let args: Vec<String> = spec
+            .args
+            .iter()
+            .map(|arg| {
+                let text = format!("{}: {}", arg.name, arg.arg_type.describe());
+                if arg.repeated {
+                    format!("[{}...]", text)
+                } else if arg.optional {
+                    format!("[{}]", text)
+                } else {
+                    text
+                }
+            })
+            .collect();
+        let signature = if args.is_empty() {
+            spec.address.to_string()
+        } else {
+            format!("{} {}", spec.address, args.join(", "))
+        };
+        doc += &format!("- `{}` — {}\n", signature, spec.description);
+    }
+
+    doc += "\n## Bundles\n\nEach bundle starts with `/bundle_info tag`, followed by:\n\n";
+    for spec in BUNDLES {
+        doc += &format!("- `{}` — {}\n", spec.tag, spec.description);
+        for (index, part) in spec.layout.iter().enumerate() {
+            doc += &format!("  {}. {}\n", index + 1, part);
+        }
+    }
+
+    doc
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use rosc::{OscBundle, OscMessage, OscPacket, OscTime, OscType};
+
+    use super::{message_spec, validate_bundle, validate_message};
+    use crate::generators::ArpMode;
+
+    fn msg(addr: &str, args: Vec<OscType>) -> OscMessage {
+        OscMessage { addr: addr.to_string(), args }
+    }
+
+    #[test]
+    fn message_validation_test() {
+        assert!(validate_message(&msg("/set_bpm", vec![OscType::Int(120)])).is_ok());
+        assert_eq!(
+            validate_message(&msg("/set_bpm", vec![OscType::String("fast".to_string())])).unwrap_err(),
+            "/set_bpm: argument 0 (bpm) should be positive int, got String(\"fast\")"
+        );
+        assert!(validate_message(&msg("/set_bpm", vec![OscType::Int(0)])).is_err());
+        assert_eq!(
+            validate_message(&msg("/set_nudge", vec![OscType::String("drums".to_string())])).unwrap_err(),
+            "/set_nudge: missing argument 1 (milliseconds), expected number"
+        );
+        assert!(validate_message(&msg("/hard_stop", vec![OscType::Int(1)])).is_err());
+        assert!(validate_message(&msg("/route", vec![
+            OscType::String("drums".to_string()),
+            OscType::String("a".to_string()),
+            OscType::String("b".to_string()),
+        ])).is_ok());
+    }
+
+    #[test]
+    fn arpeggio_modes_test() {
+        // Every mode the spec documents is one the generator accepts
+        let description = message_spec("/arpeggio").unwrap().description;
+        let modes = description.split("(mode: ").nth(1).unwrap().trim_end_matches(").");
+        for mode in modes.split(", ") {
+            assert!(ArpMode::from_str(mode).is_ok(), "{} does not parse", mode);
+        }
+    }
+
+    #[test]
+    fn bundle_validation_test() {
+        let timed_msg = OscPacket::Bundle(OscBundle {
+            timetag: OscTime { seconds: 0, fractional: 1 },
+            content: vec![
+                OscPacket::Message(msg("/bundle_info", vec![OscType::String("timed_msg".to_string())])),
+                OscPacket::Message(msg("/timed_msg_info", vec![OscType::String("0.5".to_string())])),
+                // Client packets are not validated
+                OscPacket::Message(msg("/note_on", vec![OscType::Int(1)])),
+            ],
+        });
+        let update_queue = |alias: OscType| OscBundle {
+            timetag: OscTime { seconds: 0, fractional: 1 },
+            content: vec![
+                OscPacket::Message(msg("/bundle_info", vec![OscType::String("update_queue".to_string())])),
+                OscPacket::Message(msg("/update_queue_info", vec![alias, OscType::Int(0)])),
+                OscPacket::Bundle(OscBundle {
+                    timetag: OscTime { seconds: 0, fractional: 1 },
+                    content: vec![timed_msg.clone()],
+                }),
+            ],
+        };
+
+        assert!(validate_bundle(&update_queue(OscType::String("drums".to_string()))).is_ok());
+        assert_eq!(
+            validate_bundle(&update_queue(OscType::Int(1))).unwrap_err(),
+            "update_queue bundle: /update_queue_info: argument 0 (alias) should be string, got Int(1)"
+        );
+    }
+}
@@ -0,0 +1,69 @@
+/*
+
+    Small deterministic random source (SplitMix64).
+
+    Used wherever the sequencer needs "random" behaviour that must be reproducible from a seed,
+        e.g. generated density patterns. No external crate needed for something this simple.
+
+*/
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Seeds handed out by fresh_seed, so that calls within the same clock reading still differ
+static FRESH_SEEDS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone)]
+pub struct SeededRandom {
+    state: u64,
+}
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> SeededRandom {
+        SeededRandom { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // Uniform value in [0.0, 1.0)
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    // Uniform index in [0, upper)
+    pub fn next_index(&mut self, upper: usize) -> usize {
+        if upper == 0 {
+            return 0;
+        }
+        (self.next_u64() % upper as u64) as usize
+    }
+}
+
+// Combine several values (e.g. seed, loop and entry index) into one well-mixed seed
+pub fn combine_seed(parts: &[u64]) -> u64 {
+    parts.iter().fold(0xCBF2_9CE4_8422_2325, |acc, part| {
+        SeededRandom::new(acc ^ part).next_u64()
+    })
+}
+
+// Stable seed for a name, e.g. a sequencer alias (FNV-1a)
+pub fn seed_from_name(name: &str) -> u64 {
+    name.bytes().fold(0xCBF2_9CE4_8422_2325, |acc, byte| {
+        (acc ^ byte as u64).wrapping_mul(0x0100_0000_01B3)
+    })
+}
+
+// A different seed on every call, for randomness nobody asked to reproduce
+pub fn fresh_seed() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or(0);
+    combine_seed(&[nanos, FRESH_SEEDS.fetch_add(1, Ordering::Relaxed)])
+}
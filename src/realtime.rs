@@ -0,0 +1,142 @@
+use log::{info, warn};
+
+/*
+
+    Optional realtime scheduling for the sequencing thread, for live use: SCHED_FIFO priority,
+        locking the process memory (so the loop never waits on a page fault) and pinning to a CPU core.
+    Applied from the sequencing thread itself (see start_live_loop), so all other threads keep normal priority.
+
+    Each option that cannot be applied, typically for lack of permissions, is skipped with a warning
+        and the thread runs on as it was.
+
+*/
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RealtimeOptions {
+    pub priority: Option<i32>, // SCHED_FIFO priority, 1 (lowest) to 99
+    pub lock_memory: bool,
+    pub cpu: Option<usize>,
+}
+
+impl RealtimeOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.priority.is_some() || self.lock_memory || self.cpu.is_some()
+    }
+
+    // Apply to the calling thread, returning (and logging) a warning for each option that could not be applied
+    pub fn apply(&self) -> Vec<String> {
+        let mut warnings = vec![];
+
+        if let Some(priority) = self.priority {
+            match set_fifo_priority(priority) {
+                Ok(()) => info!("Sequencing thread running with SCHED_FIFO priority {}", priority),
+                Err(e) => warnings.push(format!("Could not set realtime priority {}: {}", priority, e)),
+            }
+        }
+
+        if self.lock_memory {
+            match lock_memory() {
+                Ok(()) => info!("Process memory locked"),
+                Err(e) => warnings.push(format!("Could not lock memory: {}", e)),
+            }
+        }
+
+        if let Some(cpu) = self.cpu {
+            match pin_to_cpu(cpu) {
+                Ok(()) => info!("Sequencing thread pinned to CPU {}", cpu),
+                Err(e) => warnings.push(format!("Could not pin to CPU {}: {}", cpu, e)),
+            }
+        }
+
+        for warning in &warnings {
+            warn!("{}", warning);
+        }
+        warnings
+    }
+}
+
+#[cfg(unix)]
+fn set_fifo_priority(priority: i32) -> Result<(), String> {
+    if !(1..=99).contains(&priority) {
+        return Err("priority must be within 1-99".to_string());
+    }
+
+    let param = libc::sched_param { sched_priority: priority };
+    // Returns the error number rather than setting errno
+    let result = unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) };
+    match result {
+        0 => Ok(()),
+        libc::EPERM => Err("permission denied (run as root, grant CAP_SYS_NICE or raise rtprio in /etc/security/limits.conf)".to_string()),
+        errno => Err(std::io::Error::from_raw_os_error(errno).to_string()),
+    }
+}
+
+#[cfg(unix)]
+fn lock_memory() -> Result<(), String> {
+    match unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } {
+        0 => Ok(()),
+        _ => match std::io::Error::last_os_error().raw_os_error() {
+            Some(libc::EPERM) | Some(libc::ENOMEM) => {
+                Err("not permitted (grant CAP_IPC_LOCK or raise memlock in /etc/security/limits.conf)".to_string())
+            }
+            _ => Err(std::io::Error::last_os_error().to_string()),
+        },
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn pin_to_cpu(cpu: usize) -> Result<(), String> {
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return Err("no such CPU".to_string());
+    }
+
+    let result = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        // 0 is the calling thread
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    match result {
+        0 => Ok(()),
+        _ => match std::io::Error::last_os_error().raw_os_error() {
+            Some(libc::EINVAL) => Err("CPU not available to this process".to_string()),
+            _ => Err(std::io::Error::last_os_error().to_string()),
+        },
+    }
+}
+
+#[cfg(not(unix))]
+fn set_fifo_priority(_priority: i32) -> Result<(), String> {
+    Err("not supported on this platform".to_string())
+}
+
+#[cfg(not(unix))]
+fn lock_memory() -> Result<(), String> {
+    Err("not supported on this platform".to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_to_cpu(_cpu: usize) -> Result<(), String> {
+    Err("not supported on this platform".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::RealtimeOptions;
+
+    #[test]
+    fn options_test() {
+        let none = RealtimeOptions::default();
+        assert!(!none.is_enabled());
+        assert!(none.apply().is_empty());
+
+        // Rejected before any system call, so the test thread is left as it was
+        let invalid = RealtimeOptions { priority: Some(100), cpu: Some(100_000), ..Default::default() };
+        assert!(invalid.is_enabled());
+        let warnings = thread::spawn(move || invalid.apply()).join().unwrap();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("1-99"), "{}", warnings[0]);
+    }
+}
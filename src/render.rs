@@ -0,0 +1,333 @@
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use bigdecimal::{BigDecimal, ToPrimitive, Zero};
+use ringbuf::traits::{Producer, Split};
+use ringbuf::HeapRb;
+use rosc::{encoder, OscBundle, OscMessage, OscPacket, OscTime, OscType};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use toml::Value as TomlValue;
+
+use crate::clock::{Clock, VirtualClock};
+use crate::config;
+use crate::local_messaging::{LocalQueuePayload, LocalSequencerMessage};
+use crate::master_sequencer::{MasterSequencer, SequencerResetMode, SequencerStartMode};
+use crate::packet_utils;
+use crate::sequencer::SequencerEntry;
+use crate::sequencing_daemon::{DaemonStatus, OutgoingEvent, SequencingDaemon};
+use crate::ticks;
+use crate::timeline::{self, Timeline};
+
+/*
+
+    Offline rendering: plays a session through the sequencing loop on a VirtualClock, as fast as
+        the machine allows, and collects every event with its exact beat and time in seconds.
+
+    Session files are TOML, with queues in the update_queue format ("time" is the beats until the next entry,
+        as a number or a decimal string):
+
+    bpm = 120
+
+    [[sequencers]]
+    alias = "drums"
+    one_shot = false
+    seed = 1            # optional
+    entries = [
+        { time = 0.5, address = "/s_new", args = ["kick", -1, 0, 0] },
+        { time = "0.25", address = "/s_new", args = ["hat", -1, 0, 0] },
+    ]
+
+*/
+
+#[derive(Deserialize, Debug)]
+pub struct Session {
+    #[serde(default = "default_bpm")]
+    pub bpm: i32,
+    #[serde(default)]
+    pub sequencers: Vec<SessionSequencer>,
+}
+
+fn default_bpm() -> i32 {
+    120
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SessionSequencer {
+    pub alias: String,
+    #[serde(default)]
+    pub one_shot: bool,
+    pub seed: Option<u64>,
+    pub entries: Vec<SessionEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SessionEntry {
+    pub time: TomlValue,
+    pub address: String,
+    #[serde(default)]
+    pub args: Vec<TomlValue>,
+}
+
+impl Session {
+    pub fn load(path: &str) -> Result<Session, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        Session::from_toml(&contents).map_err(|e| format!("Invalid session {}: {}", path, e))
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Session, String> {
+        let session: Session = toml::from_str(contents).map_err(|e| e.to_string())?;
+        timeline::check_bpm(session.bpm)?;
+        Ok(session)
+    }
+
+    fn payloads(&self) -> Result<Vec<LocalQueuePayload<OscPacket>>, String> {
+        self.sequencers.iter().map(|seq| {
+            let mut entries: Vec<SequencerEntry<OscPacket>> = vec![];
+            let mut timeline = BigDecimal::zero();
+            for entry in &seq.entries {
+                let args = entry.args.iter()
+                    .map(config::to_osc_arg)
+                    .collect::<Result<Vec<OscType>, String>>()
+                    .map_err(|e| format!("{} in {}", e, seq.alias))?;
+                entries.push(SequencerEntry::new(
+                    timeline.clone(),
+                    OscPacket::Message(OscMessage { addr: entry.address.clone(), args }),
+                ));
+                timeline += to_beats(&entry.time)?;
+            }
+
+            Ok(LocalQueuePayload {
+                sequencer_alias: seq.alias.clone(),
+                entries,
+                lanes: vec![],
+                end_beat: timeline,
+                one_shot: seq.one_shot,
+                seed: seq.seed,
+            })
+        }).collect()
+    }
+}
+
+// Parsed from text, as floats would not give exact beats
+fn to_beats(value: &TomlValue) -> Result<BigDecimal, String> {
+    let text = match value {
+        TomlValue::Integer(i) => i.to_string(),
+        TomlValue::Float(f) => f.to_string(),
+        TomlValue::String(s) => s.clone(),
+        other => return Err(format!("Expected beats as a number or string, got {}", other)),
+    };
+    let beats = BigDecimal::from_str(&text).map_err(|e| format!("Invalid beats '{}': {}", text, e))?;
+    if beats < BigDecimal::zero() {
+        return Err(format!("Beats must not be negative, got {}", beats));
+    }
+    Ok(beats)
+}
+
+#[derive(Debug, Clone)]
+pub struct RenderedEvent {
+    pub beat: BigDecimal,
+    pub seconds: f64,
+    pub alias: String,
+    pub packet: OscPacket,
+}
+
+/*
+    Render the first length_beats of a session, ticking every tick_time of virtual time.
+    Events fire on the first tick at or after their beat, but are stamped with the exact beat:
+        how far the sequencer had moved past the entry on that tick is subtracted from the tick beat.
+*/
+pub fn render(session: &Session, length_beats: &BigDecimal, tick_time: Duration) -> Result<Vec<RenderedEvent>, String> {
+    let payloads = session.payloads()?;
+
+    let (mut producer, consumer) = HeapRb::<LocalSequencerMessage<OscPacket>>::new(1).split();
+    producer.try_push(LocalSequencerMessage::BatchQueue(payloads, false))
+        .map_err(|_| "Failed to queue session".to_string())?;
+
+    let fired: Arc<Mutex<Vec<OutgoingEvent<OscPacket>>>> = Arc::new(Mutex::new(vec![]));
+    let output = fired.clone();
+
+    let mut daemon = SequencingDaemon::new(
+        MasterSequencer::new(SequencerStartMode::WithLongestSequence, SequencerResetMode::Individual),
+        session.bpm,
+        tick_time,
+        consumer,
+        Arc::new(Mutex::new(DaemonStatus::default())),
+        move |events: Vec<OutgoingEvent<OscPacket>>, _| output.lock().unwrap().extend(events),
+    );
+
+    let mut clock = VirtualClock::new(SystemTime::UNIX_EPOCH);
+    // Read the same way the daemon reads it, so that positions stay exact
+    let mut timeline = Timeline::new(session.bpm);
+    let length_ticks = ticks::from_beats(length_beats);
+    let mut rendered: Vec<RenderedEvent> = vec![];
+
+    loop {
+        let position = timeline.ticks_at(clock.now());
+        daemon.tick(clock.now());
+
+        for outgoing in fired.lock().unwrap().drain(..) {
+            let event = outgoing.event;
+            let event_tick = position - event.late_ticks;
+
+            if event_tick >= length_ticks {
+                continue;
+            }
+
+            let event_beat = ticks::to_beats(event_tick);
+            rendered.push(RenderedEvent {
+                seconds: beats_to_seconds(&event_beat, session.bpm),
+                beat: event_beat,
+                packet: packet_utils::event_packet(&event, outgoing.variables.as_deref(), outgoing.transforms.as_deref()),
+                alias: event.alias,
+            });
+        }
+
+        if position >= length_ticks {
+            break;
+        }
+
+        clock.sleep(tick_time);
+    }
+
+    // Sequencers fire in no particular order within a tick; keep files reproducible
+    rendered.sort_by(|a, b| a.beat.cmp(&b.beat).then_with(|| a.alias.cmp(&b.alias)));
+    Ok(rendered)
+}
+
+pub fn beats_to_seconds(beats: &BigDecimal, bpm: i32) -> f64 {
+    beats.to_f64().unwrap_or(0.0) * 60.0 / bpm as f64
+}
+
+// Messages of a packet, with bundles flattened
+fn messages(packet: &OscPacket) -> Vec<&OscMessage> {
+    match packet {
+        OscPacket::Message(msg) => vec![msg],
+        OscPacket::Bundle(bundle) => bundle.content.iter().flat_map(messages).collect(),
+    }
+}
+
+fn arg_json(arg: &OscType) -> Value {
+    match arg {
+        OscType::Int(i) => json!(i),
+        OscType::Long(l) => json!(l),
+        OscType::Float(f) => json!(f),
+        OscType::Double(d) => json!(d),
+        OscType::String(s) => json!(s),
+        OscType::Bool(b) => json!(b),
+        other => json!(format!("{:?}", other)),
+    }
+}
+
+// One object per message: beat (exact, as a string), seconds, alias, address and args
+pub fn to_json(events: &[RenderedEvent]) -> String {
+    let rows: Vec<Value> = events.iter()
+        .flat_map(|event| messages(&event.packet).into_iter().map(move |msg| json!({
+            "beat": event.beat.to_string(),
+            "seconds": event.seconds,
+            "alias": event.alias,
+            "address": msg.addr,
+            "args": msg.args.iter().map(arg_json).collect::<Vec<Value>>(),
+        })))
+        .collect();
+    serde_json::to_string_pretty(&rows).unwrap_or_default()
+}
+
+// One row per message, args space-separated in the last column
+pub fn to_csv(events: &[RenderedEvent]) -> String {
+    let mut csv = "beat,seconds,alias,address,args\n".to_string();
+    for event in events {
+        for msg in messages(&event.packet) {
+            let args: Vec<String> = msg.args.iter().map(|arg| match arg_json(arg) {
+                Value::String(s) => s,
+                other => other.to_string(),
+            }).collect();
+            csv += &format!(
+                "{},{},{},{},{}\n",
+                event.beat, event.seconds, csv_field(&event.alias), csv_field(&msg.addr), csv_field(&args.join(" "))
+            );
+        }
+    }
+    csv
+}
+
+fn csv_field(text: &str) -> String {
+    if text.contains(',') || text.contains('"') || text.contains('\n') {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+/*
+    SuperCollider NRT score: each bundle encoded and prefixed with its size as a big-endian int32,
+        timetags in seconds from the start. Events at the same time share a bundle, and a closing
+        /c_set 0 0 at end_seconds sets the length of the render.
+*/
+pub fn to_score(events: &[RenderedEvent], end_seconds: f64) -> Result<Vec<u8>, String> {
+    let mut bundles: Vec<(f64, Vec<OscPacket>)> = vec![];
+    for event in events {
+        match bundles.last_mut() {
+            Some((seconds, content)) if *seconds == event.seconds => content.push(event.packet.clone()),
+            _ => bundles.push((event.seconds, vec![event.packet.clone()])),
+        }
+    }
+    bundles.push((end_seconds, vec![OscPacket::Message(OscMessage {
+        addr: "/c_set".to_string(),
+        args: vec![OscType::Int(0), OscType::Int(0)],
+    })]));
+
+    let mut score: Vec<u8> = vec![];
+    for (seconds, content) in bundles {
+        let bytes = encoder::encode(&OscPacket::Bundle(OscBundle {
+            timetag: score_time(seconds),
+            content,
+        })).map_err(|e| format!("Failed to encode score bundle: {}", e))?;
+        score.extend_from_slice(&(bytes.len() as i32).to_be_bytes());
+        score.extend(bytes);
+    }
+    Ok(score)
+}
+
+fn score_time(seconds: f64) -> OscTime {
+    OscTime {
+        seconds: seconds.trunc() as u32,
+        fractional: (seconds.fract() * 4_294_967_296.0) as u32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use bigdecimal::BigDecimal;
+
+    use super::{render, to_csv, Session};
+
+    #[test]
+    fn render_test() {
+        let session = Session::from_toml(r#"
+            bpm = 120
+
+            [[sequencers]]
+            alias = "drums"
+            entries = [
+                { time = 0.75, address = "/kick", args = [1] },
+                { time = "0.25", address = "/hat", args = ["closed", 0.5] },
+            ]
+        "#).unwrap();
+
+        // An odd tick time, so that no event falls on a tick
+        let events = render(&session, &BigDecimal::from(2), Duration::from_micros(7000)).unwrap();
+
+        let beats: Vec<String> = events.iter().map(|e| e.beat.to_string()).collect();
+        assert_eq!(beats, vec!["0", "0.75", "1", "1.75"]);
+        assert_eq!(events[1].seconds, 0.375);
+        assert_eq!(events[1].beat, BigDecimal::from_str("0.75").unwrap());
+
+        let csv = to_csv(&events);
+        assert_eq!(csv.lines().nth(2), Some("0.75,0.375,drums,/hat,closed 0.5"));
+    }
+}
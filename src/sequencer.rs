@@ -1,7 +1,13 @@
-use std::str::FromStr;
-use bigdecimal::{BigDecimal, Zero};
+use bigdecimal::BigDecimal;
 use log::debug;
 
+use std::collections::HashSet;
+
+use crate::automation::{AutomationLane, QueuedLane};
+use crate::groove::{Groove, TickGroove};
+use crate::random::{combine_seed, SeededRandom};
+use crate::ticks::{self, Ticks};
+
 
 /*
 
@@ -9,6 +15,11 @@ use log::debug;
 
     Generic sequencer class.
     See description of fields in struct. 
+    Entries are queued with beats, but all positions are kept as ticks (see ticks.rs) once queued.
+    Queued entries are sorted by trigger tick, and each loop is played through a cursor into its schedule,
+        so that a tick only ever looks at the entries it plays.
+    Automation lanes (see automation.rs) are queued and loop along with the entries, and are sampled on the ticks
+        that pass their next sample.
 
 */
 
@@ -16,26 +27,126 @@ use log::debug;
 pub struct SequencerEntry<T: Clone> {
     pub trigger_beat: BigDecimal,
     pub contents: T,
+    pub attributes: EntryAttributes,
 }
 
 impl<T: Clone> SequencerEntry<T> {
     pub fn new(beat: BigDecimal, entry: T) -> SequencerEntry<T> {
         SequencerEntry {
             trigger_beat: beat,
-            contents: entry
+            contents: entry,
+            attributes: EntryAttributes::default()
         }
     }
+
+    pub fn with_attributes(beat: BigDecimal, entry: T, attributes: EntryAttributes) -> SequencerEntry<T> {
+        SequencerEntry {
+            trigger_beat: beat,
+            contents: entry,
+            attributes
+        }
+    }
+}
+
+/*
+    Conditions for whether an entry plays on a given loop of its sequence. 
+    Loops are counted from 0 for the first time a sequencer plays. 
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum EntryCondition {
+    EveryNthLoop { n: u64, offset: u64 },
+    SkipFirstLoop,
+    FirstLoopOnly,
+}
+
+impl EntryCondition {
+    pub fn is_met(&self, loop_index: u64) -> bool {
+        match self {
+            EntryCondition::EveryNthLoop { n, offset } => *n == 0 || loop_index % n == offset % n,
+            EntryCondition::SkipFirstLoop => loop_index > 0,
+            EntryCondition::FirstLoopOnly => loop_index == 0,
+        }
+    }
+}
+
+/*
+    Optional per-entry behaviour, evaluated each time the sequence loops. 
+    All randomness is derived from the sequencer seed, the loop index and the entry index, 
+        so the same seed always plays the same "random" variations. 
+*/
+#[derive(Debug, Clone, Default)]
+pub struct EntryAttributes {
+    pub probability: Option<f32>, // Chance (0.0 - 1.0) that the entry plays on a given loop
+    pub conditions: Vec<EntryCondition>, // All must be met for the entry to play
+    pub humanize_beats: Option<BigDecimal>, // Max random offset (in either direction) of the trigger beat
+    pub arg_randomization: Vec<(String, f32)>, // Named args and the max random offset of their values
 }
 
+/*
+    An entry as it plays on the current loop, with its effective trigger tick. 
+    The entry index is the position of the entry in the queued sequence, sorted by trigger beat. 
+    The roll is a deterministic random value for contents-specific variation (see arg_randomization). 
+    Late ticks are how far the sequencer had moved past the trigger tick on the tick that played the entry. 
+    The alias is left empty by the sequencer itself and filled in by MasterSequencer. 
+    Automation samples carry their value, with the entry index being that of their lane. 
+*/
+#[derive(Debug, Clone)]
+pub struct SequencerEvent<T: Clone> {
+    pub alias: String,
+    pub entry_index: usize,
+    pub trigger_tick: Ticks,
+    pub late_ticks: Ticks,
+    pub contents: T,
+    pub loop_index: u64,
+    pub roll: u64,
+    pub arg_randomization: Vec<(String, f32)>,
+    pub value: Option<f32>,
+}
+
+// An entry scheduled on the current loop; contents are only cloned once it plays
+#[derive(Debug, Clone)]
+struct ScheduledEntry {
+    entry_index: usize,
+    trigger_tick: Ticks,
+    roll: u64,
+}
+
+// An entry with its beats converted to ticks, once when queued rather than on every loop
+#[derive(Debug, Clone)]
+struct QueuedEntry<T: Clone> {
+    trigger_tick: Ticks,
+    humanize_ticks: Option<Ticks>,
+    entry: SequencerEntry<T>,
+}
+
+impl<T: Clone> QueuedEntry<T> {
+    fn new(entry: SequencerEntry<T>) -> QueuedEntry<T> {
+        QueuedEntry {
+            trigger_tick: ticks::from_beats(&entry.trigger_beat),
+            humanize_ticks: entry.attributes.humanize_beats.as_ref().map(ticks::from_beats),
+            entry,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Sequencer<T: Clone> {
-    pub active_sequence: Vec<SequencerEntry<T>>, // The current sequence, accessed with tick() until end_beat is reached. 
-    pub queued_sequence: Vec<SequencerEntry<T>>, // The queued sequence that will replace active_sequence when end_beat is reached. 
-    pub current_beat: BigDecimal, // Beat timeline, to be compared with end_beat. 
-    processed_beats: Option<BigDecimal>, // Last step of current_beat, used for internal logic. 
-    pub end_beat: BigDecimal, // The last beat of the current sequence. 
-    pub queue_end_beat: BigDecimal // The last beat of the queued sequence. Replaces end_beat when end_beat is reached. 
+    active_sequence: Vec<QueuedEntry<T>>, // The current sequence, accessed with tick() until end_tick is reached. 
+    queued_sequence: Option<Vec<QueuedEntry<T>>>, // A newly queued sequence that will replace active_sequence when end_tick is reached, if any. 
+    current_tick: Ticks, // Tick timeline, to be compared with end_tick. 
+    processed_tick: Option<Ticks>, // Last step of current_tick, used for internal logic. 
+    end_tick: Ticks, // The last tick of the current sequence. 
+    queue_end_tick: Ticks, // The last tick of the queued sequence. Replaces end_tick when end_tick is reached. 
+    pub seed: u64, // Base for all per-entry randomness, see EntryAttributes. 
+    pub loop_index: u64, // How many times the sequencer has looped, starting at 0. 
+    groove: Option<Groove>, // Timing offsets applied to all entries, see set_groove(). 
+    groove_ticks: Option<TickGroove>, // The same groove in ticks, as build_schedule() uses it. 
+    started: bool, // Whether the sequencer has been reset at least once, i.e. is past loop_index 0. 
+    schedule: Vec<ScheduledEntry>, // The active sequence as it plays on the current loop, sorted by trigger tick. 
+    cursor: usize, // Index of the first entry in schedule not yet played. 
+    active_lanes: Vec<QueuedLane<T>>, // Automation lanes of the current sequence. 
+    queued_lanes: Option<Vec<QueuedLane<T>>>, // Automation lanes of the queued sequence, replacing active_lanes along with it. 
+    next_samples: Vec<Ticks>, // Tick of the next sample of each active lane. 
 }
 
 impl<T: Clone> Sequencer<T> {
@@ -43,48 +154,187 @@ impl<T: Clone> Sequencer<T> {
     pub fn new() -> Sequencer<T> {
         Sequencer {
             active_sequence: vec![],
-            queued_sequence: vec![],
-            current_beat: BigDecimal::zero(),
-            processed_beats: None,
-            end_beat: BigDecimal::zero(),
-            queue_end_beat: BigDecimal::zero()
+            queued_sequence: None,
+            current_tick: 0,
+            processed_tick: None,
+            end_tick: 0,
+            queue_end_tick: 0,
+            seed: 0,
+            loop_index: 0,
+            groove: None,
+            groove_ticks: None,
+            started: false,
+            schedule: vec![],
+            cursor: 0,
+            active_lanes: vec![],
+            queued_lanes: None,
+            next_samples: vec![],
         }
     }
 
-    // Overshoot is the amount of beats you've already started counting on the new sequence, e.g. by having the last tick amount
-    //  overshoot the end_beat by n amount
-    pub fn reset(&mut self, overshoot: BigDecimal) {
-        debug!("[sequencer] reset: overshoot={}, active_entries={}, end_beat={}, queue_end_beat={}", overshoot, self.queued_sequence.len(), self.end_beat, self.queue_end_beat);
-        self.current_beat = overshoot;
-        self.processed_beats = None; 
-        self.active_sequence = self.queued_sequence.clone();
-        self.end_beat = self.queue_end_beat.clone();
+    // Overshoot is the amount of ticks you've already started counting on the new sequence, e.g. by having the last tick amount
+    //  overshoot the end_tick by n amount
+    pub fn reset(&mut self, overshoot: Ticks) {
+        debug!("[sequencer] reset: overshoot={}, active_entries={}, end_tick={}, queue_end_tick={}", overshoot, self.queued_sequence.as_ref().map(|queued| queued.len()).unwrap_or(self.active_sequence.len()), self.end_tick, self.queue_end_tick);
+        self.current_tick = overshoot;
+        self.processed_tick = None; 
+        // Without a new queue the active sequence loops as it is
+        if let Some(queued) = self.queued_sequence.take() {
+            self.active_sequence = queued;
+        }
+        if let Some(queued) = self.queued_lanes.take() {
+            self.active_lanes = queued;
+        }
+        self.end_tick = self.queue_end_tick;
+
+        if self.started {
+            self.loop_index += 1;
+        }
+        self.started = true;
+        self.schedule = self.build_schedule();
+        self.cursor = 0;
+        self.next_samples = vec![0; self.active_lanes.len()];
+    }
+
+    /*
+        Change the groove, taking effect immediately. Entries already played on the current loop 
+            are not played again even if the new groove moves them later. 
+    */
+    pub fn set_groove(&mut self, groove: Option<Groove>) {
+        self.groove_ticks = groove.as_ref().map(Groove::to_ticks);
+        self.groove = groove;
+
+        if self.started {
+            let played: HashSet<usize> = self.schedule[..self.cursor].iter()
+                .map(|scheduled| scheduled.entry_index)
+                .collect();
+
+            self.schedule = self.build_schedule().into_iter()
+                .filter(|scheduled| !played.contains(&scheduled.entry_index))
+                .collect();
+
+            // Entries the new groove moves before the current position are skipped for this loop
+            self.cursor = match self.processed_tick {
+                Some(processed) => self.schedule.partition_point(|scheduled| scheduled.trigger_tick <= processed),
+                None => 0,
+            };
+        }
+    }
+
+    pub fn get_groove(&self) -> Option<&Groove> {
+        self.groove.as_ref()
+    }
+
+    /*
+        Resolve attributes of the active sequence for the current loop: drop entries that should not play 
+            and apply groove and humanization offsets, keeping trigger ticks within the sequence. 
+    */
+    fn build_schedule(&self) -> Vec<ScheduledEntry> {
+        let mut schedule: Vec<ScheduledEntry> = self.active_sequence.iter().enumerate()
+            .filter_map(|(index, queued)| {
+                let entry = &queued.entry;
+                let attributes = &entry.attributes;
+                let mut rng = SeededRandom::new(combine_seed(&[self.seed, self.loop_index, index as u64]));
+
+                // Always draw, so that changing one attribute does not shift the randomness of the others
+                let probability_roll = rng.next_f32();
+                let humanize_roll = rng.next_f32();
+                let roll = rng.next_u64();
+
+                let conditions_met = attributes.conditions.iter().all(|cond| cond.is_met(self.loop_index));
+                let probability_met = attributes.probability.map(|p| probability_roll < p).unwrap_or(true);
+                if !conditions_met || !probability_met {
+                    return None;
+                }
+
+                let mut trigger_tick = queued.trigger_tick;
+                if let Some(groove) = &self.groove_ticks {
+                    trigger_tick = trigger_tick.saturating_add(groove.offset_for(queued.trigger_tick));
+                }
+                if let Some(humanize) = queued.humanize_ticks {
+                    let factor = humanize_roll as f64 * 2.0 - 1.0;
+                    trigger_tick = trigger_tick.saturating_add((humanize as f64 * factor).round() as Ticks);
+                }
+                if self.groove_ticks.is_some() || queued.humanize_ticks.is_some() {
+                    trigger_tick = trigger_tick.max(0).min(self.end_tick);
+                }
+
+                Some(ScheduledEntry {
+                    entry_index: index,
+                    trigger_tick,
+                    roll,
+                })
+            })
+            .collect();
+
+        // Already in order unless groove or humanization moved entries, in which case the stable sort has little to do
+        schedule.sort_by_key(|scheduled| scheduled.trigger_tick);
+        schedule
     }
 
-    pub fn tick(&mut self, beats: BigDecimal) -> Vec<T> {
+    pub fn tick(&mut self, ticks: Ticks) -> Vec<T> {
+        self.tick_events(ticks).into_iter().map(|event| event.contents).collect()
+    }
+
+    pub fn tick_events(&mut self, ticks: Ticks) -> Vec<SequencerEvent<T>> {
 
 
         // Finished sequences stop ticking
         if !&self.is_finished() {
 
-            self.current_beat += beats;
-    
-            let candidates: Vec<T> = self
-                .active_sequence
-                .iter()
-                .filter(|n| {
-                    // Bit chunky! In rough terms: entries not yet processed which current_beat has now passed. 
-                    &n.trigger_beat <= &self.current_beat && match &self.processed_beats { Some(value) => &n.trigger_beat > value, None => true }
+            self.current_tick += ticks;
+
+            // Entries not yet played which current_tick has now passed
+            let start = self.cursor;
+            while self.cursor < self.schedule.len() && self.schedule[self.cursor].trigger_tick <= self.current_tick {
+                self.cursor += 1;
+            }
+
+            let mut candidates: Vec<SequencerEvent<T>> = self.schedule[start..self.cursor].iter()
+                .map(|scheduled| {
+                    let entry = &self.active_sequence[scheduled.entry_index].entry;
+                    SequencerEvent {
+                        alias: String::new(),
+                        entry_index: scheduled.entry_index,
+                        trigger_tick: scheduled.trigger_tick,
+                        late_ticks: self.current_tick - scheduled.trigger_tick,
+                        contents: entry.contents.clone(),
+                        loop_index: self.loop_index,
+                        roll: scheduled.roll,
+                        arg_randomization: entry.attributes.arg_randomization.clone(),
+                        value: None,
+                    }
                 })
-                .map(|n| n.clone().contents.clone())
                 .collect();
 
+            // One sample per lane and tick: if a tick passed several sample points, only the latest is sent
+            let last_sample_tick = self.current_tick.min(self.end_tick - 1);
+            for (index, (lane, next_sample)) in self.active_lanes.iter().zip(self.next_samples.iter_mut()).enumerate() {
+                if *next_sample > last_sample_tick {
+                    continue;
+                }
+                let sample_tick = *next_sample + (last_sample_tick - *next_sample) / lane.rate_ticks * lane.rate_ticks;
+                *next_sample = sample_tick + lane.rate_ticks;
+
+                candidates.push(SequencerEvent {
+                    alias: String::new(),
+                    entry_index: index,
+                    trigger_tick: sample_tick,
+                    late_ticks: self.current_tick - sample_tick,
+                    contents: lane.contents.clone(),
+                    loop_index: self.loop_index,
+                    roll: 0,
+                    arg_randomization: vec![],
+                    value: Some(lane.value_at(sample_tick)),
+                });
+            }
+
             if !candidates.is_empty() {
-                debug!("[sequencer] tick: {} candidates at beat {} (processed={:?})", candidates.len(), self.current_beat, self.processed_beats);
+                debug!("[sequencer] tick: {} candidates at tick {} (processed={:?})", candidates.len(), self.current_tick, self.processed_tick);
             }
 
-            // Note that entries up until this beat have been tick-returned and should not be returned again on later current_beats
-            self.processed_beats = Some(self.current_beat.clone());
+            // Note that entries up until this tick have been tick-returned and should not be returned again on later current_ticks
+            self.processed_tick = Some(self.current_tick);
     
             candidates
     
@@ -95,56 +345,99 @@ impl<T: Clone> Sequencer<T> {
     }
 
     pub fn is_finished(&self) -> bool {
-        let cursor = &self.processed_beats.clone().unwrap_or(self.current_beat.clone());
-        let finished = cursor >= &self.end_beat;
+        let position = self.processed_tick.unwrap_or(self.current_tick);
+        let finished = position >= self.end_tick;
         if finished {
-            debug!("[sequencer] is_finished: true (cursor={}, end_beat={}, processed_beats={:?}, current_beat={})", cursor, self.end_beat, self.processed_beats, self.current_beat);
+            debug!("[sequencer] is_finished: true (position={}, end_tick={}, processed_tick={:?}, current_tick={})", position, self.end_tick, self.processed_tick, self.current_tick);
+        }
+        finished
+    }
+
+    // Use to check by how much tick() has pushed current_tick past end_tick, if at all. Only relevant if is_finished(). 
+    pub fn get_overshoot(&self) -> Ticks {
+        (self.current_tick - self.end_tick).max(0)
+    }
+
+    // Ticks from current_tick until the next entry, lane sample or the end of the sequence, whichever comes first; None once finished
+    pub fn ticks_until_due(&self) -> Option<Ticks> {
+        if self.is_finished() {
+            return None;
         }
-        return finished; 
+        let next = self.schedule.get(self.cursor)
+            .map(|scheduled| scheduled.trigger_tick)
+            .into_iter()
+            .chain(self.next_samples.iter().copied())
+            .fold(self.end_tick, Ticks::min);
+        Some((next - self.current_tick).max(0))
+    }
+
+    pub fn current_tick(&self) -> Ticks {
+        self.current_tick
+    }
+
+    pub fn end_tick(&self) -> Ticks {
+        self.end_tick
     }
 
-    // Use to check by how much tick() has pushed current_beat past end_beat, if at all. Only relevant if is_finished(). 
-    pub fn get_overshoot(&self) -> BigDecimal {
-        return match &self.current_beat > &self.end_beat {true => &self.current_beat - &self.end_beat, false => BigDecimal::zero()}; 
+    // Position and length in beats, for status and logging outside the engine
+    pub fn current_beat(&self) -> BigDecimal {
+        ticks::to_beats(self.current_tick)
+    }
+
+    pub fn end_beat(&self) -> BigDecimal {
+        ticks::to_beats(self.end_tick)
     }
 
     /*
         NOTE: This presumes that new_queue already has its entries arranged on a timeline, with each time signature representing their start time. 
     */
     pub fn queue(&mut self, new_queue: Vec<SequencerEntry<T>>, end_beat: BigDecimal) {
-        debug!("[sequencer] queue: {} entries, end_beat={}", new_queue.len(), end_beat);
-        self.queue_end_beat = end_beat.clone();
-        self.queued_sequence = new_queue.clone();
+        self.queue_with_lanes(new_queue, vec![], end_beat);
+    }
+
+    // As queue, with automation lanes that replace the current ones along with the entries
+    pub fn queue_with_lanes(&mut self, new_queue: Vec<SequencerEntry<T>>, lanes: Vec<AutomationLane<T>>, end_beat: BigDecimal) {
+        debug!("[sequencer] queue: {} entries, {} lanes, end_beat={}", new_queue.len(), lanes.len(), end_beat);
+        self.queue_end_tick = ticks::from_beats(&end_beat);
+        let mut queued: Vec<QueuedEntry<T>> = new_queue.into_iter().map(QueuedEntry::new).collect();
+        // Stable, so entries on the same tick keep the order they were given in
+        queued.sort_by_key(|entry| entry.trigger_tick);
+        self.queued_sequence = Some(queued);
+        self.queued_lanes = Some(lanes.into_iter().map(QueuedLane::new).collect());
     }
 
 
 }
 
+#[cfg(test)]
 mod tests {
     use std::str::FromStr;
 
-    use super::SequencerEntry;
+    use super::{EntryAttributes, EntryCondition, SequencerEntry, SequencerEvent};
+    use crate::automation::{AutomationLane, AutomationShape};
+    use crate::groove::Groove;
     use super::Sequencer;
     use bigdecimal::BigDecimal;
+    use crate::ticks::{self, Ticks};
 
     #[test]
     fn reset_test() {
         let entries: Vec<SequencerEntry<&str>> = vec![
-            SequencerEntry {trigger_beat: BigDecimal::from_str("0.0").unwrap(), contents:"one"},    
-            SequencerEntry {trigger_beat: BigDecimal::from_str("0.2").unwrap(), contents:"two"},    
-            SequencerEntry {trigger_beat: BigDecimal::from_str("1.0").unwrap(), contents:"three"},    
+            SequencerEntry::new(BigDecimal::from_str("0.0").unwrap(), "one"),    
+            SequencerEntry::new(BigDecimal::from_str("0.2").unwrap(), "two"),    
+            SequencerEntry::new(BigDecimal::from_str("1.0").unwrap(), "three"),    
         ];
 
         let mut sequencer = Sequencer::new();
         sequencer.queue(entries, big("1.5"));
-        sequencer.reset(big("0.3"));
+        sequencer.reset(ticks("0.3"));
 
-        assert_eq!(sequencer.current_beat, BigDecimal::from_str("0.3").unwrap());
-        assert_eq!(sequencer.processed_beats, None);
+        assert_eq!(sequencer.current_beat(), BigDecimal::from_str("0.3").unwrap());
+        assert_eq!(sequencer.processed_tick, None);
 
-        assert_eq!(sequencer.tick(big("0.6")), vec!["one", "two"]);
-        assert_eq!(sequencer.current_beat, BigDecimal::from_str("0.9").unwrap());
-        assert_eq!(&sequencer.processed_beats.clone().unwrap(), &BigDecimal::from_str("0.9").unwrap());
+        assert_eq!(sequencer.tick(ticks("0.6")), vec!["one", "two"]);
+        assert_eq!(sequencer.current_beat(), BigDecimal::from_str("0.9").unwrap());
+        assert_eq!(sequencer.processed_tick, Some(ticks("0.9")));
 
     }
 
@@ -152,38 +445,214 @@ mod tests {
         BigDecimal::from_str(inp).unwrap()
     }
 
+    fn ticks(inp: &str) -> Ticks {
+        ticks::from_beats(&big(inp))
+    }
+
     #[test]
     fn tick_test() {
         let entries: Vec<SequencerEntry<&str>> = vec![
-            SequencerEntry {trigger_beat: big("0.0"), contents:"one"},    
-            SequencerEntry {trigger_beat: big("0.5"), contents:"two"},    
-            SequencerEntry {trigger_beat: big("1.5"), contents:"three"},    
+            SequencerEntry::new(big("0.0"), "one"),    
+            SequencerEntry::new(big("0.5"), "two"),    
+            SequencerEntry::new(big("1.5"), "three"),    
         ];
 
         let mut sequencer = Sequencer::new();
         sequencer.queue(entries, big("3.0"));
-        sequencer.reset(big("0.0"));
-
-        assert_eq!(sequencer.tick(big("0.25")), vec!["one"]);
-        assert_eq!(sequencer.current_beat, big("0.25"));
-        assert_eq!(sequencer.processed_beats.clone().unwrap(), big("0.25"));
-
-        assert_eq!(sequencer.tick(big("0.25")), vec!["two"]);
-        assert_eq!(sequencer.current_beat, big("0.5"));
-        assert_eq!(sequencer.processed_beats.clone().unwrap(), big("0.5"));
-        assert_eq!(sequencer.tick(big("0.25")).is_empty(), true);
-        assert_eq!(sequencer.tick(big("0.25")).is_empty(), true);
-        assert_eq!(sequencer.tick(big("0.25")).is_empty(), true);
-        assert_eq!(sequencer.tick(big("0.25")), vec!["three"]);
-        assert_eq!(sequencer.tick(big("1.4")).is_empty(), true);
-        assert_eq!(sequencer.current_beat, big("2.9"));
+        sequencer.reset(ticks("0.0"));
+
+        assert_eq!(sequencer.tick(ticks("0.25")), vec!["one"]);
+        assert_eq!(sequencer.current_beat(), big("0.25"));
+        assert_eq!(sequencer.processed_tick, Some(ticks("0.25")));
+
+        assert_eq!(sequencer.tick(ticks("0.25")), vec!["two"]);
+        assert_eq!(sequencer.current_beat(), big("0.5"));
+        assert_eq!(sequencer.processed_tick, Some(ticks("0.5")));
+        assert_eq!(sequencer.tick(ticks("0.25")).is_empty(), true);
+        assert_eq!(sequencer.tick(ticks("0.25")).is_empty(), true);
+        assert_eq!(sequencer.tick(ticks("0.25")).is_empty(), true);
+        assert_eq!(sequencer.tick(ticks("0.25")), vec!["three"]);
+        assert_eq!(sequencer.tick(ticks("1.4")).is_empty(), true);
+        assert_eq!(sequencer.current_beat(), big("2.9"));
         assert_eq!(sequencer.is_finished(), false);
-        assert_eq!(sequencer.tick(big("0.3")).is_empty(), true);
-        assert_eq!(sequencer.current_beat, big("3.2"));
+        assert_eq!(sequencer.tick(ticks("0.3")).is_empty(), true);
+        assert_eq!(sequencer.current_beat(), big("3.2"));
         assert_eq!(sequencer.is_finished(), true);
-        assert_eq!(sequencer.tick(big("0.3")).is_empty(), true);
-        assert_eq!(sequencer.current_beat, big("3.2"));
+        assert_eq!(sequencer.tick(ticks("0.3")).is_empty(), true);
+        assert_eq!(sequencer.current_beat(), big("3.2"));
         assert_eq!(sequencer.is_finished(), true);
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn unsorted_queue_test() {
+        let entries: Vec<SequencerEntry<&str>> = vec![
+            SequencerEntry::new(big("1.0"), "three"),
+            SequencerEntry::new(big("0.0"), "one"),
+            SequencerEntry::new(big("0.5"), "two"),
+            SequencerEntry::new(big("0.5"), "two_again"),
+        ];
+
+        let mut sequencer = Sequencer::new();
+        sequencer.queue(entries, big("2.0"));
+        sequencer.reset(ticks("0.0"));
+
+        assert_eq!(sequencer.tick(ticks("0.5")), vec!["one", "two", "two_again"]);
+        assert!(sequencer.tick(ticks("0.25")).is_empty());
+        assert_eq!(sequencer.tick(ticks("0.25")), vec!["three"]);
+    }
+
+    #[test]
+    fn long_sequence_test() {
+        // An automation curve of ten thousand points, played back in steps that do not line up with them
+        let entries: Vec<SequencerEntry<usize>> = (0..10_000)
+            .map(|i| SequencerEntry::new(BigDecimal::from(i as u32) / BigDecimal::from(1000), i))
+            .collect();
+
+        let mut sequencer = Sequencer::new();
+        sequencer.queue(entries, big("10.0"));
+
+        for _ in 0..2 {
+            sequencer.reset(ticks("0.0"));
+            let mut played: Vec<usize> = vec![];
+            while !sequencer.is_finished() {
+                played.extend(sequencer.tick(ticks("0.0037")));
+            }
+            assert_eq!(played, (0..10_000).collect::<Vec<usize>>());
+        }
+    }
+
+    fn play_loops(sequencer: &mut Sequencer<&'static str>, loops: usize) -> Vec<Vec<&'static str>> {
+        (0..loops).map(|_| {
+            sequencer.reset(ticks("0.0"));
+            sequencer.tick(ticks("4.0"))
+        }).collect()
+    }
+
+    #[test]
+    fn conditions_test() {
+        let every_second = EntryAttributes { conditions: vec![EntryCondition::EveryNthLoop { n: 2, offset: 1 }], ..Default::default() };
+        let skip_first = EntryAttributes { conditions: vec![EntryCondition::SkipFirstLoop], ..Default::default() };
+        let entries: Vec<SequencerEntry<&str>> = vec![
+            SequencerEntry::new(big("0.0"), "always"),
+            SequencerEntry::with_attributes(big("1.0"), "odd", every_second),
+            SequencerEntry::with_attributes(big("2.0"), "later", skip_first),
+        ];
+
+        let mut sequencer = Sequencer::new();
+        sequencer.queue(entries, big("4.0"));
+
+        assert_eq!(play_loops(&mut sequencer, 4), vec![
+            vec!["always"],
+            vec!["always", "odd", "later"],
+            vec!["always", "later"],
+            vec!["always", "odd", "later"],
+        ]);
+        assert_eq!(sequencer.loop_index, 3);
+    }
+
+    #[test]
+    fn probability_is_seeded_test() {
+        let half = EntryAttributes { probability: Some(0.5), ..Default::default() };
+        let entries: Vec<SequencerEntry<&str>> = (0..8)
+            .map(|i| SequencerEntry::with_attributes(BigDecimal::from(i) / BigDecimal::from(2), "maybe", half.clone()))
+            .collect();
+
+        let mut first = Sequencer::new();
+        first.seed = 99;
+        first.queue(entries.clone(), big("4.0"));
+        let mut second = Sequencer::new();
+        second.seed = 99;
+        second.queue(entries.clone(), big("4.0"));
+
+        let first_loops = play_loops(&mut first, 8);
+        assert_eq!(first_loops, play_loops(&mut second, 8));
+
+        let played: usize = first_loops.iter().map(|l| l.len()).sum();
+        assert!(played > 0 && played < 64);
+    }
+
+    #[test]
+    fn humanize_test() {
+        let loose = EntryAttributes { humanize_beats: Some(big("0.1")), ..Default::default() };
+        let entries: Vec<SequencerEntry<&str>> = vec![
+            SequencerEntry::with_attributes(big("0.0"), "first", loose.clone()),
+            SequencerEntry::with_attributes(big("2.0"), "second", loose.clone()),
+        ];
+
+        let mut sequencer = Sequencer::new();
+        sequencer.queue(entries, big("4.0"));
+
+        for _ in 0..10 {
+            sequencer.reset(ticks("0.0"));
+            let events = sequencer.tick_events(ticks("4.0"));
+            assert_eq!(events.len(), 2);
+            assert!(events[0].trigger_tick >= ticks("0.0") && events[0].trigger_tick <= ticks("0.1"));
+            assert!(events[1].trigger_tick >= ticks("1.9") && events[1].trigger_tick <= ticks("2.1"));
+        }
+    }
+
+    #[test]
+    fn groove_test() {
+        let entries: Vec<SequencerEntry<&str>> = (0..4)
+            .map(|i| SequencerEntry::new(BigDecimal::from(i) / BigDecimal::from(4), "16th"))
+            .collect();
+
+        let mut sequencer = Sequencer::new();
+        sequencer.queue(entries, big("1.0"));
+        sequencer.set_groove(Some(Groove::new(big("0.25"), vec![big("0.0"), big("0.1")]).unwrap()));
+        sequencer.reset(ticks("0.0"));
+
+        let beats: Vec<Ticks> = sequencer.tick_events(ticks("1.0")).iter().map(|e| e.trigger_tick).collect();
+        assert_eq!(beats, vec![ticks("0.0"), ticks("0.35"), ticks("0.5"), ticks("0.85")]);
+
+        // Changing the groove mid-loop must not replay entries that already played
+        sequencer.reset(ticks("0.0"));
+        assert_eq!(sequencer.tick(ticks("0.3")), vec!["16th"]);
+        sequencer.set_groove(Some(Groove::new(big("0.25"), vec![big("0.1")]).unwrap()));
+        let remaining: Vec<Ticks> = sequencer.tick_events(ticks("0.7")).iter().map(|e| e.trigger_tick).collect();
+        assert_eq!(remaining, vec![ticks("0.35"), ticks("0.6"), ticks("0.85")]);
+    }
+
+    #[test]
+    fn offset_overflow_test() {
+        // Offsets this far out saturate to the ends of Ticks, and must still end up clamped to the sequence
+        let loose = EntryAttributes { humanize_beats: Some(big("1e12")), ..Default::default() };
+        let entries = vec![SequencerEntry::with_attributes(big("0.5"), "late", loose)];
+
+        let mut sequencer = Sequencer::new();
+        sequencer.queue(entries, big("1.0"));
+        for (offset, expected) in [("1e12", "1.0"), ("-1e12", "0.0")] {
+            sequencer.set_groove(Some(Groove::new(big("0.25"), vec![big(offset)]).unwrap()));
+            sequencer.reset(ticks("0.0"));
+            let events = sequencer.tick_events(ticks("1.0"));
+            assert_eq!(events.iter().map(|e| e.trigger_tick).collect::<Vec<Ticks>>(), vec![ticks(expected)]);
+        }
+    }
+
+    #[test]
+    fn automation_test() {
+        let ramp = AutomationShape::Breakpoints(vec![(big("0.0"), 0.0), (big("1.0"), 1.0)]);
+        let lane = AutomationLane::new("ramp", ramp, big("0.25")).unwrap();
+
+        let mut sequencer = Sequencer::new();
+        sequencer.queue_with_lanes(vec![SequencerEntry::new(big("0.0"), "note")], vec![lane], big("1.0"));
+        sequencer.reset(ticks("0.0"));
+
+        let values = |events: Vec<SequencerEvent<&'static str>>| -> Vec<(&str, Option<f32>)> {
+            events.iter().map(|e| (e.contents, e.value)).collect()
+        };
+
+        assert_eq!(values(sequencer.tick_events(ticks("0.1"))), vec![("note", None), ("ramp", Some(0.0))]);
+        assert_eq!(sequencer.ticks_until_due(), Some(ticks("0.15")));
+
+        // A tick passing several sample points sends only the latest, and none at the end of the sequence
+        assert_eq!(values(sequencer.tick_events(ticks("0.5"))), vec![("ramp", Some(0.5))]);
+        assert_eq!(values(sequencer.tick_events(ticks("0.5"))), vec![("ramp", Some(0.75))]);
+        assert!(sequencer.is_finished());
+
+        // The lane starts over with the next loop
+        sequencer.reset(sequencer.get_overshoot());
+        assert_eq!(values(sequencer.tick_events(ticks("0.0"))), vec![("note", None), ("ramp", Some(0.0))]);
+    }
+}
@@ -1,14 +1,29 @@
-use std::{cell::RefCell, str::FromStr, sync::Arc, thread, time::SystemTime};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime},
+};
 
-use bigdecimal::BigDecimal;
-use jdw_osc_lib::model::TimedOSCPacket;
+use bigdecimal::{BigDecimal, Zero};
 use log::{debug, info, warn};
-use ringbuf::{storage::Heap, traits::Consumer, wrap::caching::Caching, SharedRb};
+use ringbuf::traits::{Consumer, Observer};
 use rosc::OscPacket;
 
 use crate::{
-    local_messaging::LocalSequencerMessage, master_sequencer::MasterSequencer, midi_utils,
-    sequencer::SequencerEntry,
+    bundle_model::QueueEntryMessage,
+    clock::Clock,
+    local_messaging::{LocalMessageConsumer, LocalSequencerMessage}, master_sequencer::{MasterSequencer, SequencerStatus},
+    sequencer::{SequencerEntry, SequencerEvent},
+    realtime::RealtimeOptions,
+    stats::DaemonStats,
+    ticks::{self, Ticks},
+    timeline::{self, Timeline},
+    transform::{self, Transforms},
 };
 
 /*
@@ -21,17 +36,18 @@ pub struct OscSequencePayload {
     pub end_beat: BigDecimal,
 }
 
-pub fn to_sequence(input: Vec<TimedOSCPacket>) -> OscSequencePayload {
+pub fn to_sequence(input: Vec<QueueEntryMessage>) -> OscSequencePayload {
     let mut new_sequence: Vec<SequencerEntry<OscPacket>> = vec![];
-    let mut new_timeline = BigDecimal::from_str("0.0").unwrap();
+    let mut new_timeline = BigDecimal::zero();
 
-    for packet in &input {
-        new_sequence.push(SequencerEntry::new(
+    for entry in input {
+        new_sequence.push(SequencerEntry::with_attributes(
             new_timeline.clone(),
-            packet.packet.clone(),
+            entry.timed_packet.packet,
+            entry.attributes,
         ));
 
-        new_timeline += packet.time.clone();
+        new_timeline += entry.timed_packet.time;
     }
 
     // TODO: Note the composite payload - sequencer.rs takes an end_beat for queue
@@ -41,10 +57,93 @@ pub fn to_sequence(input: Vec<TimedOSCPacket>) -> OscSequencePayload {
     }
 }
 
+/*
+    Time offset applied when stamping the send time of events, to compensate for latency differences
+        between downstream targets. Positive values delay, negative values advance.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum Nudge {
+    Millis(f64),
+    Beats(BigDecimal),
+}
+
+impl Nudge {
+    // Offsets beyond the range of i64 saturate; beat nudges are 0 without a (positive) tempo
+    pub fn to_nanos(&self, bpm: i32) -> i64 {
+        match self {
+            Nudge::Millis(ms) => (ms * 1_000_000.0) as i64,
+            Nudge::Beats(beats) => {
+                if bpm <= 0 {
+                    return 0;
+                }
+                let nanos = ticks::from_beats(beats) as i128 * 60_000_000_000 / (ticks::TICKS_PER_BEAT as i128 * bpm as i128);
+                nanos.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+            }
+        }
+    }
+}
+
+/*
+    Per-alias variable, substituted for $name placeholders in outgoing packets (see packet_utils::substitute_placeholders).
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum Variable {
+    Int(i32),
+    Float(f32),
+    Text(String),
+}
+
+pub type Variables = HashMap<String, Variable>;
+
+// Shift a time by a signed amount of nanoseconds
+pub fn offset_time(time: SystemTime, nanos: i64) -> SystemTime {
+    if nanos >= 0 {
+        time.checked_add(Duration::from_nanos(nanos as u64))
+            .unwrap_or(time)
+    } else {
+        time.checked_sub(Duration::from_nanos(nanos.unsigned_abs()))
+            .unwrap_or(time)
+    }
+}
+
+/*
+    An event ready to go out, with the time it should be played (tick time adjusted by nudge)
+        and the variables and transforms of its alias as of that tick, if it has any.
+*/
+#[derive(Debug, Clone)]
+pub struct OutgoingEvent<T: Clone> {
+    pub event: SequencerEvent<T>,
+    pub send_time: SystemTime,
+    pub variables: Option<Arc<Variables>>,
+    pub transforms: Option<Arc<Transforms>>,
+}
+
+/*
+    Snapshot of the daemon, refreshed every tick for status queries from other threads.
+    The tick counter tells readers whether anything has happened since they last looked.
+    Timing statistics (see stats.rs) are published as of the previous tick.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct DaemonStatus {
+    pub bpm: i32,
+    pub tick: u64,
+    pub stats: DaemonStats,
+    pub sequencers: Vec<SequencerStatus>,
+}
+
+impl DaemonStatus {
+    pub fn is_running(&self) -> bool {
+        self.sequencers.iter().any(|seq| seq.active)
+    }
+}
+
 pub struct SequencingDaemonState {
     pub bpm: RefCell<i32>,
     pub reset: RefCell<bool>,
     pub hard_stop: RefCell<bool>,
+    pub nudges: RefCell<HashMap<String, Nudge>>,
+    pub variables: RefCell<HashMap<String, Arc<Variables>>>, // Shared with outgoing events, copied on write
+    pub transforms: RefCell<HashMap<String, Arc<Transforms>>>, // As variables
 }
 
 impl SequencingDaemonState {
@@ -53,121 +152,588 @@ impl SequencingDaemonState {
             bpm: RefCell::new(bpm_param),
             reset: RefCell::new(false),
             hard_stop: RefCell::new(false),
+            nudges: RefCell::new(HashMap::new()),
+            variables: RefCell::new(HashMap::new()),
+            transforms: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn send_time(&self, alias: &str, tick_time: SystemTime) -> SystemTime {
+        match self.nudges.borrow().get(alias) {
+            Some(nudge) => offset_time(tick_time, nudge.to_nanos(*self.bpm.borrow())),
+            None => tick_time,
         }
     }
 }
 
-pub fn start_live_loop<T: 'static + Clone + Send, F>(
-    mut master_sequencer: MasterSequencer<T>,
-    bpm_param: i32,
-    mut message_sub: Caching<Arc<SharedRb<Heap<LocalSequencerMessage<T>>>>, false, true>,
+/*
+    The sequencing loop, one tick at a time: apply the elapsed beats, hand due events to
+        entry_operations and take in new messages. Time comes from outside (see Clock),
+        so a daemon can be stepped on virtual time as well as run live by start_live_loop.
+    Ticks need not be evenly spaced: time_until_due tells when the next one is needed, 
+        and tick_time is the longest wait between them (and so the latency of control messages).
+*/
+
+// Shortest wait between ticks, so that sequences of no length cannot spin the loop
+const MIN_TICK_TIME: Duration = Duration::from_micros(100);
+
+pub struct SequencingDaemon<T: Clone, F> {
+    master_sequencer: MasterSequencer<T>,
+    state: SequencingDaemonState,
+    tick_time: Duration,
+    message_sub: LocalMessageConsumer<T>,
+    status: Arc<Mutex<DaemonStatus>>,
     entry_operations: F,
-) where
-    F: 'static + Send + Fn(Vec<T>, SystemTime) -> (),
+    timeline: Timeline,
+    last_tick: Ticks,
+    last_loop_time: Option<SystemTime>,
+    tick_count: u64,
+    stats: DaemonStats,
+    // Scheduling lateness of the events handed out by the last tick, recorded once they are sent (see record_sent)
+    unsent_lateness: Vec<Duration>,
+}
+
+impl<T: Clone, F> SequencingDaemon<T, F>
+where
+    F: Fn(Vec<OutgoingEvent<T>>, SystemTime),
 {
-    thread::spawn(move || {
-        let state = SequencingDaemonState::new(bpm_param);
+    pub fn new(
+        master_sequencer: MasterSequencer<T>,
+        bpm_param: i32,
+        tick_time: Duration,
+        message_sub: LocalMessageConsumer<T>,
+        status: Arc<Mutex<DaemonStatus>>,
+        entry_operations: F,
+    ) -> SequencingDaemon<T, F> {
+        SequencingDaemon {
+            master_sequencer,
+            state: SequencingDaemonState::new(bpm_param),
+            tick_time,
+            message_sub,
+            status,
+            entry_operations,
+            timeline: Timeline::new(bpm_param),
+            last_tick: 0,
+            last_loop_time: None,
+            tick_count: 0,
+            stats: DaemonStats::default(),
+            unsent_lateness: Vec::new(),
+        }
+    }
 
-        let mut last_loop_time: Option<SystemTime> = None;
+    pub fn tick_time(&self) -> Duration {
+        self.tick_time
+    }
 
-        let sleeper = spin_sleep::SpinSleeper::new(100);
+    // Time from now until the next tick is needed: when the next entry or sequence end is due, but no later than tick_time
+    pub fn time_until_due(&self, now: SystemTime) -> Duration {
+        if *self.state.reset.borrow() || *self.state.hard_stop.borrow() {
+            return MIN_TICK_TIME.min(self.tick_time);
+        }
 
-        loop {
-            let tick_time_sys = SystemTime::now();
-            let elapsed_ns: u64 = match last_loop_time {
-                Some(t) => tick_time_sys
-                    .duration_since(t)
-                    .unwrap_or_default()
-                    .as_nanos() as u64,
-                None => 0,
-            };
-            last_loop_time = Some(tick_time_sys);
-
-            let current_bpm = state.bpm.clone();
-            let reset_requested = state.reset.clone().into_inner();
-            let hard_stop_requested = state.hard_stop.clone().into_inner();
-            {
-                state.reset.replace(false);
-                state.hard_stop.replace(false);
-            }
+        let due = self.master_sequencer.ticks_until_due()
+            .and_then(|ticks| self.timeline.time_of(self.last_tick + ticks));
+        match due {
+            Some(time) => time.duration_since(now).unwrap_or_default().max(MIN_TICK_TIME).min(self.tick_time),
+            None => self.tick_time,
+        }
+    }
 
-            let elapsed_beats =
-                midi_utils::duration_to_beats(elapsed_ns, current_bpm.clone().into_inner());
+    // How long the work of the last tick took, published with the status of the next one
+    pub fn record_tick_duration(&mut self, duration: Duration) {
+        if duration > self.tick_time {
+            self.stats.overruns += 1;
+            debug!("Tick took {:?}, longer than tick time {:?}", duration, self.tick_time);
+        }
+        self.stats.tick_duration.record(duration);
+    }
 
-            if hard_stop_requested {
-                master_sequencer.force_wipe();
-            } else {
-                master_sequencer.start_check();
+    /*
+        When the events of the last tick were done sending, so that their lateness includes the time spent
+            in entry_operations. Ticks of a caller that never reports this record the scheduling lateness alone.
+    */
+    pub fn record_sent(&mut self, sent_time: SystemTime) {
+        let send_delay = self.last_loop_time
+            .and_then(|tick_time| sent_time.duration_since(tick_time).ok())
+            .unwrap_or_default();
+        self.record_lateness(send_delay);
+    }
 
-                if reset_requested {
-                    master_sequencer.force_reset();
-                } else {
-                    master_sequencer.reset_check();
-                }
-                let collected = master_sequencer.tick(elapsed_beats);
+    fn record_lateness(&mut self, send_delay: Duration) {
+        for late in self.unsent_lateness.drain(..) {
+            self.stats.lateness.record(late + send_delay);
+        }
+    }
+
+    // Run a single tick as of the given time; the ticks advanced are those elapsed since the previous tick
+    pub fn tick(&mut self, tick_time_sys: SystemTime) {
+        self.record_lateness(Duration::ZERO);
+
+        // Ticks come from the absolute timeline, so nothing is lost or gained between ticks however they are spaced
+        let position = self.timeline.ticks_at(tick_time_sys);
+        let elapsed_ticks = position - self.last_tick;
+        self.last_tick = position;
+        self.last_loop_time = Some(tick_time_sys);
 
-                entry_operations(collected, tick_time_sys);
+        let reset_requested = self.state.reset.clone().into_inner();
+        let hard_stop_requested = self.state.hard_stop.clone().into_inner();
+        {
+            self.state.reset.replace(false);
+            self.state.hard_stop.replace(false);
+        }
+
+        if hard_stop_requested {
+            self.master_sequencer.force_wipe();
+        } else {
+            self.master_sequencer.start_check();
+
+            if reset_requested {
+                self.master_sequencer.force_reset();
+            } else {
+                self.master_sequencer.reset_check();
             }
+            let state = &self.state;
+            let unsent_lateness = &mut self.unsent_lateness;
+            let bpm = *state.bpm.borrow();
+            let collected: Vec<OutgoingEvent<T>> = self.master_sequencer
+                .tick_events(elapsed_ticks)
+                .into_iter()
+                .map(|event| {
+                    unsent_lateness.push(ticks::to_duration(event.late_ticks, bpm));
+                    OutgoingEvent {
+                        send_time: state.send_time(&event.alias, tick_time_sys),
+                        variables: state.variables.borrow().get(&event.alias).cloned(),
+                        transforms: state.transforms.borrow().get(&event.alias).cloned(),
+                        event,
+                    }
+                })
+                .collect();
 
-            while let Some(msg) = message_sub.try_pop() {
-                debug!("POP");
+            (self.entry_operations)(collected, tick_time_sys);
+        }
+
+        self.stats.record_queue_depth(self.message_sub.occupied_len());
+        while let Some(msg) = self.message_sub.try_pop() {
+            debug!("POP");
+            self.apply(msg);
+        }
 
-                match msg {
-                    LocalSequencerMessage::HardStop => {
-                        state.hard_stop.replace(true);
+        // Never block the loop on a reader; a skipped update is caught up on the next tick
+        self.tick_count += 1;
+        if let Ok(mut current) = self.status.try_lock() {
+            *current = DaemonStatus {
+                bpm: *self.state.bpm.borrow(),
+                tick: self.tick_count,
+                stats: self.stats,
+                sequencers: self.master_sequencer.status(),
+            };
+        }
+    }
+
+    fn apply(&mut self, msg: LocalSequencerMessage<T>) {
+        match msg {
+            LocalSequencerMessage::HardStop => {
+                self.state.hard_stop.replace(true);
+            }
+            LocalSequencerMessage::Reset => {
+                self.state.reset.replace(true);
+            }
+            LocalSequencerMessage::SetBpm(new_bpm) => {
+                // Takes effect from the tick it arrived on
+                let result = match self.last_loop_time {
+                    Some(time) => self.timeline.set_bpm(time, new_bpm),
+                    None => timeline::check_bpm(new_bpm).map(|()| {
+                        self.timeline = Timeline::new(new_bpm);
+                    }),
+                };
+                match result {
+                    Ok(()) => {
+                        self.state.bpm.replace(new_bpm);
                     }
-                    LocalSequencerMessage::Reset => {
-                        state.reset.replace(true);
+                    Err(e) => warn!("Ignoring tempo change: {}", e),
+                }
+            }
+            LocalSequencerMessage::EndAfterFinish => {
+                self.master_sequencer.end_after_finish();
+            }
+            LocalSequencerMessage::Queue(payload) => {
+                info!("QUEUE RECEIVED");
+                self.master_sequencer.queue_with_lanes(
+                    payload.sequencer_alias.as_str(),
+                    payload.entries,
+                    payload.lanes,
+                    payload.end_beat,
+                    payload.one_shot,
+                );
+                if let Some(seed) = payload.seed {
+                    self.master_sequencer.set_seed(payload.sequencer_alias.as_str(), seed);
+                }
+            }
+            LocalSequencerMessage::SetNudge(alias, nudge) => match nudge {
+                Some(nudge) => {
+                    self.state.nudges.borrow_mut().insert(alias, nudge);
+                }
+                None => {
+                    self.state.nudges.borrow_mut().remove(&alias);
+                }
+            },
+            LocalSequencerMessage::SetVariable(alias, name, value) => {
+                let mut variables = self.state.variables.borrow_mut();
+                match value {
+                    Some(value) => {
+                        Arc::make_mut(variables.entry(alias).or_default()).insert(name, value);
                     }
-                    LocalSequencerMessage::SetBpm(new_bpm) => {
-                        state.bpm.replace(new_bpm);
+                    None => {
+                        if let Some(alias_variables) = variables.get_mut(&alias) {
+                            Arc::make_mut(alias_variables).remove(&name);
+                            if alias_variables.is_empty() {
+                                variables.remove(&alias);
+                            }
+                        }
                     }
-                    LocalSequencerMessage::EndAfterFinish => {
-                        master_sequencer.end_after_finish();
+                }
+            }
+            LocalSequencerMessage::SetTransform(alias, transform) => {
+                let mut transforms = self.state.transforms.borrow_mut();
+                match transform {
+                    Some(transform) => {
+                        let pipeline = transforms.entry(alias.clone()).or_default();
+                        transform::set_transform(Arc::make_mut(pipeline), transform);
+                        if pipeline.is_empty() {
+                            transforms.remove(&alias);
+                        }
                     }
-                    LocalSequencerMessage::Queue(payload) => {
-                        info!("QUEUE RECEIVED");
-                        master_sequencer.queue(
-                            payload.sequencer_alias.as_str(),
-                            payload.entries,
-                            payload.end_beat,
-                            payload.one_shot,
-                        );
+                    None => {
+                        transforms.remove(&alias);
                     }
-                    LocalSequencerMessage::BatchQueue(payloads) => {
-                        for payload in payloads {
-                            master_sequencer.queue(
-                                payload.sequencer_alias.as_str(),
-                                payload.entries,
-                                payload.end_beat,
-                                payload.one_shot,
-                            );
-                        }
+                }
+            }
+            LocalSequencerMessage::SetGroove(alias, groove) => {
+                self.master_sequencer.set_groove(alias.as_deref(), groove);
+            }
+            LocalSequencerMessage::BatchQueue(payloads, stop_missing) => {
+                // Applied within a single drain, so the whole batch lands on the same tick
+                if stop_missing {
+                    let aliases: Vec<&str> =
+                        payloads.iter().map(|p| p.sequencer_alias.as_str()).collect();
+                    self.master_sequencer.end_missing_after_finish(&aliases);
+                }
+                for payload in payloads {
+                    self.master_sequencer.queue_with_lanes(
+                        payload.sequencer_alias.as_str(),
+                        payload.entries,
+                        payload.lanes,
+                        payload.end_beat,
+                        payload.one_shot,
+                    );
+                    if let Some(seed) = payload.seed {
+                        self.master_sequencer.set_seed(payload.sequencer_alias.as_str(), seed);
                     }
                 }
             }
+        }
+    }
+}
 
-            let now = SystemTime::now();
-            let time_taken_ns = now
-                .duration_since(tick_time_sys)
-                .unwrap_or_default()
-                .as_nanos() as u64;
+/*
+    Runs a daemon on a thread of its own until the stop flag is set, sleeping on the given clock until the next
+        tick is due (see time_until_due). The loop finishes its current tick before returning, so joining the handle
+        waits at most one tick_time. Realtime options apply to this thread only.
+*/
+pub fn start_live_loop<T, F, C>(
+    mut daemon: SequencingDaemon<T, F>,
+    mut clock: C,
+    stop: Arc<AtomicBool>,
+    realtime: RealtimeOptions,
+) -> JoinHandle<()>
+where
+    T: 'static + Clone + Send,
+    F: 'static + Send + Fn(Vec<OutgoingEvent<T>>, SystemTime),
+    C: 'static + Send + Clock,
+{
+    thread::spawn(move || {
+        if realtime.is_enabled() {
+            realtime.apply();
+        }
 
-            let tick_time_ns = crate::config::Config::get().tick_time_us * 1000;
+        while !stop.load(Ordering::Relaxed) {
+            let tick_time_sys = clock.now();
+            daemon.tick(tick_time_sys);
 
-            if time_taken_ns > tick_time_ns {
-                warn!(
-                    "Operations performed (time: {}) exceed tick time, overflow...",
-                    time_taken_ns
-                );
-            }
+            let now = clock.now();
+            daemon.record_sent(now);
+            daemon.record_tick_duration(now.duration_since(tick_time_sys).unwrap_or_default());
             debug!("End loop");
-            let time_left_until_tick = if time_taken_ns < tick_time_ns {
-                tick_time_ns - time_taken_ns
-            } else {
-                0
-            };
-            sleeper.sleep(std::time::Duration::from_nanos(time_left_until_tick));
+            clock.sleep(daemon.time_until_due(now));
         }
-    });
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime};
+
+    use bigdecimal::BigDecimal;
+    use ringbuf::traits::{Producer, Split};
+    use ringbuf::HeapRb;
+
+    use super::{DaemonStatus, Nudge, OutgoingEvent, SequencingDaemon, Variable};
+    use crate::clock::{Clock, VirtualClock};
+    use crate::local_messaging::{LocalQueuePayload, LocalSequencerMessage};
+    use crate::master_sequencer::{MasterSequencer, SequencerResetMode, SequencerStartMode};
+    use crate::sequencer::SequencerEntry;
+
+    fn big(s: &str) -> BigDecimal {
+        BigDecimal::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn virtual_clock_test() {
+        let (mut producer, consumer) = HeapRb::<LocalSequencerMessage<String>>::new(4).split();
+        let played = Arc::new(Mutex::new(Vec::<(String, Duration)>::new()));
+        let output = played.clone();
+
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut clock = VirtualClock::new(start);
+        let status = Arc::new(Mutex::new(DaemonStatus::default()));
+
+        let mut daemon = SequencingDaemon::new(
+            MasterSequencer::new(SequencerStartMode::Immediate, SequencerResetMode::Individual),
+            120,
+            Duration::from_millis(10),
+            consumer,
+            status.clone(),
+            move |events, tick_time: SystemTime| {
+                for outgoing in events {
+                    output.lock().unwrap().push((outgoing.event.contents, tick_time.duration_since(start).unwrap()));
+                }
+            },
+        );
+
+        producer.try_push(LocalSequencerMessage::Queue(LocalQueuePayload {
+            sequencer_alias: "test".to_string(),
+            entries: vec![
+                SequencerEntry::new(big("0.0"), "one".to_string()),
+                SequencerEntry::new(big("0.5"), "two".to_string()),
+            ],
+            lanes: vec![],
+            end_beat: big("1.0"),
+            one_shot: true,
+            seed: None,
+        })).unwrap();
+
+        // 120 bpm: a beat is 500ms, or 50 ticks
+        // Every tick takes 2ms to send what it played
+        for _ in 0..100 {
+            daemon.tick(clock.now());
+            daemon.record_sent(clock.now() + Duration::from_millis(2));
+            clock.sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(clock.elapsed(), Duration::from_secs(1));
+        assert_eq!(
+            *played.lock().unwrap(),
+            vec![
+                ("one".to_string(), Duration::from_millis(10)),
+                ("two".to_string(), Duration::from_millis(250)),
+            ]
+        );
+
+        // A sequencer starts with the beats elapsed since the previous tick, so "one" counts as 10ms late plus sending
+        let stats = status.lock().unwrap().stats;
+        assert_eq!(stats.lateness.count, 2);
+        assert_eq!(stats.lateness.max_us, 12_000);
+        assert_eq!(stats.lateness.sum_us, 14_000);
+    }
+
+    #[test]
+    fn event_driven_test() {
+        let (mut producer, consumer) = HeapRb::<LocalSequencerMessage<String>>::new(4).split();
+        let played = Arc::new(Mutex::new(Vec::<(String, Duration)>::new()));
+        let output = played.clone();
+
+        let start = SystemTime::UNIX_EPOCH;
+        let mut clock = VirtualClock::new(start);
+
+        let mut daemon = SequencingDaemon::new(
+            MasterSequencer::new(SequencerStartMode::Immediate, SequencerResetMode::Individual),
+            120,
+            Duration::from_millis(10),
+            consumer,
+            Arc::new(Mutex::new(DaemonStatus::default())),
+            move |events, tick_time: SystemTime| {
+                for outgoing in events {
+                    output.lock().unwrap().push((outgoing.event.contents, tick_time.duration_since(start).unwrap()));
+                }
+            },
+        );
+
+        producer.try_push(LocalSequencerMessage::Queue(LocalQueuePayload {
+            sequencer_alias: "test".to_string(),
+            entries: vec![
+                SequencerEntry::new(big("0.0"), "one".to_string()),
+                SequencerEntry::new(big("0.3001"), "two".to_string()),
+            ],
+            lanes: vec![],
+            end_beat: big("1.0"),
+            one_shot: true,
+            seed: None,
+        })).unwrap();
+
+        // As start_live_loop steps it, without any time spent on the work itself
+        let mut ticks = 0;
+        while clock.elapsed() < Duration::from_secs(1) {
+            daemon.tick(clock.now());
+            clock.sleep(daemon.time_until_due(clock.now()));
+            ticks += 1;
+        }
+
+        // The queue is picked up at the end of the first tick and starts on the next one, right after.
+        // Then 0.3001 beats at 120 bpm is 150.05ms, on the nanosecond rather than the next multiple of tick_time
+        assert_eq!(
+            *played.lock().unwrap(),
+            vec![
+                ("one".to_string(), Duration::from_micros(100)),
+                ("two".to_string(), Duration::from_micros(150_050)),
+            ]
+        );
+        assert!(ticks < 110, "{} ticks", ticks);
+    }
+
+    #[test]
+    fn invalid_bpm_test() {
+        let (mut producer, consumer) = HeapRb::<LocalSequencerMessage<String>>::new(4).split();
+        let status = Arc::new(Mutex::new(DaemonStatus::default()));
+        let mut clock = VirtualClock::new(SystemTime::UNIX_EPOCH);
+
+        let mut daemon = SequencingDaemon::new(
+            MasterSequencer::new(SequencerStartMode::Immediate, SequencerResetMode::Individual),
+            120,
+            Duration::from_millis(10),
+            consumer,
+            status.clone(),
+            |_: Vec<OutgoingEvent<String>>, _| {},
+        );
+
+        // Sent straight to the daemon, past the checks of the engine and control handlers
+        daemon.tick(clock.now());
+        producer.try_push(LocalSequencerMessage::SetBpm(0)).unwrap();
+        producer.try_push(LocalSequencerMessage::SetBpm(-1)).unwrap();
+        daemon.tick(clock.now());
+
+        clock.sleep(Duration::from_millis(500));
+        daemon.tick(clock.now());
+        assert_eq!(status.lock().unwrap().bpm, 120);
+    }
+
+    #[test]
+    fn long_running_drift_test() {
+        let (mut producer, consumer) = HeapRb::<LocalSequencerMessage<String>>::new(4).split();
+        let played = Arc::new(Mutex::new(Vec::<Duration>::new()));
+        let output = played.clone();
+
+        let start = SystemTime::UNIX_EPOCH;
+        let mut clock = VirtualClock::new(start);
+
+        let mut daemon = SequencingDaemon::new(
+            MasterSequencer::new(SequencerStartMode::Immediate, SequencerResetMode::Individual),
+            120,
+            Duration::from_millis(10),
+            consumer,
+            Arc::new(Mutex::new(DaemonStatus::default())),
+            move |events, tick_time: SystemTime| {
+                for _ in events {
+                    output.lock().unwrap().push(tick_time.duration_since(start).unwrap());
+                }
+            },
+        );
+
+        producer.try_push(LocalSequencerMessage::Queue(LocalQueuePayload {
+            sequencer_alias: "loop".to_string(),
+            entries: vec![SequencerEntry::new(big("0.0"), "beat".to_string())],
+            lanes: vec![],
+            end_beat: big("1.0"),
+            one_shot: false,
+            seed: None,
+        })).unwrap();
+
+        // An hour of ticks between 9 and 13ms apart
+        let mut step: u64 = 0;
+        while clock.elapsed() < Duration::from_secs(3600) {
+            daemon.tick(clock.now());
+            clock.sleep(Duration::from_nanos(9_000_000 + (step * 7_919_993) % 4_000_000));
+            step += 1;
+        }
+
+        // Every beat (500ms at 120 bpm) still fires within two ticks of when it is due, an hour in:
+        //  the tick that passes the loop end, and the one after it that starts the next loop
+        let played = played.lock().unwrap();
+        assert_eq!(played.len(), 7200);
+        for (index, time) in played.iter().enumerate() {
+            let due = Duration::from_millis(500) * index as u32;
+            assert!(*time >= due && *time - due < Duration::from_millis(26), "beat {} at {:?}", index, time);
+        }
+    }
+
+    #[test]
+    fn nudge_test() {
+        assert_eq!(Nudge::Beats(big("0.5")).to_nanos(120), 250_000_000);
+        assert_eq!(Nudge::Beats(big("-0.5")).to_nanos(120), -250_000_000);
+        assert_eq!(Nudge::Beats(big("0.5")).to_nanos(0), 0);
+        assert_eq!(Nudge::Beats(big("0.5")).to_nanos(-60), 0);
+
+        // Saturates rather than overflowing
+        assert_eq!(Nudge::Beats(big("1e30")).to_nanos(1), i64::MAX);
+        assert_eq!(Nudge::Beats(big("-1e30")).to_nanos(1), i64::MIN);
+        assert_eq!(Nudge::Millis(1e300).to_nanos(120), i64::MAX);
+    }
+
+    #[test]
+    fn variables_test() {
+        let (mut producer, consumer) = HeapRb::<LocalSequencerMessage<String>>::new(4).split();
+        let played = Arc::new(Mutex::new(Vec::<Option<Variable>>::new()));
+        let output = played.clone();
+
+        let mut clock = VirtualClock::new(SystemTime::UNIX_EPOCH);
+        let mut daemon = SequencingDaemon::new(
+            MasterSequencer::new(SequencerStartMode::Immediate, SequencerResetMode::Individual),
+            120,
+            Duration::from_millis(10),
+            consumer,
+            Arc::new(Mutex::new(DaemonStatus::default())),
+            move |events, _tick_time| {
+                for outgoing in events {
+                    let value = outgoing.variables.and_then(|vars| vars.get("transpose").cloned());
+                    output.lock().unwrap().push(value);
+                }
+            },
+        );
+
+        producer.try_push(LocalSequencerMessage::Queue(LocalQueuePayload {
+            sequencer_alias: "bass".to_string(),
+            entries: vec![SequencerEntry::new(big("0.0"), "note".to_string())],
+            lanes: vec![],
+            end_beat: big("1.0"),
+            one_shot: false,
+            seed: None,
+        })).unwrap();
+        let mut play_loop = |msg: LocalSequencerMessage<String>| {
+            producer.try_push(msg).unwrap();
+            let until = clock.elapsed() + Duration::from_millis(500);
+            while clock.elapsed() < until {
+                daemon.tick(clock.now());
+                clock.sleep(daemon.time_until_due(clock.now()));
+            }
+        };
+
+        let set = |value: Option<Variable>| LocalSequencerMessage::SetVariable("bass".to_string(), "transpose".to_string(), value);
+        play_loop(set(Some(Variable::Int(7))));
+        play_loop(set(Some(Variable::Int(-5))));
+        play_loop(set(None));
+
+        // Values apply from the tick they arrive on, so each loop plays with the one set before it
+        assert_eq!(*played.lock().unwrap(), vec![Some(Variable::Int(7)), Some(Variable::Int(-5)), None]);
+    }
 }
@@ -0,0 +1,143 @@
+/*
+
+    SLIP framing (RFC 1055) as used for OSC 1.1 over stream transports such as TCP.
+    Each packet is wrapped in END bytes, with END and ESC bytes inside the packet escaped.
+
+*/
+
+use log::warn;
+
+const END: u8 = 0xC0;
+const ESC: u8 = 0xDB;
+const ESC_END: u8 = 0xDC;
+const ESC_ESC: u8 = 0xDD;
+
+// Largest frame accepted by default, well above any sane OSC packet
+pub const MAX_FRAME_SIZE: usize = 4 * 1024 * 1024;
+
+pub fn encode(packet: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(packet.len() + 2);
+    framed.push(END);
+    for byte in packet {
+        match *byte {
+            END => framed.extend_from_slice(&[ESC, ESC_END]),
+            ESC => framed.extend_from_slice(&[ESC, ESC_ESC]),
+            other => framed.push(other),
+        }
+    }
+    framed.push(END);
+    framed
+}
+
+/*
+    Incremental decoder for a byte stream. Feed it whatever arrives and collect completed frames.
+    Empty frames (e.g. from the double END framing of OSC 1.1) are skipped.
+    A frame growing past max_frame is dropped along with the rest of it, up to the next END,
+        so that a peer that never ends a frame cannot grow the buffer without bound.
+*/
+#[derive(Debug)]
+pub struct SlipDecoder {
+    buffer: Vec<u8>,
+    escaping: bool,
+    max_frame: usize,
+    discarding: bool,
+}
+
+impl Default for SlipDecoder {
+    fn default() -> Self {
+        SlipDecoder::with_max_frame(MAX_FRAME_SIZE)
+    }
+}
+
+impl SlipDecoder {
+    pub fn new() -> SlipDecoder {
+        SlipDecoder::default()
+    }
+
+    pub fn with_max_frame(max_frame: usize) -> SlipDecoder {
+        SlipDecoder {
+            buffer: vec![],
+            escaping: false,
+            max_frame,
+            discarding: false,
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<Vec<u8>> {
+        let mut frames = vec![];
+        for byte in bytes {
+            if self.escaping {
+                self.escaping = false;
+                match *byte {
+                    ESC_END => self.append(END),
+                    ESC_ESC => self.append(ESC),
+                    // Protocol violation; keep the byte rather than dropping data silently
+                    other => self.append(other),
+                }
+                continue;
+            }
+
+            match *byte {
+                END => {
+                    if !self.buffer.is_empty() {
+                        frames.push(std::mem::take(&mut self.buffer));
+                    }
+                    self.discarding = false;
+                }
+                ESC => self.escaping = true,
+                other => self.append(other),
+            }
+        }
+        frames
+    }
+
+    fn append(&mut self, byte: u8) {
+        if self.discarding {
+            return;
+        }
+        if self.buffer.len() >= self.max_frame {
+            warn!("Dropped SLIP frame larger than {} bytes", self.max_frame);
+            self.buffer = vec![];
+            self.discarding = true;
+            return;
+        }
+        self.buffer.push(byte);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, SlipDecoder};
+
+    #[test]
+    fn round_trip_test() {
+        let packet: Vec<u8> = vec![0x2F, 0xC0, 0x01, 0xDB, 0xDC, 0x00];
+        let framed = encode(&packet);
+        assert_eq!(framed, vec![0xC0, 0x2F, 0xDB, 0xDC, 0x01, 0xDB, 0xDD, 0xDC, 0x00, 0xC0]);
+
+        let mut decoder = SlipDecoder::new();
+        assert_eq!(decoder.push(&framed), vec![packet]);
+    }
+
+    #[test]
+    fn split_stream_test() {
+        let mut stream = encode(&[1, 2, 0xC0]);
+        stream.extend(encode(&[3, 4]));
+
+        let mut decoder = SlipDecoder::new();
+        let mut frames = vec![];
+        for chunk in stream.chunks(3) {
+            frames.extend(decoder.push(chunk));
+        }
+        assert_eq!(frames, vec![vec![1, 2, 0xC0], vec![3, 4]]);
+    }
+
+    #[test]
+    fn max_frame_test() {
+        let mut stream = encode(&[1, 2, 3, 4, 5]);
+        stream.extend(encode(&[6, 7]));
+
+        let mut decoder = SlipDecoder::with_max_frame(4);
+        assert_eq!(decoder.push(&stream), vec![vec![6, 7]]);
+    }
+}
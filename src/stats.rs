@@ -0,0 +1,210 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+
+use crate::control::ControlHandlers;
+use crate::local_messaging::SenderMetrics;
+
+/*
+
+    Timing statistics of the sequencing loop, kept by the daemon and published with its status.
+
+    - lateness: how long after its scheduled time each event went out, i.e. how far the loop had
+        moved past the entry on the tick that played it plus the time that tick spent sending
+    - tick_duration: how long the work of each tick took, with overruns counting those over tick_time
+    - queue_depth: control messages waiting in the ring buffer at the start of the last tick
+
+    Exposed through the OSC /stats query and, with metrics_port set, as Prometheus text on localhost.
+
+*/
+
+// Inclusive upper bounds of the histogram buckets, in microseconds; a last bucket counts everything above
+pub const BUCKET_BOUNDS_US: [u64; 10] = [50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 100_000];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Histogram {
+    pub buckets: [u64; 11], // Not cumulative: each count is for its own bucket only
+    pub count: u64,
+    pub sum_us: u64,
+    pub max_us: u64,
+}
+
+impl Histogram {
+    pub fn record(&mut self, duration: Duration) {
+        let us = duration.as_micros().min(u64::MAX as u128) as u64;
+        let index = BUCKET_BOUNDS_US.iter().position(|bound| us <= *bound).unwrap_or(BUCKET_BOUNDS_US.len());
+        self.buckets[index] += 1;
+        self.count += 1;
+        self.sum_us = self.sum_us.saturating_add(us);
+        self.max_us = self.max_us.max(us);
+    }
+
+    pub fn mean_us(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum_us as f64 / self.count as f64
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DaemonStats {
+    pub lateness: Histogram,
+    pub tick_duration: Histogram,
+    pub overruns: u64,
+    pub queue_depth: usize,
+    pub max_queue_depth: usize,
+}
+
+impl DaemonStats {
+    pub fn record_queue_depth(&mut self, depth: usize) {
+        self.queue_depth = depth;
+        self.max_queue_depth = self.max_queue_depth.max(depth);
+    }
+}
+
+fn prometheus_histogram(text: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    text.push_str(&format!("# HELP {} {}\n# TYPE {} histogram\n", name, help, name));
+    let mut cumulative = 0;
+    for (bound, count) in BUCKET_BOUNDS_US.iter().zip(histogram.buckets.iter()) {
+        cumulative += count;
+        text.push_str(&format!("{}_bucket{{le=\"{}\"}} {}\n", name, *bound as f64 / 1_000_000.0, cumulative));
+    }
+    text.push_str(&format!("{}_bucket{{le=\"+Inf\"}} {}\n", name, histogram.count));
+    text.push_str(&format!("{}_sum {}\n", name, histogram.sum_us as f64 / 1_000_000.0));
+    text.push_str(&format!("{}_count {}\n", name, histogram.count));
+}
+
+fn prometheus_value(text: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    text.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n{} {}\n", name, help, name, kind, name, value));
+}
+
+// Prometheus text exposition format, version 0.0.4
+pub fn prometheus_text(stats: &DaemonStats, sender: &SenderMetrics) -> String {
+    let mut text = String::new();
+    prometheus_histogram(&mut text, "jdw_event_lateness_seconds", "Time from when events were due until they went out.", &stats.lateness);
+    prometheus_histogram(&mut text, "jdw_tick_duration_seconds", "Time spent on the work of a tick.", &stats.tick_duration);
+    prometheus_value(&mut text, "jdw_tick_overruns_total", "counter", "Ticks whose work took longer than tick_time.", stats.overruns);
+    prometheus_value(&mut text, "jdw_queue_depth", "gauge", "Control messages waiting at the start of the last tick.", stats.queue_depth as u64);
+    prometheus_value(&mut text, "jdw_queue_depth_max", "gauge", "Most control messages ever waiting at the start of a tick.", stats.max_queue_depth as u64);
+
+    text.push_str("# HELP jdw_control_messages_total Control messages by outcome.\n# TYPE jdw_control_messages_total counter\n");
+    for (result, value) in [("sent", sender.sent), ("coalesced", sender.coalesced), ("dropped", sender.dropped)] {
+        text.push_str(&format!("jdw_control_messages_total{{result=\"{}\"}} {}\n", result, value));
+    }
+    prometheus_value(&mut text, "jdw_control_retries_total", "counter", "Times a control message had to wait for room in the ring buffer.", sender.retries);
+    text
+}
+
+/*
+    Serves GET /metrics as Prometheus text on 127.0.0.1 only, one request per connection,
+        each connection on its own thread so a slow client cannot hold up the next scrape.
+*/
+pub fn start_metrics_server(port: i32, control: Arc<ControlHandlers>) {
+    let addr = format!("127.0.0.1:{}", port);
+    let listener = match TcpListener::bind(&addr) {
+        Ok(listener) => listener,
+        Err(e) => panic!("Failed to bind metrics endpoint on {}: {}", addr, e),
+    };
+
+    info!("STARTING METRICS ENDPOINT ON http://{}/metrics", addr);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let control = control.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = serve_request(stream, &control) {
+                            warn!("Failed to serve metrics request: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Failed to accept metrics connection: {}", e),
+            }
+        }
+    });
+}
+
+fn serve_request(mut stream: TcpStream, control: &ControlHandlers) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+    // Only the request line matters; the rest of the request is ignored
+    let mut request = vec![];
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let read = stream.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.split_whitespace();
+
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let (stats, sender) = control.stats();
+            ("200 OK", prometheus_text(&stats, &sender))
+        }
+        _ => ("404 Not Found", "Not found\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{prometheus_text, DaemonStats, Histogram};
+    use crate::local_messaging::SenderMetrics;
+
+    #[test]
+    fn histogram_test() {
+        let mut histogram = Histogram::default();
+        histogram.record(Duration::from_micros(40));
+        histogram.record(Duration::from_micros(50));
+        histogram.record(Duration::from_micros(700));
+        histogram.record(Duration::from_secs(1));
+
+        assert_eq!(histogram.buckets, [2, 0, 0, 0, 1, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(histogram.count, 4);
+        assert_eq!(histogram.max_us, 1_000_000);
+        assert_eq!(histogram.mean_us(), 250_197.5);
+    }
+
+    #[test]
+    fn prometheus_test() {
+        let mut stats = DaemonStats::default();
+        stats.lateness.record(Duration::from_micros(80));
+        stats.lateness.record(Duration::from_micros(300));
+        stats.overruns = 2;
+        stats.record_queue_depth(3);
+        stats.record_queue_depth(1);
+
+        let text = prometheus_text(&stats, &SenderMetrics { sent: 5, dropped: 1, ..Default::default() });
+        let lines: Vec<&str> = text.lines().collect();
+
+        assert!(lines.contains(&"# TYPE jdw_event_lateness_seconds histogram"));
+        assert!(lines.contains(&"jdw_event_lateness_seconds_bucket{le=\"0.0001\"} 1"));
+        assert!(lines.contains(&"jdw_event_lateness_seconds_bucket{le=\"0.1\"} 2"));
+        assert!(lines.contains(&"jdw_event_lateness_seconds_bucket{le=\"+Inf\"} 2"));
+        assert!(lines.contains(&"jdw_event_lateness_seconds_sum 0.00038"));
+        assert!(lines.contains(&"jdw_tick_overruns_total 2"));
+        assert!(lines.contains(&"jdw_queue_depth 1"));
+        assert!(lines.contains(&"jdw_queue_depth_max 3"));
+        assert!(lines.contains(&"jdw_control_messages_total{result=\"dropped\"} 1"));
+    }
+}
@@ -0,0 +1,111 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+use rosc::{decoder, encoder};
+
+use crate::control::ControlHandlers;
+use crate::slip::{self, SlipDecoder};
+
+const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+// A client that does not read its replies for this long is disconnected rather than left to block its reader
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/*
+
+    OSC 1.1 over TCP: SLIP-framed packets, for updates too large to be trusted to a UDP datagram.
+    Each connection gets its own reader thread; packets are dispatched in arrival order
+        to the same handlers as the UDP reader, and replies go back on the same connection.
+    Connections are closed once a shutdown has been requested, checked at least every SHUTDOWN_POLL.
+    The listener is on by default, so a port already in use only costs TCP input, with a warning.
+
+*/
+pub fn start_listener(addr: String, control: Arc<ControlHandlers>) {
+    let listener = match TcpListener::bind(&addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Failed to bind TCP listener on {}, continuing without TCP input: {}", addr, e);
+            return;
+        }
+    };
+
+    info!("STARTING TCP OSC READER ON {}", addr);
+
+    thread::spawn(move || serve(listener, control));
+}
+
+// Accept connections on an already bound listener, each read on a thread of its own
+pub fn serve(listener: TcpListener, control: Arc<ControlHandlers>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let control = control.clone();
+                thread::spawn(move || read_connection(stream, control));
+            }
+            Err(e) => warn!("Failed to accept TCP connection: {}", e),
+        }
+    }
+}
+
+fn read_connection(mut stream: TcpStream, control: Arc<ControlHandlers>) {
+    let peer = stream
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_else(|_| "unknown".to_string());
+    info!("TCP CONNECTION FROM {}", peer);
+
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(e) => {
+            warn!("Failed to set up TCP connection from {}: {}", peer, e);
+            return;
+        }
+    };
+
+    if let Err(e) = stream.set_read_timeout(Some(SHUTDOWN_POLL)) {
+        warn!("Failed to set TCP read timeout for {}, shutdown will wait for the next packet: {}", peer, e);
+    }
+    if let Err(e) = writer.set_write_timeout(Some(REPLY_TIMEOUT)) {
+        warn!("Failed to set up TCP connection from {}: {}", peer, e);
+        return;
+    }
+
+    let mut slip_decoder = SlipDecoder::new();
+    let mut buf = [0u8; 8192];
+
+    'connection: while !control.is_shutdown() {
+        let read = match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) => {
+                warn!("TCP read from {} failed: {}", peer, e);
+                break;
+            }
+        };
+
+        for frame in slip_decoder.push(&buf[..read]) {
+            match decoder::decode_udp(&frame) {
+                Ok((_, packet)) => {
+                    for reply in control.handle(packet) {
+                        match encoder::encode(&reply) {
+                            Ok(bytes) => {
+                                if let Err(e) = writer.write_all(&slip::encode(&bytes)) {
+                                    warn!("Failed to reply to {}, closing the connection: {}", peer, e);
+                                    break 'connection;
+                                }
+                            }
+                            Err(e) => warn!("Failed to encode reply: {}", e),
+                        }
+                    }
+                }
+                Err(e) => warn!("Failed to decode OSC packet from {}: {:?}", peer, e),
+            }
+        }
+    }
+
+    info!("TCP CONNECTION CLOSED {}", peer);
+}
@@ -0,0 +1,74 @@
+use std::time::Duration;
+
+use bigdecimal::num_bigint::{BigInt, Sign};
+use bigdecimal::{BigDecimal, RoundingMode, ToPrimitive};
+
+/*
+
+    Fixed-point beat positions used inside the engine: an integer number of ticks at TICKS_PER_BEAT
+        pulses per quarter note. Integer ticks are cheap to add and compare on every loop, and exact for
+        any beat given with up to nine decimals.
+
+    Beats are BigDecimal everywhere else (OSC messages, session files, status replies), and are converted
+        with from_beats/to_beats where they enter and leave the engine.
+
+*/
+
+pub type Ticks = i64;
+
+pub const TICKS_PER_BEAT: Ticks = 1_000_000_000;
+const TICK_DECIMALS: i64 = 9;
+
+// Rounded half up to the nearest tick; beats beyond the range of Ticks saturate
+pub fn from_beats(beats: &BigDecimal) -> Ticks {
+    (beats * BigDecimal::from(TICKS_PER_BEAT))
+        .with_scale_round(0, RoundingMode::HalfUp)
+        .to_i64()
+        .unwrap_or(if beats.sign() == Sign::Minus { Ticks::MIN } else { Ticks::MAX })
+}
+
+pub fn to_beats(ticks: Ticks) -> BigDecimal {
+    BigDecimal::new(BigInt::from(ticks), TICK_DECIMALS).normalized()
+}
+
+// Time the given ticks take at the given tempo, rounded down to the nanosecond
+pub fn to_duration(ticks: Ticks, bpm: i32) -> Duration {
+    if ticks <= 0 || bpm <= 0 {
+        return Duration::ZERO;
+    }
+    let nanos = ticks as i128 * 60_000_000_000 / (TICKS_PER_BEAT as i128 * bpm as i128);
+    Duration::from_nanos(nanos.min(u64::MAX as i128) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bigdecimal::BigDecimal;
+
+    use std::time::Duration;
+
+    use super::{from_beats, to_beats, to_duration, TICKS_PER_BEAT};
+
+    fn big(inp: &str) -> BigDecimal {
+        BigDecimal::from_str(inp).unwrap()
+    }
+
+    #[test]
+    fn conversion_test() {
+        assert_eq!(from_beats(&big("1.0")), TICKS_PER_BEAT);
+        assert_eq!(from_beats(&big("0.333333333")), 333_333_333);
+        assert_eq!(from_beats(&big("-0.25")), -250_000_000);
+
+        // Below the resolution of a tick, rounded half up
+        assert_eq!(from_beats(&big("0.0000000005")), 1);
+        assert_eq!(from_beats(&big("0.0000000004")), 0);
+        assert_eq!(from_beats(&big("1e30")), i64::MAX);
+
+        assert_eq!(to_beats(from_beats(&big("2.75"))).to_string(), "2.75");
+        assert_eq!(to_beats(0), big("0"));
+
+        assert_eq!(to_duration(TICKS_PER_BEAT / 2, 120), Duration::from_millis(250));
+        assert_eq!(to_duration(-5, 120), Duration::ZERO);
+    }
+}
@@ -0,0 +1,161 @@
+use std::time::{Duration, SystemTime};
+
+use crate::ticks::{Ticks, TICKS_PER_BEAT};
+
+/*
+
+    Beat position as a function of time, from an anchor (a time and the beat at that time)
+        and the tempo since then. A tempo change sets a new anchor at the time of the change.
+
+    Positions are exact integers in units of 1/UNITS_PER_BEAT beat, i.e. nanoseconds times bpm,
+        and are only rounded (to whole ticks, see ticks.rs) when read. Since every position is
+        computed from the anchor rather than added up tick by tick, rounding and jitter never accumulate:
+        the beats passed between two reads are always the difference of two absolute positions.
+
+*/
+
+const UNITS_PER_BEAT: i128 = 60_000_000_000; // Nanoseconds per minute
+
+// Time stands still at 0 bpm and runs backwards below it, so every tempo change is checked here
+pub fn check_bpm(bpm: i32) -> Result<(), String> {
+    if bpm <= 0 {
+        return Err(format!("BPM must be positive, got {}", bpm));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct Timeline {
+    anchor_time: Option<SystemTime>,
+    anchor_units: i128,
+    bpm: i32,
+}
+
+impl Timeline {
+    pub fn new(bpm: i32) -> Timeline {
+        Timeline {
+            anchor_time: None,
+            anchor_units: 0,
+            bpm,
+        }
+    }
+
+    pub fn bpm(&self) -> i32 {
+        self.bpm
+    }
+
+    // Position in beat units; the first read anchors beat 0 at the given time
+    fn units_at(&mut self, time: SystemTime) -> i128 {
+        let anchor_time = *self.anchor_time.get_or_insert(time);
+        // Times before the anchor (e.g. the system clock being set back) hold the position at the anchor
+        let nanos = time.duration_since(anchor_time).unwrap_or_default().as_nanos() as i128;
+        self.anchor_units + nanos * self.bpm as i128
+    }
+
+    // Position in ticks, rounded half up
+    pub fn ticks_at(&mut self, time: SystemTime) -> Ticks {
+        let units = self.units_at(time);
+        ((units * TICKS_PER_BEAT as i128 + UNITS_PER_BEAT / 2) / UNITS_PER_BEAT) as Ticks
+    }
+
+    // Earliest time at which ticks_at reads the position at the current tempo; None before the first read
+    pub fn time_of(&self, position: Ticks) -> Option<SystemTime> {
+        let anchor_time = self.anchor_time?;
+        if self.bpm <= 0 {
+            return None;
+        }
+        // Half a tick early, as ticks_at rounds half up
+        let units = (position as i128 * 2 - 1) * UNITS_PER_BEAT / (2 * TICKS_PER_BEAT as i128);
+        let remaining = units - self.anchor_units;
+        if remaining <= 0 {
+            return Some(anchor_time);
+        }
+        let nanos = (remaining + self.bpm as i128 - 1) / self.bpm as i128;
+        Some(anchor_time + Duration::from_nanos(nanos.min(u64::MAX as i128) as u64))
+    }
+
+    // Change tempo as of the given time; positions before it are unaffected
+    pub fn set_bpm(&mut self, time: SystemTime, bpm: i32) -> Result<(), String> {
+        check_bpm(bpm)?;
+        self.anchor_units = self.units_at(time);
+        self.anchor_time = Some(time);
+        self.bpm = bpm;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::Timeline;
+    use crate::ticks::{Ticks, TICKS_PER_BEAT};
+
+    #[test]
+    fn tempo_change_test() {
+        let start = SystemTime::UNIX_EPOCH;
+        let mut timeline = Timeline::new(120);
+
+        assert_eq!(timeline.ticks_at(start), 0);
+        assert_eq!(timeline.ticks_at(start + Duration::from_secs(1)), 2 * TICKS_PER_BEAT);
+
+        timeline.set_bpm(start + Duration::from_secs(1), 90).unwrap();
+        assert_eq!(timeline.ticks_at(start + Duration::from_secs(3)), 5 * TICKS_PER_BEAT);
+
+        // 70 beats per 60e9 ns does not terminate, and is rounded at the last tick only
+        let mut timeline = Timeline::new(70);
+        timeline.ticks_at(start);
+        assert_eq!(timeline.ticks_at(start + Duration::from_micros(1)), 1167);
+
+        // Tempos that would stop or reverse time leave the timeline as it was
+        assert!(timeline.set_bpm(start + Duration::from_secs(1), 0).is_err());
+        assert!(timeline.set_bpm(start + Duration::from_secs(1), -1).is_err());
+        assert_eq!(timeline.bpm(), 70);
+    }
+
+    #[test]
+    fn time_of_test() {
+        let start = SystemTime::UNIX_EPOCH;
+        let mut timeline = Timeline::new(70);
+        assert_eq!(timeline.time_of(TICKS_PER_BEAT), None);
+
+        timeline.ticks_at(start);
+        assert_eq!(timeline.time_of(-1), Some(start));
+
+        // Positions that fall between nanoseconds are read on the nanosecond after
+        for position in [1, 1167, TICKS_PER_BEAT / 3, 7 * TICKS_PER_BEAT + 5] {
+            let time = timeline.time_of(position).unwrap();
+            assert!(timeline.ticks_at(time) >= position);
+            assert!(timeline.ticks_at(time - Duration::from_nanos(1)) < position);
+        }
+
+        timeline.set_bpm(start + Duration::from_secs(6), 140).unwrap();
+        assert_eq!(timeline.time_of(21 * TICKS_PER_BEAT), Some(start + Duration::from_secs(12)));
+    }
+
+    #[test]
+    fn zero_drift_test() {
+        let start = SystemTime::UNIX_EPOCH;
+        let mut timeline = Timeline::new(97);
+
+        // Three hours of ticks with jitter between 4 and 6ms, as a loaded machine would give
+        let mut time = start;
+        let mut summed: Ticks = 0;
+        let mut last_tick = timeline.ticks_at(start);
+        let mut step: u64 = 0;
+        while time < start + Duration::from_secs(3 * 3600) {
+            time += Duration::from_nanos(4_000_000 + (step * 7_919_993) % 2_000_000);
+            step += 1;
+
+            let tick = timeline.ticks_at(time);
+            summed += tick - last_tick;
+            last_tick = tick;
+        }
+
+        // The ticks handed out one by one add up to exactly the position computed from the elapsed time
+        let elapsed_ns = time.duration_since(start).unwrap().as_nanos();
+        let exact = elapsed_ns * 97 * TICKS_PER_BEAT as u128;
+        let expected = ((exact + 30_000_000_000) / 60_000_000_000) as Ticks;
+        assert_eq!(summed, expected);
+    }
+}
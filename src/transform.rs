@@ -0,0 +1,170 @@
+use rosc::{OscPacket, OscType};
+
+use crate::packet_utils;
+
+/*
+
+    Per-alias changes applied to outgoing packets as they are sent, leaving the queued sequence as it is,
+        so that e.g. a bassline can be transposed live without requeueing it.
+
+    - Transpose: semitones, multiplying "freq" by the matching ratio and added to "note"
+    - Offset: an amount added to a named numeric arg
+    - Scale: a factor a named numeric arg is multiplied with, e.g. "amp" or "velocity"
+    - Prefix: replaces the leading segments of message addresses, e.g. "/note" to "/drone/note" turns
+        "/note/on" into "/drone/note/on" but leaves "/note_on" as it is
+
+    An alias has a pipeline of these, applied in the order they were first set (see set_transform).
+
+*/
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transform {
+    Transpose(f32),
+    Offset { arg: String, amount: f32 },
+    Scale { arg: String, factor: f32 },
+    Prefix { from: String, to: String },
+}
+
+pub type Transforms = Vec<Transform>;
+
+impl Transform {
+    // True if both change the same thing, in which case the newer one takes the place of the older
+    pub fn replaces(&self, other: &Transform) -> bool {
+        match (self, other) {
+            (Transform::Transpose(_), Transform::Transpose(_)) => true,
+            (Transform::Offset { arg: new, .. }, Transform::Offset { arg: old, .. }) => new == old,
+            (Transform::Scale { arg: new, .. }, Transform::Scale { arg: old, .. }) => new == old,
+            (Transform::Prefix { from: new, .. }, Transform::Prefix { from: old, .. }) => new == old,
+            _ => false,
+        }
+    }
+
+    // True if applying it changes nothing, e.g. a transpose of 0 semitones
+    pub fn is_identity(&self) -> bool {
+        match self {
+            Transform::Transpose(semitones) => *semitones == 0.0,
+            Transform::Offset { amount, .. } => *amount == 0.0,
+            Transform::Scale { factor, .. } => *factor == 1.0,
+            Transform::Prefix { from, to } => from == to,
+        }
+    }
+
+    pub fn apply(&self, packet: &OscPacket) -> OscPacket {
+        match self {
+            Transform::Transpose(semitones) => {
+                let ratio = 2f64.powf(*semitones as f64 / 12.0);
+                let packet = packet_utils::map_named_arg(packet, "freq", &|value| multiply(value, ratio));
+                packet_utils::map_named_arg(&packet, "note", &|value| add(value, *semitones as f64))
+            }
+            Transform::Offset { arg, amount } => {
+                packet_utils::map_named_arg(packet, arg, &|value| add(value, *amount as f64))
+            }
+            Transform::Scale { arg, factor } => {
+                packet_utils::map_named_arg(packet, arg, &|value| multiply(value, *factor as f64))
+            }
+            Transform::Prefix { from, to } => packet_utils::map_address(packet, &|addr| {
+                addr.strip_prefix(from.as_str())
+                    // Only whole segments match
+                    .filter(|rest| rest.is_empty() || rest.starts_with('/') || from.ends_with('/'))
+                    .map(|rest| format!("{}{}", to, rest))
+            }),
+        }
+    }
+}
+
+// Add the transform to the pipeline in place of one it replaces, or at the end; identities just remove
+pub fn set_transform(pipeline: &mut Transforms, transform: Transform) {
+    let existing = pipeline.iter().position(|old| transform.replaces(old));
+    match (existing, transform.is_identity()) {
+        (Some(index), true) => {
+            pipeline.remove(index);
+        }
+        (Some(index), false) => pipeline[index] = transform,
+        (None, true) => {}
+        (None, false) => pipeline.push(transform),
+    }
+}
+
+pub fn apply_all(transforms: &[Transform], packet: &OscPacket) -> OscPacket {
+    transforms.iter().fold(packet.clone(), |pct, transform| transform.apply(&pct))
+}
+
+fn add(value: &OscType, amount: f64) -> OscType {
+    match value {
+        OscType::Float(val) => OscType::Float((*val as f64 + amount) as f32),
+        OscType::Double(val) => OscType::Double(val + amount),
+        OscType::Int(val) => OscType::Int((*val as f64 + amount).round() as i32),
+        other => other.clone(),
+    }
+}
+
+fn multiply(value: &OscType, factor: f64) -> OscType {
+    match value {
+        OscType::Float(val) => OscType::Float((*val as f64 * factor) as f32),
+        OscType::Double(val) => OscType::Double(val * factor),
+        OscType::Int(val) => OscType::Int((*val as f64 * factor).round() as i32),
+        other => other.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rosc::{OscMessage, OscPacket, OscType};
+
+    use super::{apply_all, set_transform, Transform, Transforms};
+
+    fn note(addr: &str, freq: f32, note: i32, amp: f32) -> OscPacket {
+        OscPacket::Message(OscMessage {
+            addr: addr.to_string(),
+            args: vec![
+                OscType::String("freq".to_string()),
+                OscType::Float(freq),
+                OscType::String("note".to_string()),
+                OscType::Int(note),
+                OscType::String("amp".to_string()),
+                OscType::Float(amp),
+            ],
+        })
+    }
+
+    #[test]
+    fn transform_test() {
+        let transforms = vec![
+            Transform::Transpose(12.0),
+            Transform::Scale { arg: "amp".to_string(), factor: 0.5 },
+            Transform::Prefix { from: "/note".to_string(), to: "/bass/note".to_string() },
+        ];
+
+        assert_eq!(
+            apply_all(&transforms, &note("/note/on", 220.0, 45, 0.8)),
+            note("/bass/note/on", 440.0, 57, 0.4)
+        );
+        // Prefix only applies to addresses starting with it
+        assert_eq!(apply_all(&transforms[2..], &note("/other", 220.0, 45, 0.8)), note("/other", 220.0, 45, 0.8));
+    }
+
+    #[test]
+    fn prefix_segment_test() {
+        let prefix = [Transform::Prefix { from: "/note".to_string(), to: "/bass".to_string() }];
+        assert_eq!(apply_all(&prefix, &note("/note", 220.0, 45, 0.8)), note("/bass", 220.0, 45, 0.8));
+        assert_eq!(apply_all(&prefix, &note("/note/off", 220.0, 45, 0.8)), note("/bass/off", 220.0, 45, 0.8));
+        // Addresses merely starting with the same characters are a different address
+        assert_eq!(apply_all(&prefix, &note("/note_on", 220.0, 45, 0.8)), note("/note_on", 220.0, 45, 0.8));
+        assert_eq!(apply_all(&prefix, &note("/notes", 220.0, 45, 0.8)), note("/notes", 220.0, 45, 0.8));
+
+        let trailing = [Transform::Prefix { from: "/drone/".to_string(), to: "/".to_string() }];
+        assert_eq!(apply_all(&trailing, &note("/drone/note_on", 220.0, 45, 0.8)), note("/note_on", 220.0, 45, 0.8));
+    }
+
+    #[test]
+    fn set_transform_test() {
+        let mut pipeline = Transforms::new();
+        set_transform(&mut pipeline, Transform::Transpose(7.0));
+        set_transform(&mut pipeline, Transform::Offset { arg: "note".to_string(), amount: 1.0 });
+        set_transform(&mut pipeline, Transform::Transpose(-5.0));
+        assert_eq!(pipeline, vec![Transform::Transpose(-5.0), Transform::Offset { arg: "note".to_string(), amount: 1.0 }]);
+
+        set_transform(&mut pipeline, Transform::Transpose(0.0));
+        assert_eq!(pipeline, vec![Transform::Offset { arg: "note".to_string(), amount: 1.0 }]);
+    }
+}
@@ -0,0 +1,68 @@
+use std::io::ErrorKind;
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{info, warn};
+use rosc::{decoder, encoder};
+
+use crate::control::ControlHandlers;
+
+const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+
+/*
+
+    Reader for OSC over UDP. Replies to requests (see ControlHandlers::handle) are sent
+        back to the address the request came from.
+    Returns once a shutdown has been requested, checked at least every SHUTDOWN_POLL.
+
+*/
+pub fn listen(addr: String, buffer_size: usize, control: Arc<ControlHandlers>) {
+    let socket = match UdpSocket::bind(&addr) {
+        Ok(socket) => socket,
+        Err(e) => panic!("Failed to bind UDP socket on {}: {}", addr, e),
+    };
+
+    info!("STARTING UDP OSC READER ON {}", addr);
+
+    serve(socket, buffer_size, control);
+}
+
+// Read and handle packets from an already bound socket until shutdown
+pub fn serve(socket: UdpSocket, buffer_size: usize, control: Arc<ControlHandlers>) {
+    if let Err(e) = socket.set_read_timeout(Some(SHUTDOWN_POLL)) {
+        warn!("Failed to set UDP read timeout, shutdown will wait for the next packet: {}", e);
+    }
+
+    let mut buf = vec![0u8; buffer_size];
+
+    while !control.is_shutdown() {
+        let (size, sender) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) => {
+                warn!("UDP receive failed: {}", e);
+                continue;
+            }
+        };
+
+        let packet = match decoder::decode_udp(&buf[..size]) {
+            Ok((_, packet)) => packet,
+            Err(e) => {
+                warn!("Failed to decode OSC packet from {}: {:?}", sender, e);
+                continue;
+            }
+        };
+
+        for reply in control.handle(packet) {
+            match encoder::encode(&reply) {
+                Ok(bytes) => {
+                    if let Err(e) = socket.send_to(&bytes, sender) {
+                        warn!("Failed to reply to {}: {}", sender, e);
+                    }
+                }
+                Err(e) => warn!("Failed to encode reply: {}", e),
+            }
+        }
+    }
+}